@@ -0,0 +1,76 @@
+//! Counts the frames per frame type on a channel for ten seconds, with a custom
+//! `PacketSink` instead of a capture file
+//!
+//! cargo run --example count_frames -- 15
+
+use ccsniffer_rust::mac::FrameType;
+use ccsniffer_rust::packet::CapturedPacket;
+use ccsniffer_rust::session::{CaptureSession, SessionOptions};
+use ccsniffer_rust::sink::PacketSink;
+use ccsniffer_rust::sniffer::{SnifferDevice, SnifferError, PRODUCT, VENDOR};
+use ccsniffer_rust::writer::WriterError;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CAPTURE_TIME: Duration = Duration::from_secs(10);
+
+/// Sinks run on the writer thread of the session, so the counts are shared with main
+/// through a mutex
+struct FrameCounter {
+    counts: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl PacketSink for FrameCounter {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+        let frame_type = match packet.payload.get(..2) {
+            Some(fc) => FrameType::from(u16::from_le_bytes([fc[0], fc[1]])).to_string(),
+            None => "?".to_string(),
+        };
+        *self.counts.lock().unwrap().entry(frame_type).or_default() += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), WriterError> {
+        Ok(())
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let channel = match std::env::args().nth(1) {
+        Some(channel) => channel.parse()?,
+        None => 13,
+    };
+
+    let sniffer = SnifferDevice::new(SnifferDevice::find_device(VENDOR, PRODUCT)?)?;
+    sniffer.verify()?;
+
+    let counts = Arc::new(Mutex::new(BTreeMap::new()));
+    let counter = FrameCounter {
+        counts: counts.clone(),
+    };
+    let session = CaptureSession::start(
+        sniffer,
+        channel,
+        vec![Box::new(counter)],
+        &SessionOptions::default(),
+    )?;
+
+    let started = Instant::now();
+    while started.elapsed() < CAPTURE_TIME {
+        match session.receive_packet() {
+            Ok(Some(packet)) => session.queue_packet(packet)?,
+            // Frames too short for the metadata, read timeouts and interleaved acks
+            Ok(None) | Err(SnifferError::TimeOut) | Err(SnifferError::NonPacketFrame(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let stats = session.finish()?;
+
+    println!("{} frames on channel {}", stats.packets, channel);
+    for (frame_type, count) in counts.lock().unwrap().iter() {
+        println!("{}: {}", frame_type, count);
+    }
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use crate::fcs::{TAP_FCS_CRC16, TAP_FCS_NONE};
+use crate::packet::CapturedPacket;
+use crate::pcaptap::{read_header, TapBlock};
+use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionOption;
+use pcap_file::pcapng::{Block, PcapNgReader};
+use pcap_file::{DataLink, PcapError};
+use std::io::Read;
+use std::time::Duration;
+use std::{error, fmt, io};
+
+// Microseconds, when the interface has no if_tsresol option
+const DEFAULT_TSRESOL: u8 = 6;
+
+#[derive(Debug)]
+pub enum AnalyzeError {
+    PcapError(PcapError),
+    NotTap(DataLink),
+    UnknownInterface(usize),
+    InvalidTap(usize, io::Error),
+    /// An FCS type other than none and 16-bit CRC
+    UnsupportedFcs(usize, u8),
+    UnsupportedTsResol(u8),
+}
+
+impl fmt::Display for AnalyzeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnalyzeError::PcapError(e) => write!(f, "not a readable pcapng file: {}", e),
+            AnalyzeError::NotTap(linktype) => write!(
+                f,
+                "link type {:?} is not IEEE 802.15.4 TAP, only captures written with --linktype tap can be analyzed",
+                linktype
+            ),
+            AnalyzeError::UnknownInterface(packet) => {
+                write!(f, "packet {}: no interface description", packet)
+            }
+            AnalyzeError::InvalidTap(packet, e) => write!(f, "packet {}: {}", packet, e),
+            AnalyzeError::UnsupportedFcs(packet, fcs_type) => write!(
+                f,
+                "packet {}: unsupported FCS type {}, only none and 16-bit CRC can be analyzed",
+                packet, fcs_type
+            ),
+            AnalyzeError::UnsupportedTsResol(tsresol) => {
+                write!(f, "unsupported timestamp resolution {:#04x}", tsresol)
+            }
+        }
+    }
+}
+
+impl From<PcapError> for AnalyzeError {
+    fn from(e: PcapError) -> Self {
+        AnalyzeError::PcapError(e)
+    }
+}
+
+impl error::Error for AnalyzeError {}
+
+/// Reads the packets back from a pcapng file as written by `CaptureWriter` with the TAP
+/// link type, with the radio metadata taken from the TAP TLVs
+pub fn read_tap_capture<R: Read>(r: R) -> Result<Vec<CapturedPacket>, AnalyzeError> {
+    let mut reader = PcapNgReader::new(r)?;
+    // Timestamp units per second of every interface
+    let mut interfaces = vec![];
+    let mut packets = vec![];
+
+    while let Some(block) = reader.next_block() {
+        match block? {
+            Block::InterfaceDescription(idb) => {
+                if idb.linktype != DataLink::IEEE802_15_4_TAP {
+                    return Err(AnalyzeError::NotTap(idb.linktype));
+                }
+                let tsresol = idb.options.iter().find_map(|o| match o {
+                    InterfaceDescriptionOption::IfTsResol(r) => Some(*r),
+                    _ => None,
+                });
+                let tsresol = tsresol.unwrap_or(DEFAULT_TSRESOL);
+                interfaces.push(
+                    units_per_second(tsresol).ok_or(AnalyzeError::UnsupportedTsResol(tsresol))?,
+                );
+            }
+            Block::EnhancedPacket(epb) => {
+                // Numbered from 1, like Wireshark does
+                let number = packets.len() + 1;
+                let units = match interfaces.get(epb.interface_id as usize) {
+                    Some(units) => *units,
+                    None => return Err(AnalyzeError::UnknownInterface(number)),
+                };
+                let (blocks, payload) =
+                    read_header(&epb.data).map_err(|e| AnalyzeError::InvalidTap(number, e))?;
+
+                let mut packet = CapturedPacket {
+                    timestamp: scale_timestamp(epb.timestamp.as_nanos(), units),
+                    channel: 0,
+                    rssi: 0,
+                    lqi: 0,
+                    payload: payload.to_vec(),
+                };
+                for block in blocks {
+                    match block {
+                        // The bytes are read back as written, with --fcs strip that is
+                        // without the last two
+                        TapBlock::FcsType(t) if t != TAP_FCS_CRC16 && t != TAP_FCS_NONE => {
+                            return Err(AnalyzeError::UnsupportedFcs(number, t))
+                        }
+                        TapBlock::TlvRssi(rssi) => packet.rssi = rssi.round() as i8,
+                        TapBlock::TlvLqi(lqi) => packet.lqi = lqi,
+                        TapBlock::ChannelAssignment(channel) => packet.channel = channel as u8,
+                        _ => {}
+                    }
+                }
+                packets.push(packet);
+            }
+            _ => {}
+        }
+    }
+    Ok(packets)
+}
+
+/// Units per second for an if_tsresol value: a negative power of 10, or of 2 with the
+/// upper bit set
+fn units_per_second(tsresol: u8) -> Option<u128> {
+    if tsresol & 0x80 == 0 {
+        10u128.checked_pow(tsresol as u32)
+    } else {
+        1u128.checked_shl((tsresol & 0x7f) as u32)
+    }
+}
+
+/// pcap-file reads the raw EPB timestamp as nanoseconds, this converts it from the units
+/// of the interface
+fn scale_timestamp(raw: u128, units: u128) -> Duration {
+    let nanos = raw % units * 1_000_000_000 / units;
+    Duration::new((raw / units) as u64, nanos as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze::{read_tap_capture, AnalyzeError};
+    use crate::fcs::FcsMode;
+    use crate::packet::CapturedPacket;
+    use crate::sink::PacketSink;
+    use crate::writer::{CaptureWriter, LinkType, TsResolution, WriterOptions};
+    use pcap_file::DataLink;
+    use std::time::Duration;
+
+    // tests/fixtures/frames.hex converted with `from-file --channel 15`
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/frames.pcapng");
+
+    fn write_capture(options: &WriterOptions) -> Vec<u8> {
+        let mut writer = CaptureWriter::new(Vec::new(), "test", options).unwrap();
+        let packet = CapturedPacket {
+            timestamp: Duration::from_secs(1),
+            channel: 13,
+            rssi: -45,
+            lqi: 120,
+            payload: vec![0x02, 0x00, 0x2a, 0xe0, 0x3b],
+        };
+        writer.write_packet(&packet).unwrap();
+        writer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn read_fixture() {
+        let packets = read_tap_capture(FIXTURE).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p.channel == 15));
+        assert_eq!(packets[0].payload, [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+        assert_eq!(packets[2].payload[..2], [0x03, 0x08]);
+    }
+
+    #[test]
+    fn read_radio_metadata() {
+        let packets = read_tap_capture(write_capture(&WriterOptions::default()).as_slice());
+        let packet = &packets.unwrap()[0];
+        assert_eq!(packet.timestamp, Duration::from_secs(1));
+        assert_eq!(packet.channel, 13);
+        assert_eq!(packet.rssi, -45);
+        assert_eq!(packet.lqi, 120);
+    }
+
+    #[test]
+    fn read_us_timestamps() {
+        let options = WriterOptions {
+            ts_resolution: TsResolution::Us,
+            ..Default::default()
+        };
+        let packets = read_tap_capture(write_capture(&options).as_slice()).unwrap();
+        assert_eq!(packets[0].timestamp, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn not_tap() {
+        let options = WriterOptions {
+            linktype: LinkType::WithFcs,
+            ..Default::default()
+        };
+        let result = read_tap_capture(write_capture(&options).as_slice());
+        assert!(matches!(
+            result,
+            Err(AnalyzeError::NotTap(DataLink::IEEE802_15_4))
+        ));
+
+        let result = read_tap_capture(&b"not a capture"[..]);
+        assert!(matches!(result, Err(AnalyzeError::PcapError(_))));
+    }
+
+    #[test]
+    fn fcs_types() {
+        for fcs in [FcsMode::None, FcsMode::Keep] {
+            let options = WriterOptions {
+                fcs,
+                ..Default::default()
+            };
+            let packets = read_tap_capture(write_capture(&options).as_slice()).unwrap();
+            assert_eq!(packets[0].payload, [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use crate::compress::Compression;
+use crate::expression::Expression;
+use crate::fcs::FcsMode;
+use crate::location::Location;
+use crate::mac::{Address, FrameType};
+use crate::packet::LqiMode;
+use crate::pcaptap::Phy;
+use crate::queue::OnFull;
+use crate::runlog::LogMode;
+use crate::sink::OutputFormat;
+use crate::stats::valid_rssi_decay;
+use crate::writer::{LinkType, TsResolution};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::{error, fmt, io};
+
+/// Defaults for the command line options, read from a TOML file. Every key is optional
+/// and unknown keys are rejected, so a typo doesn't silently fall back to the default.
+///
+/// ```toml
+/// channel = 15
+/// capture-file = "zigbee.pcap"
+/// fcs = "validate"
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub channel: Option<u8>,
+    pub capture_file: Option<PathBuf>,
+    pub fcs: Option<FcsMode>,
+    pub snaplen: Option<u32>,
+    pub out_format: Option<OutputFormat>,
+    pub compress: Option<Compression>,
+    pub linktype: Option<LinkType>,
+    pub radio_comments: Option<bool>,
+    pub comment: Option<Vec<String>>,
+    pub location: Option<Location>,
+    pub raw_out: Option<PathBuf>,
+    pub beacons: Option<bool>,
+    pub allow_type: Option<Vec<FrameType>>,
+    pub deny_type: Option<Vec<FrameType>>,
+    pub allow_addr: Option<Vec<Address>>,
+    pub deny_addr: Option<Vec<Address>>,
+    pub filter: Option<Expression>,
+    pub trigger: Option<Expression>,
+    pub pretrigger: Option<usize>,
+    pub stop_on: Option<Expression>,
+    pub stop_after: Option<u64>,
+    pub timeslot_length: Option<u32>,
+    pub phy: Option<Phy>,
+    pub drop_unparseable: Option<bool>,
+    pub drop_inconsistent: Option<bool>,
+    pub summary: Option<PathBuf>,
+    pub summary_only: Option<bool>,
+    pub histograms: Option<PathBuf>,
+    pub index: Option<PathBuf>,
+    pub checksum_seed: Option<u8>,
+    pub strict: Option<bool>,
+    pub max_frame_len: Option<usize>,
+    pub strict_version: Option<bool>,
+    pub match_name: Option<String>,
+    pub usb_config: Option<u8>,
+    pub in_endpoint: Option<u8>,
+    pub out_endpoint: Option<u8>,
+    pub command_delay: Option<u64>,
+    pub settle_ms: Option<u64>,
+    pub wait: Option<bool>,
+    pub plug_debounce_ms: Option<u64>,
+    pub queue_depth: Option<usize>,
+    pub on_full: Option<OnFull>,
+    pub rssi_decay: Option<f64>,
+    pub top_talkers: Option<usize>,
+    pub no_sniff_off: Option<bool>,
+    pub lqi_mode: Option<LqiMode>,
+    pub quiet: Option<bool>,
+    pub log_file: Option<PathBuf>,
+    pub log_mode: Option<LogMode>,
+    pub idle_backoff: Option<u64>,
+    pub startup_check: Option<u64>,
+    pub keepalive_interval: Option<u64>,
+    pub heartbeat: Option<u64>,
+    pub lowpan_fragments: Option<bool>,
+    pub direction_flags: Option<bool>,
+    pub ts_resolution: Option<TsResolution>,
+    pub relative_time: Option<bool>,
+    pub interactive: Option<bool>,
+    pub batch_size: Option<usize>,
+    pub flush_interval: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    ParseError(toml::de::Error),
+    InvalidChannel(u8),
+    InvalidRssiDecay(f64),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::IoError(e) => write!(f, "io error: {}", e),
+            ConfigError::ParseError(e) => write!(f, "{}", e),
+            ConfigError::InvalidChannel(c) => {
+                write!(
+                    f,
+                    "channel {} is not a channel of the PHY, 11-26 for oqpsk-2450",
+                    c
+                )
+            }
+            ConfigError::InvalidRssiDecay(d) => {
+                write!(f, "rssi decay {} is not between 0 and 1", d)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::IoError(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::ParseError(e)
+    }
+}
+
+impl error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Checks the values clap would have rejected on the command line
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match (self.channel, self.rssi_decay) {
+            (Some(c), _) if !self.phy.unwrap_or_default().channels().contains(&c) => {
+                Err(ConfigError::InvalidChannel(c))
+            }
+            (_, Some(d)) if !valid_rssi_decay(d) => Err(ConfigError::InvalidRssiDecay(d)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ConfigError};
+    use crate::expression::Expression;
+    use crate::fcs::FcsMode;
+    use crate::mac::{Address, FrameType};
+    use crate::pcaptap::Phy;
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = "channel = 15\ncapture-file = \"zigbee.pcap\"\nfcs = \"validate\"\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                channel: Some(15),
+                capture_file: Some(PathBuf::from("zigbee.pcap")),
+                fcs: Some(FcsMode::Validate),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn frame_filter() {
+        let config: Config = "allow-type = [\"data\"]\ndeny-addr = [\"0xabcd\"]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(config.allow_type, Some(vec![FrameType::Data]));
+        assert_eq!(config.deny_addr, Some(vec![Address::Short(0xabcd)]));
+
+        let result = "deny-type = [\"frame\"]\n".parse::<Config>();
+        assert!(matches!(result, Err(ConfigError::ParseError(_))));
+
+        let config: Config = "filter = \"type == ack\"\n".parse().unwrap();
+        assert_eq!(config.filter, Some(Expression::Type(FrameType::Ack)));
+        let result = "filter = \"type <\"\n".parse::<Config>();
+        assert!(matches!(result, Err(ConfigError::ParseError(_))));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let result = "chanel = 15\n".parse::<Config>();
+        match result {
+            Err(ConfigError::ParseError(e)) => assert!(e.to_string().contains("chanel")),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn invalid_channel() {
+        let result = "channel = 27\n".parse::<Config>();
+        assert!(matches!(result, Err(ConfigError::InvalidChannel(27))));
+
+        let result = "channel = 5\nphy = \"bpsk-915\"\n".parse::<Config>();
+        assert_eq!(result.unwrap().phy, Some(Phy::Bpsk915));
+        let result = "channel = 11\nphy = \"bpsk-915\"\n".parse::<Config>();
+        assert!(matches!(result, Err(ConfigError::InvalidChannel(11))));
+    }
+
+    #[test]
+    fn invalid_rssi_decay() {
+        let result = "rssi-decay = 0.0\n".parse::<Config>();
+        assert!(matches!(result, Err(ConfigError::InvalidRssiDecay(_))));
+    }
+}
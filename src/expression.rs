@@ -0,0 +1,484 @@
+use crate::fcs::FCS_LEN;
+use crate::mac::{Address, FrameType, MacHeader};
+use crate::packet::CapturedPacket;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// A filter expression like `type==data && pan==0x1234 && rssi>-60`
+///
+/// Comparisons are combined with `&&`, `||` and `!`, `&&` binds tighter than `||` and
+/// parentheses group. The fields are:
+///
+/// - `type`: the frame type, beacon, data, ack, cmd or type4 to type7.
+/// - `src`, `dst`: the source or destination address, `addr` matches either.
+/// - `pan`: the destination or source PAN id.
+/// - `seq`, `len`: the sequence number and the MPDU length including the FCS.
+/// - `rssi`, `lqi`, `channel`: the radio metadata.
+///
+/// Every field takes `==` and `!=`, the numbers `seq`, `len`, `rssi`, `lqi` and `channel`
+/// also `<`, `<=`, `>` and `>=`. Numbers are decimal or hex with `0x`. A frame whose MAC
+/// header doesn't have the field, like an ack without addresses, never equals a value.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(try_from = "String")
+)]
+pub enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Type(FrameType),
+    Src(Address),
+    Dst(Address),
+    Addr(Address),
+    Pan(u16),
+    Compare(Number, Comparison, i64),
+}
+
+/// The fields that can be ordered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Number {
+    Seq,
+    Len,
+    Rssi,
+    Lqi,
+    Channel,
+}
+
+/// `!=` is parsed as a negated `==`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Expression {
+    /// Whether the packet passes the filter
+    pub fn matches(&self, packet: &CapturedPacket) -> bool {
+        let payload = &packet.payload;
+        let header = MacHeader::parse(&payload[..payload.len().saturating_sub(FCS_LEN)]).ok();
+        self.evaluate(packet, header.as_ref())
+    }
+
+    fn evaluate(&self, packet: &CapturedPacket, header: Option<&MacHeader>) -> bool {
+        match self {
+            Expression::And(a, b) => a.evaluate(packet, header) && b.evaluate(packet, header),
+            Expression::Or(a, b) => a.evaluate(packet, header) || b.evaluate(packet, header),
+            Expression::Not(e) => !e.evaluate(packet, header),
+            // The frame type only needs the frame control field
+            Expression::Type(t) => packet
+                .payload
+                .get(..2)
+                .is_some_and(|fc| FrameType::from(u16::from_le_bytes([fc[0], fc[1]])) == *t),
+            Expression::Src(a) => header.is_some_and(|h| h.src == Some(*a)),
+            Expression::Dst(a) => header.is_some_and(|h| h.dst == Some(*a)),
+            Expression::Addr(a) => header.is_some_and(|h| h.src == Some(*a) || h.dst == Some(*a)),
+            Expression::Pan(pan) => {
+                header.is_some_and(|h| h.dst_pan == Some(*pan) || h.src_pan == Some(*pan))
+            }
+            Expression::Compare(number, comparison, value) => {
+                let field = match number {
+                    Number::Seq => match header {
+                        Some(h) => h.sequence as i64,
+                        None => return false,
+                    },
+                    Number::Len => packet.payload.len() as i64,
+                    Number::Rssi => packet.rssi as i64,
+                    Number::Lqi => packet.lqi as i64,
+                    Number::Channel => packet.channel as i64,
+                };
+                match comparison {
+                    Comparison::Eq => field == *value,
+                    Comparison::Lt => field < *value,
+                    Comparison::Le => field <= *value,
+                    Comparison::Gt => field > *value,
+                    Comparison::Ge => field >= *value,
+                }
+            }
+        }
+    }
+}
+
+/// What is wrong with an expression, the positions count characters from 0
+#[derive(Debug, PartialEq)]
+pub enum ExpressionError {
+    UnexpectedChar(char, usize),
+    UnexpectedEnd,
+    Unexpected(String, usize),
+    UnknownField(String, usize),
+    BadValue(String, usize, String),
+    NotOrdered(String, usize),
+}
+
+impl Display for ExpressionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExpressionError::UnexpectedChar(c, at) => {
+                write!(f, "unexpected character '{}' at {}", c, at)
+            }
+            ExpressionError::UnexpectedEnd => write!(f, "the expression ends too early"),
+            ExpressionError::Unexpected(token, at) => {
+                write!(f, "unexpected '{}' at {}", token, at)
+            }
+            ExpressionError::UnknownField(field, at) => write!(
+                f,
+                "unknown field '{}' at {}, expected type, src, dst, addr, pan, seq, len, rssi, lqi or channel",
+                field, at
+            ),
+            ExpressionError::BadValue(value, at, problem) => {
+                write!(f, "bad value '{}' at {}: {}", value, at, problem)
+            }
+            ExpressionError::NotOrdered(field, at) => {
+                write!(f, "{} at {} can only be compared with == and !=", field, at)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Word(String),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Not => "!",
+            Token::Eq => "==",
+            Token::Ne => "!=",
+            Token::Lt => "<",
+            Token::Le => "<=",
+            Token::Gt => ">",
+            Token::Ge => ">=",
+            Token::Word(w) => w,
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Splits the expression into tokens with their positions. A word is a field name or a
+/// value, addresses and negative numbers included.
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let chars: Vec<char> = s.chars().collect();
+    let word_char = |c: char| c.is_ascii_alphanumeric() || c == ':' || c == '-' || c == '_';
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Eq, 2),
+            ('!', Some('=')) => (Token::Ne, 2),
+            ('!', _) => (Token::Not, 1),
+            ('<', Some('=')) => (Token::Le, 2),
+            ('<', _) => (Token::Lt, 1),
+            ('>', Some('=')) => (Token::Ge, 2),
+            ('>', _) => (Token::Gt, 1),
+            (c, _) if word_char(c) => {
+                let len = chars[i..].iter().take_while(|c| word_char(**c)).count();
+                (Token::Word(chars[i..i + len].iter().collect()), len)
+            }
+            (c, _) => return Err(ExpressionError::UnexpectedChar(c, i)),
+        };
+        tokens.push((i, token));
+        i += len;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens, one function per precedence level
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, t)| t)
+    }
+
+    fn take(&mut self) -> Result<(usize, Token), ExpressionError> {
+        let token = self
+            .tokens
+            .get(self.next)
+            .cloned()
+            .ok_or(ExpressionError::UnexpectedEnd)?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<Expression, ExpressionError> {
+        let mut expression = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, ExpressionError> {
+        let mut expression = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next += 1;
+            expression = Expression::And(Box::new(expression), Box::new(self.not()?));
+        }
+        Ok(expression)
+    }
+
+    fn not(&mut self) -> Result<Expression, ExpressionError> {
+        match self.take()? {
+            (_, Token::Not) => Ok(Expression::Not(Box::new(self.not()?))),
+            (_, Token::LParen) => {
+                let expression = self.or()?;
+                match self.take()? {
+                    (_, Token::RParen) => Ok(expression),
+                    (at, token) => Err(ExpressionError::Unexpected(token.to_string(), at)),
+                }
+            }
+            (at, Token::Word(field)) => self.comparison(field, at),
+            (at, token) => Err(ExpressionError::Unexpected(token.to_string(), at)),
+        }
+    }
+
+    fn comparison(&mut self, field: String, at: usize) -> Result<Expression, ExpressionError> {
+        let (comparison, negate) = match self.take()? {
+            (_, Token::Eq) => (Comparison::Eq, false),
+            (_, Token::Ne) => (Comparison::Eq, true),
+            (_, Token::Lt) => (Comparison::Lt, false),
+            (_, Token::Le) => (Comparison::Le, false),
+            (_, Token::Gt) => (Comparison::Gt, false),
+            (_, Token::Ge) => (Comparison::Ge, false),
+            (at, token) => return Err(ExpressionError::Unexpected(token.to_string(), at)),
+        };
+        let (value_at, value) = match self.take()? {
+            (value_at, Token::Word(value)) => (value_at, value),
+            (at, token) => return Err(ExpressionError::Unexpected(token.to_string(), at)),
+        };
+        let bad_value =
+            |problem: String| ExpressionError::BadValue(value.clone(), value_at, problem);
+        let number = || parse_number(&value).ok_or_else(|| bad_value("not a number".into()));
+
+        let expression = match field.as_str() {
+            "seq" => Expression::Compare(Number::Seq, comparison, number()?),
+            "len" => Expression::Compare(Number::Len, comparison, number()?),
+            "rssi" => Expression::Compare(Number::Rssi, comparison, number()?),
+            "lqi" => Expression::Compare(Number::Lqi, comparison, number()?),
+            "channel" => Expression::Compare(Number::Channel, comparison, number()?),
+            "type" | "src" | "dst" | "addr" | "pan" if comparison != Comparison::Eq => {
+                return Err(ExpressionError::NotOrdered(field, at))
+            }
+            "type" => Expression::Type(value.parse().map_err(|e| bad_value(format!("{}", e)))?),
+            "src" => Expression::Src(value.parse().map_err(|e| bad_value(format!("{}", e)))?),
+            "dst" => Expression::Dst(value.parse().map_err(|e| bad_value(format!("{}", e)))?),
+            "addr" => Expression::Addr(value.parse().map_err(|e| bad_value(format!("{}", e)))?),
+            "pan" => Expression::Pan(
+                parse_number(&value)
+                    .and_then(|n| u16::try_from(n).ok())
+                    .ok_or_else(|| bad_value("not a PAN id".into()))?,
+            ),
+            _ => return Err(ExpressionError::UnknownField(field, at)),
+        };
+        Ok(if negate {
+            Expression::Not(Box::new(expression))
+        } else {
+            expression
+        })
+    }
+}
+
+/// A decimal number, negative for the RSSI, or a hex number with `0x`
+fn parse_number(s: &str) -> Option<i64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            i64::from_str_radix(hex, 16).ok()
+        }
+        Some(_) => None,
+        None => s.parse().ok(),
+    }
+}
+
+impl FromStr for Expression {
+    type Err = ExpressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            next: 0,
+        };
+        let expression = parser.or()?;
+        match parser.tokens.get(parser.next) {
+            Some((at, token)) => Err(ExpressionError::Unexpected(token.to_string(), *at)),
+            None => Ok(expression),
+        }
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = ExpressionError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expression::{Comparison, Expression, ExpressionError, Number};
+    use crate::mac::{Address, FrameType};
+    use crate::packet::CapturedPacket;
+    use std::time::Duration;
+
+    // A data frame from 0xABCD to 0x5678 in PAN 0x1234 and an Imm-Ack, both with an FCS
+    const DATA: [u8; 12] = [
+        0x61, 0x88, 0x2a, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0xff, 0x00, 0x00,
+    ];
+    const ACK: [u8; 5] = [0x02, 0x00, 0x2a, 0xe0, 0x3b];
+
+    fn packet(payload: &[u8], rssi: i8) -> CapturedPacket {
+        CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 15,
+            rssi,
+            lqi: 100,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn matches(expression: &str, packet: &CapturedPacket) -> bool {
+        expression.parse::<Expression>().unwrap().matches(packet)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "type==data && rssi>-60".parse(),
+            Ok(Expression::And(
+                Box::new(Expression::Type(FrameType::Data)),
+                Box::new(Expression::Compare(Number::Rssi, Comparison::Gt, -60))
+            ))
+        );
+        assert_eq!(
+            "src != 0xabcd".parse(),
+            Ok(Expression::Not(Box::new(Expression::Src(Address::Short(
+                0xabcd
+            )))))
+        );
+        assert_eq!(
+            "addr==00:12:4b:00:1a:2b:3c:4d".parse(),
+            Ok(Expression::Addr(Address::Extended(0x00124b001a2b3c4d)))
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let a = || Box::new(Expression::Compare(Number::Seq, Comparison::Eq, 1));
+        let b = || Box::new(Expression::Compare(Number::Seq, Comparison::Eq, 2));
+        let c = || Box::new(Expression::Compare(Number::Seq, Comparison::Eq, 3));
+        // && binds tighter than ||
+        assert_eq!(
+            "seq==1 || seq==2 && seq==3".parse(),
+            Ok(Expression::Or(a(), Box::new(Expression::And(b(), c()))))
+        );
+        assert_eq!(
+            "(seq==1 || seq==2) && seq==3".parse(),
+            Ok(Expression::And(Box::new(Expression::Or(a(), b())), c()))
+        );
+        // ! applies to the next comparison only
+        assert_eq!(
+            "!seq==1 && seq==2".parse(),
+            Ok(Expression::And(Box::new(Expression::Not(a())), b()))
+        );
+    }
+
+    #[test]
+    fn evaluate() {
+        let data = packet(&DATA, -50);
+        let ack = packet(&ACK, -70);
+
+        assert!(matches("type==data && pan==0x1234 && rssi>-60", &data));
+        assert!(!matches("type==data && pan==0x1234 && rssi>-60", &ack));
+        assert!(matches("src==0xabcd && dst==0x5678", &data));
+        assert!(matches("addr==0x5678 && addr==0xabcd", &data));
+        assert!(matches("type==ack || rssi<=-70", &ack));
+        assert!(matches(
+            "seq==42 && len==5 && channel==15 && lqi>=100",
+            &ack
+        ));
+        assert!(matches("!(type==ack)", &data));
+
+        // An ack has no addresses or PAN id
+        assert!(!matches("src==0xabcd", &ack));
+        assert!(matches("src!=0xabcd", &ack));
+        assert!(!matches("pan==0x1234", &ack));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |s: &str| s.parse::<Expression>().unwrap_err();
+        assert_eq!(error(""), ExpressionError::UnexpectedEnd);
+        assert_eq!(error("type=="), ExpressionError::UnexpectedEnd);
+        assert_eq!(
+            error("rssi > -60 $"),
+            ExpressionError::UnexpectedChar('$', 11)
+        );
+        assert_eq!(
+            error("speed > 3"),
+            ExpressionError::UnknownField("speed".to_string(), 0)
+        );
+        assert_eq!(
+            error("type == data data"),
+            ExpressionError::Unexpected("data".to_string(), 13)
+        );
+        assert_eq!(error("(type == data"), ExpressionError::UnexpectedEnd);
+        assert_eq!(
+            error("src < 0x1234"),
+            ExpressionError::NotOrdered("src".to_string(), 0)
+        );
+        assert!(matches!(
+            error("rssi > loud"),
+            ExpressionError::BadValue(v, 7, _) if v == "loud"
+        ));
+        assert!(matches!(
+            error("pan == 0x12345"),
+            ExpressionError::BadValue(_, 7, _)
+        ));
+        assert!(matches!(
+            error("src == 0x"),
+            ExpressionError::BadValue(_, 7, _)
+        ));
+        assert_eq!(error("rssi > -60 &&"), ExpressionError::UnexpectedEnd);
+        assert_eq!(
+            error("rssi -60"),
+            ExpressionError::Unexpected("-60".to_string(), 5)
+        );
+    }
+}
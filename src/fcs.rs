@@ -0,0 +1,118 @@
+use clap::ValueEnum;
+use crc::{Crc, CRC_16_KERMIT};
+
+// IEEE 802.15.4 uses the ITU-T CRC-16, which is known as CRC-16/KERMIT in the crc catalog
+const FCS_CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_KERMIT);
+pub const FCS_LEN: usize = 2;
+
+// Values for the TAP FCS type TLV
+pub const TAP_FCS_NONE: u8 = 0;
+pub const TAP_FCS_CRC16: u8 = 1;
+
+/// What happens with the two byte FCS at the end of every MPDU
+///
+/// - none: the frame is written as received, the TAP FCS type is none. The default, the
+///   CC2531 radio can put the RSSI and the CRC status in place of the FCS, so the last two
+///   bytes are only declared an FCS when asked to.
+/// - keep: the MPDU is written as received, FCS included. The TAP FCS type is 16-bit CRC.
+/// - strip: the last two bytes are removed before writing. The TAP FCS type is none.
+/// - validate: as keep, but frames where the FCS doesn't match the payload are not written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum FcsMode {
+    #[default]
+    None,
+    Keep,
+    Strip,
+    Validate,
+}
+
+impl FcsMode {
+    /// Value for the TAP FCS type TLV that matches the bytes written in this mode
+    pub fn tap_fcs_type(&self) -> u8 {
+        match self {
+            FcsMode::Keep | FcsMode::Validate => TAP_FCS_CRC16,
+            FcsMode::None | FcsMode::Strip => TAP_FCS_NONE,
+        }
+    }
+
+    /// Returns the bytes to write for this frame, or None if the frame should be dropped
+    pub fn apply(&self, mut frame: Vec<u8>) -> Option<Vec<u8>> {
+        match self {
+            FcsMode::None | FcsMode::Keep => Some(frame),
+            FcsMode::Strip => {
+                frame.truncate(frame.len().saturating_sub(FCS_LEN));
+                Some(frame)
+            }
+            FcsMode::Validate => {
+                if fcs_valid(&frame) {
+                    Some(frame)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Checks the trailing FCS (little endian) against the rest of the frame
+pub fn fcs_valid(frame: &[u8]) -> bool {
+    if frame.len() < FCS_LEN {
+        return false;
+    }
+
+    let (data, fcs) = frame.split_at(frame.len() - FCS_LEN);
+    FCS_CRC.checksum(data) == u16::from_le_bytes([fcs[0], fcs[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fcs::{fcs_valid, FcsMode, TAP_FCS_CRC16, TAP_FCS_NONE};
+
+    // Imm-Ack for sequence number 42 with a correct FCS
+    const ACK: [u8; 5] = [0x02, 0x00, 0x2a, 0xe0, 0x3b];
+
+    #[test]
+    fn valid_fcs() {
+        assert!(fcs_valid(&ACK));
+        assert!(!fcs_valid(&[0x02, 0x00, 0x2a, 0xe0, 0x3c]));
+        assert!(!fcs_valid(&[0x02]));
+    }
+
+    #[test]
+    fn none() {
+        assert_eq!(FcsMode::default(), FcsMode::None);
+        assert_eq!(FcsMode::None.apply(ACK.to_vec()), Some(ACK.to_vec()));
+        assert_eq!(FcsMode::None.tap_fcs_type(), TAP_FCS_NONE);
+    }
+
+    #[test]
+    fn keep() {
+        assert_eq!(FcsMode::Keep.apply(ACK.to_vec()), Some(ACK.to_vec()));
+        assert_eq!(FcsMode::Keep.tap_fcs_type(), TAP_FCS_CRC16);
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(
+            FcsMode::Strip.apply(ACK.to_vec()),
+            Some(vec![0x02, 0x00, 0x2a])
+        );
+        assert_eq!(FcsMode::Strip.apply(vec![0x02]), Some(vec![]));
+        assert_eq!(FcsMode::Strip.tap_fcs_type(), TAP_FCS_NONE);
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(FcsMode::Validate.apply(ACK.to_vec()), Some(ACK.to_vec()));
+        assert_eq!(
+            FcsMode::Validate.apply(vec![0x02, 0x00, 0x2a, 0x00, 0x00]),
+            None
+        );
+        assert_eq!(FcsMode::Validate.tap_fcs_type(), TAP_FCS_CRC16);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::expression::Expression;
+use crate::fcs::FCS_LEN;
+use crate::mac::{Address, FrameType, MacHeader};
+use crate::packet::CapturedPacket;
+
+/// Which frames to keep by frame type and address, e.g. all data frames except the ones
+/// from one device. The rules combine in this order:
+///
+/// 1. A frame that matches a denied type or address is left out.
+/// 2. When types are allowed, the frame's type must be one of them.
+/// 3. When addresses are allowed, the source or destination must be one of them.
+///
+/// Addresses match the source and the destination. A frame without addresses that can be
+/// parsed matches no address rule, so it is left out as soon as addresses are allowed.
+///
+/// An expression has to match too, see `Expression`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameFilter {
+    pub allow_types: Vec<FrameType>,
+    pub deny_types: Vec<FrameType>,
+    pub allow_addresses: Vec<Address>,
+    pub deny_addresses: Vec<Address>,
+    pub expression: Option<Expression>,
+}
+
+impl FrameFilter {
+    /// Keeps every frame
+    pub fn is_empty(&self) -> bool {
+        self.allow_types.is_empty()
+            && self.deny_types.is_empty()
+            && self.allow_addresses.is_empty()
+            && self.deny_addresses.is_empty()
+            && self.expression.is_none()
+    }
+
+    /// Whether the packet passes the filter, `keep` with the expression on top
+    pub fn keep_packet(&self, packet: &CapturedPacket) -> bool {
+        self.keep(&packet.payload) && self.expression.as_ref().is_none_or(|e| e.matches(packet))
+    }
+
+    /// Whether the MPDU passes the filter
+    pub fn keep(&self, frame: &[u8]) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let frame_type = frame
+            .get(..2)
+            .map(|fc| FrameType::from(u16::from_le_bytes([fc[0], fc[1]])));
+        let addresses: Vec<Address> = match MacHeader::parse(frame) {
+            Ok(header) => header.src.into_iter().chain(header.dst).collect(),
+            Err(_) => vec![],
+        };
+        let has_type = |types: &[FrameType]| frame_type.is_some_and(|t| types.contains(&t));
+        let has_address = |allowed: &[Address]| addresses.iter().any(|a| allowed.contains(a));
+
+        if has_type(&self.deny_types) || has_address(&self.deny_addresses) {
+            return false;
+        }
+        (self.allow_types.is_empty() || has_type(&self.allow_types))
+            && (self.allow_addresses.is_empty() || has_address(&self.allow_addresses))
+    }
+}
+
+/// Ends a capture at the first frame that matches the expression, or `after` frames
+/// later. The frames are recorded in the order they are written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StopCondition {
+    expression: Expression,
+    after: u64,
+    remaining: Option<u64>,
+}
+
+impl StopCondition {
+    pub fn new(expression: Expression, after: u64) -> Self {
+        StopCondition {
+            expression,
+            after,
+            remaining: None,
+        }
+    }
+
+    /// Records a frame that is written, returns true when it is the last one
+    pub fn record(&mut self, packet: &CapturedPacket) -> bool {
+        match &mut self.remaining {
+            None if self.expression.matches(packet) => self.remaining = Some(self.after),
+            None => {}
+            Some(remaining) => *remaining = remaining.saturating_sub(1),
+        }
+        self.reached()
+    }
+
+    /// Whether the last frame was recorded, nothing should be written after it
+    pub fn reached(&self) -> bool {
+        self.remaining == Some(0)
+    }
+}
+
+/// Whether the frame control field and the addressing of an MPDU with its FCS can be
+/// decoded. Says nothing about the FCS itself.
+pub fn mac_header_parses(frame: &[u8]) -> bool {
+    frame.len() >= FCS_LEN && MacHeader::parse(&frame[..frame.len() - FCS_LEN]).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{mac_header_parses, FrameFilter, StopCondition};
+    use crate::mac::{Address, FrameType};
+    use crate::packet::CapturedPacket;
+    use std::time::Duration;
+
+    // Data frames from 0xABCD and 0x1234 to 0x5678, an Imm-Ack and a beacon from 0x0000
+    const FROM_ABCD: [u8; 10] = [0x61, 0x88, 0x2a, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0xff];
+    const FROM_1234: [u8; 10] = [0x61, 0x88, 0x2b, 0x34, 0x12, 0x78, 0x56, 0x34, 0x12, 0xff];
+    const ACK: [u8; 5] = [0x02, 0x00, 0x2a, 0xe0, 0x3b];
+    const BEACON: [u8; 9] = [0x00, 0x80, 0x01, 0x34, 0x12, 0x00, 0x00, 0xff, 0xcf];
+
+    fn kept(filter: &FrameFilter) -> [bool; 4] {
+        [FROM_ABCD.as_slice(), &FROM_1234, &ACK, &BEACON].map(|f| filter.keep(f))
+    }
+
+    #[test]
+    fn empty_keeps_everything() {
+        let filter = FrameFilter::default();
+        assert!(filter.is_empty());
+        assert_eq!(kept(&filter), [true; 4]);
+        assert!(filter.keep(&[]));
+    }
+
+    #[test]
+    fn allow_and_deny_types() {
+        let filter = FrameFilter {
+            allow_types: vec![FrameType::Data, FrameType::Ack],
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), [true, true, true, false]);
+
+        let filter = FrameFilter {
+            deny_types: vec![FrameType::Ack],
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), [true, true, false, true]);
+        // A frame too short for a frame control field has no type to deny
+        assert!(filter.keep(&[0x02]));
+
+        // Deny takes precedence over allow
+        let filter = FrameFilter {
+            allow_types: vec![FrameType::Data, FrameType::Ack],
+            deny_types: vec![FrameType::Ack],
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), [true, true, false, false]);
+    }
+
+    #[test]
+    fn data_except_from_address() {
+        let filter = FrameFilter {
+            allow_types: vec![FrameType::Data],
+            deny_addresses: vec![Address::Short(0xabcd)],
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), [false, true, false, false]);
+    }
+
+    #[test]
+    fn with_expression() {
+        let filter = FrameFilter {
+            deny_types: vec![FrameType::Beacon],
+            expression: Some("rssi > -60".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(!filter.is_empty());
+        let packet = |payload: &[u8], rssi| CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 15,
+            rssi,
+            lqi: 100,
+            payload: payload.to_vec(),
+        };
+        assert!(filter.keep_packet(&packet(&ACK, -50)));
+        assert!(!filter.keep_packet(&packet(&ACK, -70)));
+        assert!(!filter.keep_packet(&packet(&BEACON, -50)));
+    }
+
+    #[test]
+    fn stop_condition() {
+        // The expression expects an FCS
+        let packet = |payload: &[u8]| CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 15,
+            rssi: -50,
+            lqi: 100,
+            payload: [payload, &[0x00, 0x00]].concat(),
+        };
+        let frames = [
+            &ACK[..],
+            &FROM_1234,
+            &ACK,
+            &FROM_ABCD,
+            &ACK,
+            &ACK,
+            &ACK,
+            &FROM_ABCD,
+        ];
+
+        // The index of the last frame written, if the capture stopped
+        let last = |condition: &mut StopCondition| {
+            frames.iter().position(|f| condition.record(&packet(f)))
+        };
+        let src = || "src==0xabcd".parse().unwrap();
+        let mut condition = StopCondition::new(src(), 0);
+        assert_eq!(last(&mut condition), Some(3));
+        assert!(condition.reached());
+        assert_eq!(last(&mut StopCondition::new(src(), 2)), Some(5));
+        // Frames after the match count, also when they match again
+        assert_eq!(last(&mut StopCondition::new(src(), 4)), Some(7));
+        assert_eq!(last(&mut StopCondition::new(src(), 5)), None);
+        assert_eq!(
+            last(&mut StopCondition::new("type==beacon".parse().unwrap(), 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn header_parses() {
+        // The FCS isn't part of the header
+        let with_fcs = [FROM_ABCD.as_slice(), &[0x00, 0x00]].concat();
+        assert!(mac_header_parses(&with_fcs));
+        assert!(mac_header_parses(&ACK));
+        assert!(mac_header_parses(&BEACON));
+        // Cut off in the source address
+        assert!(!mac_header_parses(&FROM_ABCD[..9]));
+        // The reserved address mode
+        assert!(!mac_header_parses(&[
+            0x01, 0x04, 0x2a, 0x34, 0x12, 0x78, 0x56, 0xff, 0xff
+        ]));
+        assert!(!mac_header_parses(&[0x02]));
+    }
+
+    #[test]
+    fn allow_and_deny_addresses() {
+        // The destination matches too
+        let filter = FrameFilter {
+            allow_addresses: vec![Address::Short(0x5678)],
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), [true, true, false, false]);
+
+        let filter = FrameFilter {
+            allow_addresses: vec![Address::Short(0x5678)],
+            deny_addresses: vec![Address::Short(0x1234)],
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), [true, false, false, false]);
+
+        // Frames without addresses pass a deny rule
+        let filter = FrameFilter {
+            deny_addresses: vec![Address::Short(0x5678)],
+            ..Default::default()
+        };
+        assert_eq!(kept(&filter), [false, false, true, true]);
+    }
+}
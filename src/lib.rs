@@ -0,0 +1,24 @@
+pub mod analyze;
+pub mod compress;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod expression;
+pub mod fcs;
+pub mod filter;
+pub mod location;
+pub mod lowpan;
+pub mod mac;
+pub mod packet;
+pub mod pcaptap;
+pub mod protocol;
+pub mod queue;
+pub mod replay;
+pub mod resume;
+pub mod runlog;
+pub mod selfcheck;
+pub mod session;
+pub mod sink;
+pub mod sniffer;
+pub mod stats;
+pub mod summary;
+pub mod writer;
@@ -0,0 +1,177 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+#[cfg(feature = "nmea")]
+use std::{
+    io::{self, BufRead},
+    sync::{Arc, Mutex},
+};
+
+/// Where the sniffer was, in decimal degrees north and east
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(try_from = "String")
+)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// `lat,lon` with six decimals, about 0.1 m
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.6},{:.6}", self.latitude, self.longitude)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseLocationError(String);
+
+impl Display for ParseLocationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid location {}, expected decimal degrees like 52.370216,4.895168",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseLocationError {}
+
+/// Parses `lat,lon` in decimal degrees, south and west are negative
+impl FromStr for Location {
+    type Err = ParseLocationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseLocationError(s.to_string());
+        let (latitude, longitude) = s.split_once(',').ok_or_else(invalid)?;
+        let latitude: f64 = latitude.trim().parse().map_err(|_| invalid())?;
+        let longitude: f64 = longitude.trim().parse().map_err(|_| invalid())?;
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(invalid());
+        }
+        Ok(Location {
+            latitude,
+            longitude,
+        })
+    }
+}
+
+impl TryFrom<String> for Location {
+    type Error = ParseLocationError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The position of a GGA or RMC sentence from any talker, None for other sentences,
+/// sentences without a fix and ones with a wrong checksum
+#[cfg(feature = "nmea")]
+pub fn parse_nmea(sentence: &str) -> Option<Location> {
+    let body = sentence.trim().strip_prefix('$')?;
+    let body = match body.split_once('*') {
+        Some((body, checksum)) => {
+            let expected = u8::from_str_radix(checksum, 16).ok()?;
+            (body.bytes().fold(0, |sum, b| sum ^ b) == expected).then_some(body)?
+        }
+        None => body,
+    };
+
+    let fields: Vec<&str> = body.split(',').collect();
+    let position = match fields.first()?.get(2..)? {
+        "GGA" if fields.get(6).is_some_and(|q| !q.is_empty() && *q != "0") => fields.get(2..6)?,
+        "RMC" if fields.get(2) == Some(&"A") => fields.get(3..7)?,
+        _ => return None,
+    };
+    Some(Location {
+        latitude: nmea_degrees(position[0], position[1], "N", "S")?,
+        longitude: nmea_degrees(position[2], position[3], "E", "W")?,
+    })
+}
+
+/// Converts `dddmm.mmmm` and the hemisphere to signed decimal degrees
+#[cfg(feature = "nmea")]
+fn nmea_degrees(value: &str, hemisphere: &str, positive: &str, negative: &str) -> Option<f64> {
+    let value: f64 = value.parse().ok()?;
+    let degrees = (value / 100.0).trunc() + (value % 100.0) / 60.0;
+    match hemisphere {
+        h if h == positive => Some(degrees),
+        h if h == negative => Some(-degrees),
+        _ => None,
+    }
+}
+
+/// The position of the last fix read from an NMEA source. Clones share it: the reader
+/// updates it on its own thread while the writer reads it for every packet.
+#[cfg(feature = "nmea")]
+#[derive(Clone, Debug, Default)]
+pub struct LatestFix(Arc<Mutex<Option<Location>>>);
+
+#[cfg(feature = "nmea")]
+impl LatestFix {
+    /// None until the source reported a fix
+    pub fn get(&self) -> Option<Location> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Reads NMEA sentences until the source ends, e.g. from a GPS receiver's serial
+    /// port. Lines that aren't a fix are skipped.
+    pub fn follow(&self, mut source: impl BufRead) -> io::Result<()> {
+        let mut line = vec![];
+        while source.read_until(b'\n', &mut line)? > 0 {
+            if let Some(location) = parse_nmea(&String::from_utf8_lossy(&line)) {
+                *self.0.lock().unwrap() = Some(location);
+            }
+            line.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::location::{Location, ParseLocationError};
+
+    #[test]
+    fn parse_location() {
+        let location: Location = "52.370216,4.895168".parse().unwrap();
+        assert_eq!(location.to_string(), "52.370216,4.895168");
+        let location: Location = "-33.8568, 151.2153".parse().unwrap();
+        assert_eq!(location.to_string(), "-33.856800,151.215300");
+
+        for invalid in ["", "52.37", "52.37,x", "91,0", "0,-181"] {
+            assert_eq!(
+                invalid.parse::<Location>(),
+                Err(ParseLocationError(invalid.to_string()))
+            );
+        }
+    }
+
+    #[cfg(feature = "nmea")]
+    #[test]
+    fn nmea_sentences() {
+        use crate::location::{parse_nmea, LatestFix};
+
+        let gga = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+        assert_eq!(parse_nmea(gga).unwrap().to_string(), "48.117300,11.516667");
+        let rmc = "$GNRMC,123519,A,3351.408,S,15112.918,E,022.4,084.4,230394,003.1,W";
+        assert_eq!(
+            parse_nmea(rmc).unwrap().to_string(),
+            "-33.856800,151.215300"
+        );
+
+        // No fix, a bad checksum and a sentence without a position
+        assert_eq!(parse_nmea("$GPGGA,123519,,,,,0,00,,,M,,M,,"), None);
+        assert_eq!(parse_nmea("$GPRMC,123519,V,,,,,,,230394,,"), None);
+        assert_eq!(parse_nmea(&gga.replace("*47", "*48")), None);
+        assert_eq!(parse_nmea("$GPGSV,3,1,11,03,03,111,00"), None);
+
+        let fix = LatestFix::default();
+        let source = format!("garbage\n{}\n$GPGSV,3,1,11\n{}\r\n", gga, rmc);
+        fix.clone().follow(source.as_bytes()).unwrap();
+        assert_eq!(fix.get().unwrap().to_string(), "-33.856800,151.215300");
+    }
+}
@@ -0,0 +1,131 @@
+use crate::mac::{FrameType, MacHeader};
+use std::fmt::{Display, Formatter};
+
+// Dispatch values from RFC 4944 section 5.1, in the upper 5 bits of the first byte
+const DISPATCH_FRAG1: u8 = 0b11000;
+const DISPATCH_FRAGN: u8 = 0b11100;
+// Mesh header: 10, V, F and a 4-bit hops left, followed by the originator and final
+// addresses. V and F are set for short addresses, hops left 0xf adds a byte.
+const MESH_MASK: u8 = 0b1100_0000;
+const MESH: u8 = 0b1000_0000;
+const MESH_SHORT_ORIGINATOR: u8 = 1 << 5;
+const MESH_SHORT_FINAL: u8 = 1 << 4;
+const MESH_HOPS_EXTENDED: u8 = 0x0f;
+
+/// A 6LoWPAN fragmentation header. Only detected, the datagram has to be reassembled
+/// from the fragments with the same tag by a later stage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fragment {
+    /// Size of the whole datagram
+    pub size: u16,
+    pub tag: u16,
+    /// Offset of this fragment in the datagram in bytes, 0 for the first fragment
+    pub offset: u16,
+}
+
+impl Display for Fragment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "6lowpan fragment tag=0x{:04X} size={} offset={}",
+            self.tag, self.size, self.offset
+        )
+    }
+}
+
+/// Finds a fragmentation header at the start of the MAC payload of a data frame, after a
+/// mesh header if there is one. The payload of secured frames can't be inspected.
+pub fn fragment(frame: &[u8]) -> Option<Fragment> {
+    let header = MacHeader::parse(frame).ok()?;
+    if header.frame_type != FrameType::Data || header.security_enabled {
+        return None;
+    }
+
+    let mut payload = frame.get(header.header_len..)?;
+    let mesh = *payload.first()?;
+    if mesh & MESH_MASK == MESH {
+        let address_len = |short| if mesh & short != 0 { 2 } else { 8 };
+        let hops = if mesh & MESH_HOPS_EXTENDED == MESH_HOPS_EXTENDED {
+            1
+        } else {
+            0
+        };
+        let len = 1 + hops + address_len(MESH_SHORT_ORIGINATOR) + address_len(MESH_SHORT_FINAL);
+        payload = payload.get(len..)?;
+    }
+
+    let dispatch = *payload.first()? >> 3;
+    if dispatch != DISPATCH_FRAG1 && dispatch != DISPATCH_FRAGN {
+        return None;
+    }
+    let header = payload.get(..4)?;
+    let size = u16::from_be_bytes([header[0] & 0x07, header[1]]);
+    let tag = u16::from_be_bytes([header[2], header[3]]);
+    let offset = match dispatch {
+        DISPATCH_FRAGN => *payload.get(4)? as u16 * 8,
+        _ => 0,
+    };
+    Some(Fragment { size, tag, offset })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lowpan::{fragment, Fragment};
+
+    // Data frame 0xABCD -> 0x5678 on PAN 0x1234
+    const MAC_HEADER: [u8; 9] = [0x61, 0x88, 0x2b, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab];
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = MAC_HEADER.to_vec();
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&[0x00, 0x00]); // FCS
+        frame
+    }
+
+    #[test]
+    fn first_fragment() {
+        // FRAG1 of a 300 byte datagram with tag 0x1234, then IPHC
+        let f = fragment(&frame(&[0xc1, 0x2c, 0x12, 0x34, 0x7a, 0x33])).unwrap();
+        assert_eq!(
+            f,
+            Fragment {
+                size: 300,
+                tag: 0x1234,
+                offset: 0
+            }
+        );
+        assert_eq!(
+            f.to_string(),
+            "6lowpan fragment tag=0x1234 size=300 offset=0"
+        );
+    }
+
+    #[test]
+    fn subsequent_fragment() {
+        let f = fragment(&frame(&[0xe1, 0x2c, 0x12, 0x34, 0x0c, 0xaa])).unwrap();
+        assert_eq!(f.offset, 96);
+
+        // Behind a mesh header with short originator and final addresses
+        let payload = [0xbe, 0x00, 0x01, 0x00, 0x02, 0xe1, 0x2c, 0x12, 0x34, 0x0c];
+        assert_eq!(fragment(&frame(&payload)).unwrap().offset, 96);
+    }
+
+    #[test]
+    fn not_fragmented() {
+        // IPHC
+        assert_eq!(fragment(&frame(&[0x7a, 0x33, 0x3a])), None);
+        // Truncated FRAG1
+        assert_eq!(
+            fragment(
+                &MAC_HEADER
+                    .iter()
+                    .chain(&[0xc1, 0x2c])
+                    .copied()
+                    .collect::<Vec<u8>>()
+            ),
+            None
+        );
+        // Ack
+        assert_eq!(fragment(&[0x02, 0x00, 0x2a, 0xe0, 0x3b]), None);
+    }
+}
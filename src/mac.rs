@@ -0,0 +1,655 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+// Frame control field, see IEEE 802.15.4-2011 section 5.2.1.1
+const FC_SECURITY_ENABLED: u16 = 1 << 3;
+const FC_FRAME_PENDING: u16 = 1 << 4;
+const FC_ACK_REQUEST: u16 = 1 << 5;
+const FC_PAN_ID_COMPRESSION: u16 = 1 << 6;
+
+// Length of the key identifier per key identifier mode of the auxiliary security header
+const KEY_ID_LEN: [usize; 4] = [0, 1, 5, 9];
+
+// Length of the MIC per security level, levels 4 and up also encrypt
+const MIC_LEN: [usize; 8] = [0, 4, 8, 16, 0, 4, 8, 16];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(try_from = "String")
+)]
+pub enum FrameType {
+    Beacon,
+    Data,
+    Ack,
+    MacCommand,
+    Other(u8),
+}
+
+impl From<u16> for FrameType {
+    fn from(frame_control: u16) -> Self {
+        match frame_control & 0x07 {
+            0 => FrameType::Beacon,
+            1 => FrameType::Data,
+            2 => FrameType::Ack,
+            3 => FrameType::MacCommand,
+            n => FrameType::Other(n as u8),
+        }
+    }
+}
+
+impl Display for FrameType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameType::Beacon => write!(f, "BEACON"),
+            FrameType::Data => write!(f, "DATA"),
+            FrameType::Ack => write!(f, "ACK"),
+            FrameType::MacCommand => write!(f, "CMD"),
+            FrameType::Other(n) => write!(f, "TYPE{}", n),
+        }
+    }
+}
+
+/// A frame type on the command line that isn't one of the names `Display` writes
+#[derive(Debug, PartialEq)]
+pub struct ParseFrameTypeError(String);
+
+impl Display for ParseFrameTypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid frame type {}, expected beacon, data, ack, cmd or type4 to type7",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseFrameTypeError {}
+
+/// Parses the names `Display` writes, in upper or lower case
+impl FromStr for FrameType {
+    type Err = ParseFrameTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_uppercase();
+        let frame_type = match name.as_str() {
+            "BEACON" => FrameType::Beacon,
+            "DATA" => FrameType::Data,
+            "ACK" => FrameType::Ack,
+            "CMD" => FrameType::MacCommand,
+            _ => match name.strip_prefix("TYPE").and_then(|n| n.parse().ok()) {
+                Some(n @ 4..=7) => FrameType::Other(n),
+                _ => return Err(ParseFrameTypeError(s.to_string())),
+            },
+        };
+        Ok(frame_type)
+    }
+}
+
+impl TryFrom<String> for FrameType {
+    type Error = ParseFrameTypeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(try_from = "String")
+)]
+pub enum Address {
+    Short(u16),
+    Extended(u64),
+}
+
+impl Address {
+    /// The address as sent on air, little endian. 2 bytes are a short address and 8 an
+    /// extended one.
+    pub fn from_le_bytes(bytes: &[u8]) -> Option<Address> {
+        match bytes.len() {
+            2 => Some(Address::Short(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            8 => {
+                let mut extended = [0u8; 8];
+                extended.copy_from_slice(bytes);
+                Some(Address::Extended(u64::from_le_bytes(extended)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The address as sent on air, little endian
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        match self {
+            Address::Short(a) => a.to_le_bytes().to_vec(),
+            Address::Extended(a) => a.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+/// Formats short addresses as `0xABCD` and extended addresses most significant byte
+/// first, as printed on the devices: `00:12:4b:00:1a:2b:3c:4d`
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Short(a) => write!(f, "0x{:04X}", a),
+            Address::Extended(a) => {
+                let bytes = a.to_be_bytes();
+                for (i, b) in bytes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ":")?;
+                    }
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An address on the command line that is neither `0x1234` nor `00:12:4b:00:1a:2b:3c:4d`
+#[derive(Debug, PartialEq)]
+pub struct ParseAddressError(String);
+
+impl Display for ParseAddressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid address {}, expected a short address like 0x1234 or an extended address like 00:12:4b:00:1a:2b:3c:4d",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseAddressError {}
+
+/// Parses the forms `Display` writes. Hex digits can be upper or lower case, and an
+/// extended address can also be given as 16 digits without separators.
+impl FromStr for Address {
+    type Err = ParseAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseAddressError(s.to_string());
+        let hex = |digits: &str| digits.chars().all(|c| c.is_ascii_hexdigit());
+
+        if let Some(digits) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            if digits.is_empty() || digits.len() > 4 || !hex(digits) {
+                return Err(invalid());
+            }
+            return u16::from_str_radix(digits, 16)
+                .map(Address::Short)
+                .map_err(|_| invalid());
+        }
+
+        let digits: String = if s.contains(':') {
+            let groups: Vec<&str> = s.split(':').collect();
+            if groups.len() != 8 || groups.iter().any(|g| g.len() != 2) {
+                return Err(invalid());
+            }
+            groups.concat()
+        } else {
+            s.to_string()
+        };
+        if digits.len() != 16 || !hex(&digits) {
+            return Err(invalid());
+        }
+        u64::from_str_radix(&digits, 16)
+            .map(Address::Extended)
+            .map_err(|_| invalid())
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = ParseAddressError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MacError {
+    Truncated,
+    TrailingBytes,
+    ReservedAddressMode,
+    NotABeacon,
+    Secured,
+}
+
+impl Display for MacError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacError::Truncated => write!(f, "frame too short for its header"),
+            MacError::TrailingBytes => write!(f, "bytes after the end of an Imm-Ack"),
+            MacError::ReservedAddressMode => write!(f, "reserved addressing mode"),
+            MacError::NotABeacon => write!(f, "not a beacon frame"),
+            MacError::Secured => write!(f, "auxiliary security header not supported"),
+        }
+    }
+}
+
+impl std::error::Error for MacError {}
+
+/// Decoded IEEE 802.15.4 MAC header
+#[derive(Debug, PartialEq)]
+pub struct MacHeader {
+    pub frame_type: FrameType,
+    pub security_enabled: bool,
+    pub frame_pending: bool,
+    pub ack_request: bool,
+    pub frame_version: u8,
+    pub sequence: u8,
+    pub dst_pan: Option<u16>,
+    pub dst: Option<Address>,
+    pub src_pan: Option<u16>,
+    pub src: Option<Address>,
+    /// Length of the MAC header, the MAC payload starts at this offset
+    pub header_len: usize,
+}
+
+impl MacHeader {
+    /// Parses the MAC header at the start of an MPDU
+    pub fn parse(frame: &[u8]) -> Result<MacHeader, MacError> {
+        let mut reader = Reader {
+            data: frame,
+            offset: 0,
+        };
+
+        let frame_control = reader.u16()?;
+        let sequence = reader.u8()?;
+
+        let dst_mode = (frame_control >> 10) & 0x03;
+        let src_mode = (frame_control >> 14) & 0x03;
+        let pan_id_compression = frame_control & FC_PAN_ID_COMPRESSION != 0;
+
+        let (dst_pan, dst) = if dst_mode != 0 {
+            let pan = reader.u16()?;
+            (Some(pan), reader.address(dst_mode)?)
+        } else {
+            (None, None)
+        };
+
+        let (src_pan, src) = if src_mode != 0 {
+            let pan = if pan_id_compression {
+                dst_pan
+            } else {
+                Some(reader.u16()?)
+            };
+            (pan, reader.address(src_mode)?)
+        } else {
+            (None, None)
+        };
+
+        Ok(MacHeader {
+            frame_type: frame_control.into(),
+            security_enabled: frame_control & FC_SECURITY_ENABLED != 0,
+            frame_pending: frame_control & FC_FRAME_PENDING != 0,
+            ack_request: frame_control & FC_ACK_REQUEST != 0,
+            frame_version: ((frame_control >> 12) & 0x03) as u8,
+            sequence,
+            dst_pan,
+            dst,
+            src_pan,
+            src,
+            header_len: reader.offset,
+        })
+    }
+
+    /// Checks that `frame`, the MPDU without the FCS, holds the fields this header calls
+    /// for: the auxiliary security header and MIC of a secured 2006 frame, the fixed
+    /// fields of a beacon and the command id of a MAC command. An Imm-Ack has nothing
+    /// after its header. Frames of version 2, which can carry IEs, aren't checked.
+    pub fn check_length(&self, frame: &[u8]) -> Result<(), MacError> {
+        if self.frame_version >= 2 {
+            return Ok(());
+        }
+        if self.frame_type == FrameType::Ack {
+            return match frame.len() > self.header_len {
+                true => Err(MacError::TrailingBytes),
+                false => Ok(()),
+            };
+        }
+
+        let mut reader = Reader {
+            data: frame,
+            offset: self.header_len,
+        };
+        // 2003 frames keep their security material in the payload, it can't be checked
+        let mut mic_len = 0;
+        if self.security_enabled && self.frame_version == 1 {
+            let control = reader.u8()?;
+            reader.take(4)?; // frame counter
+            reader.take(KEY_ID_LEN[((control >> 3) & 0x03) as usize])?;
+            mic_len = MIC_LEN[(control & 0x07) as usize];
+        }
+        match self.frame_type {
+            FrameType::Beacon => {
+                reader.take(2)?; // superframe specification
+                let gts_descriptors = (reader.u8()? & 0x07) as usize;
+                if gts_descriptors > 0 {
+                    reader.take(1 + 3 * gts_descriptors)?;
+                }
+                let pending = reader.u8()?;
+                let short = (pending & 0x07) as usize;
+                let extended = ((pending >> 4) & 0x07) as usize;
+                reader.take(2 * short + 8 * extended)?;
+            }
+            FrameType::MacCommand => {
+                reader.u8()?; // command id
+            }
+            _ => {}
+        }
+        reader.take(mic_len).map(|_| ())
+    }
+
+    /// PAN the frame belongs to, the destination PAN if present
+    pub fn pan(&self) -> Option<u16> {
+        self.dst_pan.or(self.src_pan)
+    }
+}
+
+/// Superframe specification at the start of a beacon payload, see IEEE 802.15.4-2011
+/// section 5.2.2.1.2
+#[derive(Debug, PartialEq)]
+pub struct SuperframeSpec {
+    pub beacon_order: u8,
+    pub superframe_order: u8,
+    pub final_cap_slot: u8,
+    pub battery_life_extension: bool,
+    pub pan_coordinator: bool,
+    pub association_permit: bool,
+}
+
+impl SuperframeSpec {
+    /// Parses the superframe specification of a beacon frame with the given header
+    pub fn parse(frame: &[u8], header: &MacHeader) -> Result<SuperframeSpec, MacError> {
+        if header.frame_type != FrameType::Beacon {
+            return Err(MacError::NotABeacon);
+        }
+        // The auxiliary security header would come first, it isn't parsed
+        if header.security_enabled {
+            return Err(MacError::Secured);
+        }
+
+        let mut reader = Reader {
+            data: frame,
+            offset: header.header_len,
+        };
+        let spec = reader.u16()?;
+        Ok(SuperframeSpec {
+            beacon_order: (spec & 0x0f) as u8,
+            superframe_order: ((spec >> 4) & 0x0f) as u8,
+            final_cap_slot: ((spec >> 8) & 0x0f) as u8,
+            battery_life_extension: spec & (1 << 12) != 0,
+            pan_coordinator: spec & (1 << 14) != 0,
+            association_permit: spec & (1 << 15) != 0,
+        })
+    }
+}
+
+impl Display for SuperframeSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "BO={} SO={} cap={}",
+            self.beacon_order, self.superframe_order, self.final_cap_slot
+        )?;
+        if self.battery_life_extension {
+            write!(f, " ble")?;
+        }
+        if self.pan_coordinator {
+            write!(f, " coordinator")?;
+        }
+        if self.association_permit {
+            write!(f, " permit")?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], MacError> {
+        let end = self.offset + len;
+        if end > self.data.len() {
+            return Err(MacError::Truncated);
+        }
+        let slice = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, MacError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MacError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn address(&mut self, mode: u16) -> Result<Option<Address>, MacError> {
+        let len = match mode {
+            2 => 2,
+            3 => 8,
+            _ => return Err(MacError::ReservedAddressMode),
+        };
+        Ok(Address::from_le_bytes(self.take(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mac::{
+        Address, FrameType, MacError, MacHeader, ParseAddressError, ParseFrameTypeError,
+        SuperframeSpec,
+    };
+
+    // Zigbee router beacon from PAN 0x1234, coordinator and association permitted
+    const BEACON: [u8; 22] = [
+        0x00, 0x80, 0x01, 0x34, 0x12, 0x00, 0x00, 0xff, 0xcf, 0x00, 0x00, 0x00, 0x22, 0x84, 0xdd,
+        0xcc, 0xbb, 0xaa, 0x00, 0x4b, 0x12, 0x00,
+    ];
+
+    #[test]
+    fn parse_data_short() {
+        // Data, ack request, PAN ID compression, short dst and src
+        let frame = [0x61, 0x88, 0x2a, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0xff];
+        let header = MacHeader::parse(&frame).unwrap();
+        assert_eq!(header.frame_type, FrameType::Data);
+        assert!(header.ack_request);
+        assert_eq!(header.sequence, 42);
+        assert_eq!(header.pan(), Some(0x1234));
+        assert_eq!(header.dst, Some(Address::Short(0x5678)));
+        assert_eq!(header.src, Some(Address::Short(0xabcd)));
+        assert_eq!(header.src_pan, Some(0x1234));
+        assert_eq!(header.header_len, 9);
+    }
+
+    #[test]
+    fn parse_extended_src() {
+        // Data, PAN ID compression, short dst and extended src
+        let frame = [
+            0x41, 0xc8, 0x01, 0x34, 0x12, 0xff, 0xff, 0x4d, 0x3c, 0x2b, 0x1a, 0x00, 0x4b, 0x12,
+            0x00,
+        ];
+        let header = MacHeader::parse(&frame).unwrap();
+        assert_eq!(header.src, Some(Address::Extended(0x0012_4b00_1a2b_3c4d)));
+        assert_eq!(header.header_len, 15);
+    }
+
+    #[test]
+    fn parse_ack() {
+        let header = MacHeader::parse(&[0x02, 0x00, 0x2a]).unwrap();
+        assert_eq!(header.frame_type, FrameType::Ack);
+        assert_eq!(header.dst, None);
+        assert_eq!(header.src, None);
+        assert_eq!(header.header_len, 3);
+    }
+
+    #[test]
+    fn parse_truncated() {
+        assert_eq!(MacHeader::parse(&[0x61, 0x88]), Err(MacError::Truncated));
+        assert_eq!(
+            MacHeader::parse(&[0x61, 0x88, 0x2a, 0x34, 0x12, 0x78]),
+            Err(MacError::Truncated)
+        );
+    }
+
+    #[test]
+    fn check_length() {
+        let check = |frame: &[u8]| MacHeader::parse(frame).unwrap().check_length(frame);
+        assert_eq!(check(&BEACON), Ok(()));
+        // Cut off in the pending address specification
+        assert_eq!(check(&BEACON[..10]), Err(MacError::Truncated));
+
+        assert_eq!(check(&[0x02, 0x00, 0x2a]), Ok(()));
+        assert_eq!(
+            check(&[0x02, 0x00, 0x2a, 0x00]),
+            Err(MacError::TrailingBytes)
+        );
+
+        // MAC command without the command id
+        let command = [0x43, 0x88, 0x01, 0x34, 0x12, 0xff, 0xff, 0x00, 0x00];
+        assert_eq!(check(&command), Err(MacError::Truncated));
+        assert_eq!(check(&[command.as_slice(), &[0x04]].concat()), Ok(()));
+    }
+
+    #[test]
+    fn check_length_secured() {
+        // 2006 data frame with security level 5 and a one byte key index, the payload
+        // and a 4 byte MIC
+        let frame = [
+            0x49, 0x98, 0x01, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0x0d, 0x01, 0x00, 0x00, 0x00,
+            0x01, 0xaa, 0xbb, 0x01, 0x02, 0x03, 0x04,
+        ];
+        let check = |frame: &[u8]| MacHeader::parse(frame).unwrap().check_length(frame);
+        assert_eq!(check(&frame), Ok(()));
+        // Truncated in the MIC, and in the auxiliary security header
+        assert_eq!(check(&frame[..18]), Err(MacError::Truncated));
+        assert_eq!(check(&frame[..12]), Err(MacError::Truncated));
+
+        // An unsecured data frame needs no payload
+        assert_eq!(check(&[0x41, 0x08, 0x01, 0x34, 0x12, 0xff, 0xff]), Ok(()));
+    }
+
+    #[test]
+    fn parse_reserved_mode() {
+        assert_eq!(
+            MacHeader::parse(&[0x01, 0x04, 0x00, 0x34, 0x12]),
+            Err(MacError::ReservedAddressMode)
+        );
+    }
+
+    #[test]
+    fn parse_superframe_spec() {
+        let header = MacHeader::parse(&BEACON).unwrap();
+        let spec = SuperframeSpec::parse(&BEACON, &header).unwrap();
+        assert_eq!(
+            spec,
+            SuperframeSpec {
+                beacon_order: 15,
+                superframe_order: 15,
+                final_cap_slot: 15,
+                battery_life_extension: false,
+                pan_coordinator: true,
+                association_permit: true,
+            }
+        );
+        assert_eq!(spec.to_string(), "BO=15 SO=15 cap=15 coordinator permit");
+
+        let header = MacHeader::parse(&BEACON[..8]).unwrap();
+        assert_eq!(
+            SuperframeSpec::parse(&BEACON[..8], &header),
+            Err(MacError::Truncated)
+        );
+        let ack = [0x02, 0x00, 0x2a];
+        let header = MacHeader::parse(&ack).unwrap();
+        assert_eq!(
+            SuperframeSpec::parse(&ack, &header),
+            Err(MacError::NotABeacon)
+        );
+    }
+
+    #[test]
+    fn format_address() {
+        assert_eq!(Address::Short(0xabcd).to_string(), "0xABCD");
+        assert_eq!(
+            Address::Extended(0x0012_4b00_1a2b_3c4d).to_string(),
+            "00:12:4b:00:1a:2b:3c:4d"
+        );
+    }
+
+    #[test]
+    fn parse_address() {
+        for address in [
+            Address::Short(0xabcd),
+            Address::Short(0x0001),
+            Address::Extended(0x0012_4b00_1a2b_3c4d),
+        ] {
+            assert_eq!(address.to_string().parse(), Ok(address));
+        }
+        assert_eq!("0x1234".parse(), Ok(Address::Short(0x1234)));
+        assert_eq!(
+            "00124B001A2B3C4D".parse(),
+            Ok(Address::Extended(0x0012_4b00_1a2b_3c4d))
+        );
+
+        for invalid in [
+            "",
+            "0x",
+            "0x12345",
+            "0xgg",
+            "1234",
+            "00:12:4b:00:1a:2b:3c",
+            "0:12:4b:00:1a:2b:3c:4d0",
+        ] {
+            assert_eq!(
+                invalid.parse::<Address>(),
+                Err(ParseAddressError(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn parse_frame_type() {
+        for frame_type in [
+            FrameType::Beacon,
+            FrameType::Data,
+            FrameType::Ack,
+            FrameType::MacCommand,
+            FrameType::Other(5),
+        ] {
+            assert_eq!(frame_type.to_string().parse(), Ok(frame_type));
+        }
+        assert_eq!("data".parse(), Ok(FrameType::Data));
+        for invalid in ["", "frame", "type2", "type8"] {
+            assert_eq!(
+                invalid.parse::<FrameType>(),
+                Err(ParseFrameTypeError(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn address_byte_order() {
+        // On air the least significant byte comes first
+        let extended = [0x4d, 0x3c, 0x2b, 0x1a, 0x00, 0x4b, 0x12, 0x00];
+        let address = Address::from_le_bytes(&extended).unwrap();
+        assert_eq!(address.to_string(), "00:12:4b:00:1a:2b:3c:4d");
+        assert_eq!(address.to_le_bytes(), extended);
+
+        let address = Address::from_le_bytes(&[0xcd, 0xab]).unwrap();
+        assert_eq!(address, Address::Short(0xabcd));
+        assert_eq!(address.to_le_bytes(), [0xcd, 0xab]);
+
+        assert_eq!(Address::from_le_bytes(&[0x01, 0x02, 0x03]), None);
+    }
+}
@@ -1,180 +1,2230 @@
-use crate::pcaptap::TapBlock;
-use crate::sniffer::{CmdCodes, SnifferDevice, SnifferError};
-use clap::Parser;
-use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
-use pcap_file::pcapng::blocks::interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption};
-use pcap_file::pcapng::{PcapNgBlock, PcapNgWriter};
-use pcap_file::DataLink;
+use ccsniffer_rust::analyze::read_tap_capture;
+use ccsniffer_rust::compress::Compression;
+#[cfg(feature = "config")]
+use ccsniffer_rust::config::Config;
+use ccsniffer_rust::expression::Expression;
+use ccsniffer_rust::fcs::FcsMode;
+use ccsniffer_rust::filter::{mac_header_parses, FrameFilter, StopCondition};
+#[cfg(feature = "nmea")]
+use ccsniffer_rust::location::LatestFix;
+use ccsniffer_rust::location::Location;
+use ccsniffer_rust::mac::{Address, FrameType};
+use ccsniffer_rust::packet::{now, CapturedPacket, LqiMode};
+use ccsniffer_rust::pcaptap::{write_schema_json, Phy};
+use ccsniffer_rust::protocol::Message;
+use ccsniffer_rust::queue::{self, OnFull, DEFAULT_QUEUE_DEPTH};
+use ccsniffer_rust::replay::{read_frames, InputFormat, ReplayError};
+use ccsniffer_rust::resume::ResumeState;
+use ccsniffer_rust::runlog::{LogMode, RunLog};
+use ccsniffer_rust::selfcheck;
+use ccsniffer_rust::session::{
+    CaptureSession, IdleBackoff, KeepaliveTimer, SessionError, SessionOptions, StartupCheck,
+};
+use ccsniffer_rust::sink::{
+    write_packets, CArraySink, CaptureMeta, CsvSink, JsonSink, OutputFormat, PacketSink, RawSink,
+    TriggerSink,
+};
+use ccsniffer_rust::sniffer::{
+    check_protocol_version, dump, udev_rule, Capabilities, CmdCodes, SnifferDevice, SnifferError,
+    UsbOptions, MAX_FRAME_LEN, PRODUCT, VENDOR,
+};
+use ccsniffer_rust::stats::{
+    valid_rssi_decay, CaptureStats, CaptureSummary, FrameQuality, SourceSignals,
+};
+use ccsniffer_rust::summary::{describe_beacon, summarize};
+use ccsniffer_rust::writer::{
+    check_output_path, CaptureWriter, LinkType, TsResolution, WriterError, WriterOptions,
+    DEFAULT_SNAPLEN,
+};
+#[cfg(feature = "config")]
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use rusb::{Device, GlobalContext};
 use signal_hook::{consts::SIGINT, iterator::Signals};
-use std::borrow::Cow;
+use std::ffi::c_int;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use std::{error::Error, thread};
 
-mod pcaptap;
-mod sniffer;
-mod protocol;
+// Prints the capture statistics without stopping, like tcpdump
+#[cfg(unix)]
+const STATS_SIGNAL: Option<c_int> = Some(signal_hook::consts::SIGUSR1);
+#[cfg(not(unix))]
+const STATS_SIGNAL: Option<c_int> = None;
 
-const VENDOR: u16 = 0x0451; // Texas Instruments
-const PRODUCT: u16 = 0x16a8; // CC2531 USB Stick
+// How often the progress line is updated
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Exit code when writing the capture failed, e.g. on a full disk
+const EXIT_WRITE_FAILED: i32 = 3;
+
+// How often the packet count is saved for --resume, a crash loses the count since
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often a paused capture checks whether it should resume or stop
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often --wait looks for the sniffer
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// The --log-file, opened once at startup
+static RUN_LOG: OnceLock<Mutex<RunLog<File>>> = OnceLock::new();
+
+/// println! that also writes the message to the --log-file
+macro_rules! diag {
+    () => {
+        diag!("")
+    };
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        println!("{}", message);
+        log_message(&message);
+    }};
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(
+    after_help = "Send SIGUSR1 to print capture statistics to stderr without stopping the capture."
+)]
 struct Cli {
-    #[arg(short, long, value_parser= clap::value_parser!(u8).range(11..27), default_value="13")]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, global = true, value_parser= clap::value_parser!(u8).range(0..27), default_value="13")]
     channel: u8,
 
-    #[arg(short = 'f', long, default_value = "capture.pcap")]
+    /// Capture file [default: capture-ch<CHANNEL>.pcap]
+    #[arg(short = 'f', long, global = true)]
     capture_file: Option<PathBuf>,
 
+    /// Capture these channels at once with a sniffer each, e.g. 11,15,20. Every channel is
+    /// written to its own files, with -ch<CHANNEL> added to the names
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(11..27))]
+    #[arg(conflicts_with_all = ["summary", "histograms", "interactive", "eui"])]
+    channels: Vec<u8>,
+
+    /// Keep the file number and packet count in this file, and continue from them when it
+    /// exists. The number is added to the output file names, so a restart after a crash
+    /// doesn't overwrite the previous files
+    #[arg(long, value_name = "STATE", conflicts_with = "channels")]
+    resume: Option<PathBuf>,
+
+    // Read from --resume at startup, with the file number of this run
+    #[arg(skip)]
+    resume_state: Option<ResumeState>,
+
+    /// How to handle the FCS at the end of each frame
+    #[arg(long, global = true, value_enum, default_value = "none")]
+    fcs: FcsMode,
+
+    /// Maximum number of bytes stored per packet, including the TAP header. 0 stores
+    /// packets whole
+    #[arg(long, global = true, default_value_t = DEFAULT_SNAPLEN)]
+    snaplen: u32,
+
+    /// Format of the capture file
+    #[arg(long, global = true, value_enum, default_value = "pcapng")]
+    out_format: OutputFormat,
+
+    /// Compress the JSON output, gzip and zstd need the features of the same name
+    #[arg(long, global = true, value_enum, default_value = "none")]
+    compress: Compression,
+
+    /// Link type of the capture file, withfcs and nofcs write the frames without a TAP header
+    #[arg(long, global = true, value_enum, default_value = "tap")]
+    linktype: LinkType,
+
+    /// Without a TAP header, store RSSI, LQI and channel as a comment on every packet
+    #[arg(long, global = true)]
+    radio_comments: bool,
+
+    /// Value for the LQI fields: the correlation byte from the sniffer, or an LQI estimated from it
+    #[arg(long, global = true, value_enum, default_value = "raw")]
+    lqi_mode: LqiMode,
+
+    /// Comment stored in the capture file, can be given multiple times
+    #[arg(long, global = true)]
+    comment: Vec<String>,
+
+    /// Where the capture is made, in decimal degrees like 52.370216,4.895168, stored in
+    /// the capture file
+    #[arg(
+        long,
+        global = true,
+        value_name = "LAT,LON",
+        allow_hyphen_values = true
+    )]
+    location: Option<Location>,
+
+    /// Read NMEA sentences from a GPS receiver, e.g. its serial port, and store the last
+    /// position on every packet
+    #[cfg(feature = "nmea")]
+    #[arg(long, global = true, value_name = "PATH")]
+    nmea: Option<PathBuf>,
+
+    /// Store the command line in the capture file, to see how it was made. The value of
+    /// --key is left out
+    #[arg(long, global = true)]
+    record_args: bool,
+
+    /// Also write the bare frames to this file, each preceded by a 16-bit little endian length
+    #[arg(long, global = true)]
+    raw_out: Option<PathBuf>,
+
+    /// Write capture statistics to this file when done, as JSON if the name ends in .json
+    #[arg(long, global = true)]
+    summary: Option<PathBuf>,
+
+    /// Only write the summary file, no packet data
+    #[arg(long, global = true, requires = "summary")]
+    summary_only: bool,
+
+    /// Write the offset, channel and timestamp of every packet in the pcapng file to this
+    /// file as CSV
+    #[arg(long, global = true)]
+    index: Option<PathBuf>,
+
+    /// Write RSSI and LQI histograms to this file when done, as JSON if the name ends in
+    /// .json, otherwise CSV
+    #[arg(long, global = true)]
+    histograms: Option<PathBuf>,
+
+    /// Store a random UUID in the capture file and the summary
+    #[cfg(feature = "capture-id")]
+    #[arg(long, global = true)]
+    capture_id: bool,
+
+    /// EUI-64 of the sniffing radio to store in the capture file, e.g.
+    /// 00:12:4b:00:01:02:03:04. The firmware can't report it
+    #[arg(long, global = true, value_parser = parse_eui)]
+    eui: Option<u64>,
+
+    /// TSCH timeslot length in microseconds to store with every packet, e.g. 10000. The
+    /// sniffer can't see the schedule
+    #[arg(long, global = true, value_name = "US")]
+    timeslot_length: Option<u32>,
+
+    /// PHY of the frames, its bit rate is stored with every packet. The CC2531 only has
+    /// oqpsk-2450, the others are for from-file and take their own channels
+    #[arg(long, global = true, value_enum)]
+    phy: Option<Phy>,
+
+    // Generated once at startup, so every file of this run has the same id
+    #[arg(skip)]
+    capture_uuid: Option<String>,
+
+    // Taken once at startup with --relative-time, every file of this run counts from it
+    #[arg(skip)]
+    time_origin: Option<Duration>,
+
+    // Updated by the --nmea reader thread, started at startup
+    #[cfg(feature = "nmea")]
+    #[arg(skip)]
+    nmea_fix: Option<LatestFix>,
+
+    /// Only keep beacons, and print their superframe specification
+    #[arg(long, global = true)]
+    beacons: bool,
+
+    /// Only keep frames of these types: beacon, data, ack, cmd or type4 to type7
+    #[arg(long, global = true, value_delimiter = ',', value_name = "TYPES")]
+    allow_type: Vec<FrameType>,
+
+    /// Leave out frames of these types, also when they are allowed
+    #[arg(long, global = true, value_delimiter = ',', value_name = "TYPES")]
+    deny_type: Vec<FrameType>,
+
+    /// Only keep frames from or to these addresses, e.g. 0x1234 or 00:12:4b:00:1a:2b:3c:4d
+    #[arg(long, global = true, value_delimiter = ',', value_name = "ADDRS")]
+    allow_addr: Vec<Address>,
+
+    /// Leave out frames from or to these addresses, also when they are allowed
+    #[arg(long, global = true, value_delimiter = ',', value_name = "ADDRS")]
+    deny_addr: Vec<Address>,
+
+    /// Only keep frames that match this expression, e.g. "type==data && rssi>-60". See the
+    /// README for the fields
+    #[arg(long, global = true, value_name = "EXPR")]
+    filter: Option<Expression>,
+
+    /// Only start writing the output file at the first frame that matches this expression,
+    /// with the --pretrigger frames before it
+    #[arg(long, global = true, value_name = "EXPR")]
+    trigger: Option<Expression>,
+
+    /// Number of frames before the --trigger frame that are written with it, they are kept
+    /// in memory until the trigger fires
+    #[arg(long, global = true, value_name = "N", default_value_t = 100)]
+    pretrigger: usize,
+
+    /// End the capture after the first frame that matches this expression, or
+    /// --stop-after frames later
+    #[arg(long, global = true, value_name = "EXPR")]
+    stop_on: Option<Expression>,
+
+    /// Number of frames written after the --stop-on frame before the capture ends
+    #[arg(long, global = true, value_name = "FRAMES", default_value_t = 0)]
+    stop_after: u64,
+
+    /// Leave out captured frames whose MAC header can't be decoded. Frames with a bad FCS
+    /// are handled by --fcs
+    #[arg(long)]
+    drop_unparseable: bool,
+
+    /// Leave out captured frames that are too short for the fields their MAC header calls
+    /// for, or Imm-Acks with bytes after the header, a sign of truncation or corruption
+    #[arg(long)]
+    drop_inconsistent: bool,
+
+    /// Print a one line summary of every captured frame
+    #[arg(short, long, global = true)]
+    live: bool,
+
+    /// Initial value of the command checksum, in hex, for firmwares that don't use ff
+    #[arg(long, value_parser = parse_hex_u8, default_value = "ff")]
+    checksum_seed: u8,
+
+    /// Drop frames where the two length fields differ, instead of using the smaller one
+    #[arg(long)]
+    strict: bool,
+
+    /// Drop frames longer than this, by default the 127 bytes of an 802.15.4 PHY packet.
+    /// A longer frame means the framing went wrong
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_FRAME_LEN)]
+    max_frame_len: usize,
+
+    /// Refuse firmwares that report a protocol version that wasn't tested, instead of
+    /// warning
+    #[arg(long)]
+    strict_version: bool,
+
+    /// Use the sniffers whose USB product string contains this text, instead of the ones
+    /// with the CC2531 ids. Devices whose product string can't be read are skipped
+    #[arg(long, global = true, value_name = "NAME")]
+    match_name: Option<String>,
+
+    /// Index of the USB configuration to use, by default one with the sniffer endpoints
+    #[arg(long)]
+    usb_config: Option<u8>,
+
+    /// Address of the bulk endpoint to read from, in hex, by default the first one. See
+    /// --usb-info for the endpoints
+    #[arg(long, value_parser = parse_hex_u8)]
+    in_endpoint: Option<u8>,
+
+    /// Address of the bulk endpoint to write to, in hex, by default the first one
+    #[arg(long, value_parser = parse_hex_u8)]
+    out_endpoint: Option<u8>,
+
+    /// When the sniffer is in use, detach the kernel driver holding it. A claim by another
+    /// program can't be taken over
+    #[arg(long)]
+    force: bool,
+
+    /// Milliseconds to wait between the startup commands, for sticks that fail the handshake
+    #[arg(long, default_value_t = 0)]
+    command_delay: u64,
+
+    /// Milliseconds to wait after sniffing started before the first read, for firmwares
+    /// that garble the first frames while the radio settles
+    #[arg(long, default_value_t = 0)]
+    settle_ms: u64,
+
+    /// Wait for the sniffer to be plugged in instead of exiting when there is none
+    #[arg(long, conflicts_with = "channels")]
+    wait: bool,
+
+    /// Milliseconds to wait with --wait after the sniffer was plugged in before opening
+    /// it, so a stick that is still settling on a bouncy port isn't opened
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    plug_debounce_ms: u64,
+
+    /// Sleep up to this many milliseconds between reads while the channel is quiet, to save
+    /// CPU. 0 reads continuously
+    #[arg(long, default_value_t = 0)]
+    idle_backoff: u64,
+
+    /// Warn when no frame arrived this many seconds after sniffing started, the channel is
+    /// idle or the sniffer didn't start. 0 disables the warning
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    startup_check: u64,
+
+    /// Check that the sniffer still answers after this many seconds without a frame, to
+    /// tell a quiet channel from a dead sniffer. 0 disables the check
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    keepalive_interval: u64,
+
+    /// Mark every this many seconds in the capture file that the capture is alive, also
+    /// while the channel is quiet. The marks aren't frames. 0 writes none
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    heartbeat: u64,
+
+    /// Number of packets buffered between the capture and the file writer
+    #[arg(long, global = true, default_value_t = DEFAULT_QUEUE_DEPTH)]
+    queue_depth: usize,
+
+    /// What to do when the buffer is full: stall the capture, or drop the oldest packet
+    #[arg(long, global = true, value_enum, default_value = "block")]
+    on_full: OnFull,
+
+    /// Weight of a new sample in the per-source RSSI average, higher follows changes faster
+    #[arg(long, global = true, value_parser = parse_rssi_decay, default_value = "0.2")]
+    rssi_decay: f64,
+
+    /// How many of the source addresses that sent the most frames to print when done, 0
+    /// for none
+    #[arg(long, global = true, value_name = "N", default_value = "10")]
+    top_talkers: usize,
+
+    /// Resolution of the packet timestamps, us rounds them for tools that only read
+    /// microseconds
+    #[arg(long, global = true, value_enum, default_value = "ns")]
+    ts_resolution: TsResolution,
+
+    /// Store the packet times relative to the start of the capture, so the file doesn't
+    /// show when it was made. Only for pcapng files
+    #[arg(long, global = true)]
+    relative_time: bool,
+
+    /// Number of packets collected before they are written to the pcapng file. A partial
+    /// batch is written at the next flush
+    #[arg(long, global = true, default_value_t = 1)]
+    batch_size: usize,
+
+    /// Milliseconds between flushes of the output files, 0 flushes after every packet. The
+    /// files are always flushed when the capture ends
+    #[arg(long, global = true, value_name = "MS", default_value_t = 500)]
+    flush_interval: u64,
+
+    /// Count the frames carrying a 6LoWPAN fragment header and mark them with a packet
+    /// comment. The fragments are not reassembled
+    #[arg(long, global = true)]
+    lowpan_fragments: bool,
+
+    /// Mark every packet in the pcapng file as received in its epb_flags, to tell captured
+    /// frames apart from transmitted ones written by other tools
+    #[arg(long, global = true)]
+    direction_flags: bool,
+
+    /// Leave the device sniffing on exit, e.g. for another program to take over
+    #[arg(long)]
+    no_sniff_off: bool,
+
+    /// Print the USB configurations, interfaces and endpoints of the sniffer and exit
+    #[arg(long)]
+    usb_info: bool,
+
+    /// Don't print the progress line during a capture
     #[arg(short, long)]
+    quiet: bool,
+
+    /// Also write the diagnostic messages, such as the startup, warnings, errors and the
+    /// stats at the end, to this file with the time of each line
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// What to do with an existing --log-file: append to it, truncate it, or rotate it to
+    /// <PATH>.1
+    #[arg(long, global = true, value_enum, default_value = "append")]
+    log_mode: LogMode,
+
+    /// Pause and resume the capture by pressing Enter
+    #[arg(long)]
+    interactive: bool,
+
+    #[arg(short, long, global = true)]
     debug: bool,
+
+    /// Read defaults for these options from a TOML file, options given here take precedence
+    #[cfg(feature = "config")]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
-    let break_received = Arc::new(AtomicBool::new(false));
-    let break_received_me = break_received.clone();
+impl Cli {
+    fn writer_options(&self) -> WriterOptions {
+        WriterOptions {
+            fcs: self.fcs,
+            snaplen: self.snaplen,
+            comments: self.file_comments(),
+            linktype: self.linktype,
+            radio_comments: self.radio_comments,
+            capture_id: self.capture_uuid.clone(),
+            lqi_mode: self.lqi_mode,
+            lowpan_comments: self.lowpan_fragments,
+            direction_flags: self.direction_flags,
+            ts_resolution: self.ts_resolution,
+            batch_size: self.batch_size,
+            eui: self.eui,
+            timeslot_length: self.timeslot_length,
+            phy: self.phy,
+            time_origin: self.time_origin,
+            location: self.location,
+            #[cfg(feature = "nmea")]
+            fix: self.nmea_fix.clone(),
+        }
+    }
 
-    println!("CCSniffer");
-    println!("------------------");
-    println!("  Channel: {}", cli.channel);
-    if cli.capture_file.is_some() {
-        let filename = cli.capture_file.as_ref().unwrap().to_str().unwrap();
-        println!("  Capture file: {}", filename)
+    /// The --comment values, and the command line with --record-args
+    fn file_comments(&self) -> Vec<String> {
+        let mut comments = self.comment.clone();
+        if self.record_args {
+            comments.push(format!("command line: {}", command_line(std::env::args())));
+        }
+        comments
     }
-    println!();
 
-    let mut signals = Signals::new(&[SIGINT])?;
-    thread::spawn(move || {
-        for sig in signals.forever() {
-            println!("Received signal {:?}", sig);
-            if sig == 2 {
-                // CTRLC
-                if break_received.load(Ordering::Relaxed) {
-                    // Received twice, just die
-                    std::process::exit(2);
-                } else {
-                    println!("Attempting to stop sniffer");
-                    break_received.store(true, Ordering::Relaxed);
+    /// Where the packets are written: the output of analyze, otherwise the capture file
+    /// unless --summary-only is given
+    fn output_file(&self) -> Option<&PathBuf> {
+        match &self.command {
+            Some(Command::Analyze { output, .. }) => output.as_ref(),
+            _ => self.capture_file.as_ref().filter(|_| !self.summary_only),
+        }
+    }
+
+    /// Fails if one of the output files can't be written, before any device work is done
+    fn check_output_paths(&self) -> Result<(), WriterError> {
+        let paths = [
+            self.output_file(),
+            self.raw_out.as_ref(),
+            self.summary.as_ref(),
+            self.histograms.as_ref(),
+            self.index.as_ref(),
+        ];
+        for path in paths.into_iter().flatten() {
+            check_output_path(path)?;
+        }
+        Ok(())
+    }
+
+    /// Opens the output file and the raw output, none without an output file. With
+    /// `per_channel` the files of that channel of --channels are opened.
+    fn packet_sinks(
+        &self,
+        description: &str,
+        per_channel: Option<u8>,
+    ) -> Result<Vec<Box<dyn PacketSink>>, Box<dyn Error>> {
+        let path = match self.output_file() {
+            Some(p) => p,
+            None => return Ok(vec![]),
+        };
+        let channel_path = |path: &PathBuf| self.run_path(path, per_channel);
+
+        let file = File::create(channel_path(path))?;
+        let mut sinks: Vec<Box<dyn PacketSink>> = match self.out_format {
+            OutputFormat::Pcapng => {
+                let mut writer = CaptureWriter::new(file, description, &self.writer_options())?;
+                if let Some(path) = &self.index {
+                    let index = File::create(channel_path(path))?;
+                    writer.set_index(Box::new(BufWriter::new(index)))?;
                 }
+                vec![Box::new(writer)]
+            }
+            OutputFormat::CArray => vec![Box::new(CArraySink::new(file))],
+            OutputFormat::Json => {
+                let meta = CaptureMeta {
+                    device: description.to_string(),
+                    channel: per_channel.unwrap_or(self.channel),
+                    started: now(),
+                    capture_id: self.capture_uuid.clone(),
+                };
+                vec![Box::new(JsonSink::compressed(file, &meta, self.compress)?)]
             }
+            OutputFormat::Csv => vec![Box::new(CsvSink::new(file)?)],
+        };
+        if let Some(path) = &self.raw_out {
+            sinks.push(Box::new(RawSink::new(File::create(channel_path(path))?)));
         }
-    });
+        if let Some(trigger) = &self.trigger {
+            let sink = TriggerSink::new(sinks, trigger.clone(), self.pretrigger);
+            sinks = vec![Box::new(sink)];
+        }
+        Ok(sinks)
+    }
 
-    let file = File::create(cli.capture_file.unwrap()).expect("Error creating file");
+    /// The name of an output file for this run, with the channel of --channels and the file
+    /// number of --resume added
+    fn run_path(&self, path: &Path, per_channel: Option<u8>) -> PathBuf {
+        let path = match per_channel {
+            Some(channel) => channel_path(path, channel),
+            None => path.to_path_buf(),
+        };
+        match &self.resume_state {
+            Some(state) => suffixed_path(&path, &format!("-{}", state.file_index)),
+            None => path,
+        }
+    }
 
-    let device = match SnifferDevice::find_device(VENDOR, PRODUCT) {
-        Some(n) => n,
-        None => {
-            println!("No suitable devices found.");
-            exit(1);
+    fn usb_options(&self) -> UsbOptions {
+        UsbOptions {
+            config: self.usb_config,
+            in_endpoint: self.in_endpoint,
+            out_endpoint: self.out_endpoint,
+            force: self.force,
         }
-    };
+    }
 
-    let mut sniffer = match SnifferDevice::new(device) {
-        Ok(n) => n,
-        Err(e) => {
-            println!("Failed to open sniffer device for communication: {}", e);
-            exit(1);
+    fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval)
+    }
+
+    fn session_options(&self) -> SessionOptions {
+        SessionOptions {
+            fcs: self.fcs,
+            queue_depth: self.queue_depth,
+            on_full: self.on_full,
+            command_delay: Duration::from_millis(self.command_delay),
+            rssi_decay: self.rssi_decay,
+            no_sniff_off: self.no_sniff_off,
+            lowpan_fragments: self.lowpan_fragments,
+            flush_interval: self.flush_interval(),
+            heartbeat: Duration::from_secs(self.heartbeat),
         }
-    };
+    }
 
-    if cli.debug {
-        sniffer.set_debug();
+    /// The type, address and expression filters, built once per capture since it copies
+    /// them
+    fn frame_filter(&self) -> FrameFilter {
+        FrameFilter {
+            allow_types: self.allow_type.clone(),
+            deny_types: self.deny_type.clone(),
+            allow_addresses: self.allow_addr.clone(),
+            deny_addresses: self.deny_addr.clone(),
+            expression: self.filter.clone(),
+        }
+    }
+
+    /// A new --stop-on condition, None without one
+    fn stop_condition(&self) -> Option<StopCondition> {
+        let expression = self.stop_on.clone()?;
+        Some(StopCondition::new(expression, self.stop_after))
     }
 
-    let mut pcap_ng_writer = PcapNgWriter::new(file).unwrap();
+    /// Empty stats for a conversion, a capture session sets up its own
+    fn capture_stats(&self) -> CaptureStats {
+        CaptureStats {
+            sources: SourceSignals::new(self.rssi_decay),
+            lowpan_fragments: self.lowpan_fragments.then_some(0),
+            ..Default::default()
+        }
+    }
 
-    let idb = InterfaceDescriptionBlock {
-        linktype: DataLink::IEEE802_15_4_TAP,
-        snaplen: 256,
-        options: vec![
-            InterfaceDescriptionOption::IfName(Cow::from("cc2531-usb")),
-            InterfaceDescriptionOption::IfDescription(Cow::from(sniffer.get_product_name().unwrap())),
-            InterfaceDescriptionOption::IfTsResol(9), // pcap-file library uses nanoseconds for timestamps
-        ],
-    };
-    pcap_ng_writer.write_block(&idb.into_block()).unwrap();
+    #[cfg(feature = "config")]
+    /// Takes every option that wasn't given on the command line from the config
+    fn apply_config(&mut self, config: Config, matches: &ArgMatches) {
+        let keep = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if let Some(channel) = config.channel.filter(|_| !keep("channel")) {
+            self.channel = channel;
+        }
+        if let Some(capture_file) = config.capture_file.filter(|_| !keep("capture_file")) {
+            self.capture_file = Some(capture_file);
+        }
+        if let Some(fcs) = config.fcs.filter(|_| !keep("fcs")) {
+            self.fcs = fcs;
+        }
+        if let Some(snaplen) = config.snaplen.filter(|_| !keep("snaplen")) {
+            self.snaplen = snaplen;
+        }
+        if let Some(out_format) = config.out_format.filter(|_| !keep("out_format")) {
+            self.out_format = out_format;
+        }
+        if let Some(compress) = config.compress.filter(|_| !keep("compress")) {
+            self.compress = compress;
+        }
+        if let Some(beacons) = config.beacons.filter(|_| !keep("beacons")) {
+            self.beacons = beacons;
+        }
+        if let Some(drop) = config
+            .drop_unparseable
+            .filter(|_| !keep("drop_unparseable"))
+        {
+            self.drop_unparseable = drop;
+        }
+        if let Some(drop) = config
+            .drop_inconsistent
+            .filter(|_| !keep("drop_inconsistent"))
+        {
+            self.drop_inconsistent = drop;
+        }
+        if let Some(expression) = config.filter.filter(|_| !keep("filter")) {
+            self.filter = Some(expression);
+        }
+        if let Some(expression) = config.trigger.filter(|_| !keep("trigger")) {
+            self.trigger = Some(expression);
+        }
+        if let Some(pretrigger) = config.pretrigger.filter(|_| !keep("pretrigger")) {
+            self.pretrigger = pretrigger;
+        }
+        if let Some(expression) = config.stop_on.filter(|_| !keep("stop_on")) {
+            self.stop_on = Some(expression);
+        }
+        if let Some(frames) = config.stop_after.filter(|_| !keep("stop_after")) {
+            self.stop_after = frames;
+        }
+        if let Some(length) = config.timeslot_length.filter(|_| !keep("timeslot_length")) {
+            self.timeslot_length = Some(length);
+        }
+        if let Some(phy) = config.phy.filter(|_| !keep("phy")) {
+            self.phy = Some(phy);
+        }
+        if let Some(types) = config.allow_type.filter(|_| !keep("allow_type")) {
+            self.allow_type = types;
+        }
+        if let Some(types) = config.deny_type.filter(|_| !keep("deny_type")) {
+            self.deny_type = types;
+        }
+        if let Some(addresses) = config.allow_addr.filter(|_| !keep("allow_addr")) {
+            self.allow_addr = addresses;
+        }
+        if let Some(addresses) = config.deny_addr.filter(|_| !keep("deny_addr")) {
+            self.deny_addr = addresses;
+        }
+        if let Some(linktype) = config.linktype.filter(|_| !keep("linktype")) {
+            self.linktype = linktype;
+        }
+        if let Some(radio_comments) = config.radio_comments.filter(|_| !keep("radio_comments")) {
+            self.radio_comments = radio_comments;
+        }
+        if let Some(lqi_mode) = config.lqi_mode.filter(|_| !keep("lqi_mode")) {
+            self.lqi_mode = lqi_mode;
+        }
+        if let Some(location) = config.location.filter(|_| !keep("location")) {
+            self.location = Some(location);
+        }
+        if let Some(comment) = config.comment.filter(|_| !keep("comment")) {
+            self.comment = comment;
+        }
+        if let Some(raw_out) = config.raw_out.filter(|_| !keep("raw_out")) {
+            self.raw_out = Some(raw_out);
+        }
+        if let Some(summary) = config.summary.filter(|_| !keep("summary")) {
+            self.summary = Some(summary);
+        }
+        if let Some(index) = config.index.filter(|_| !keep("index")) {
+            self.index = Some(index);
+        }
+        if let Some(histograms) = config.histograms.filter(|_| !keep("histograms")) {
+            self.histograms = Some(histograms);
+        }
+        if let Some(summary_only) = config.summary_only.filter(|_| !keep("summary_only")) {
+            self.summary_only = summary_only;
+        }
+        if let Some(seed) = config.checksum_seed.filter(|_| !keep("checksum_seed")) {
+            self.checksum_seed = seed;
+        }
+        if let Some(strict) = config.strict.filter(|_| !keep("strict")) {
+            self.strict = strict;
+        }
+        if let Some(len) = config.max_frame_len.filter(|_| !keep("max_frame_len")) {
+            self.max_frame_len = len;
+        }
+        if let Some(strict) = config.strict_version.filter(|_| !keep("strict_version")) {
+            self.strict_version = strict;
+        }
+        if let Some(name) = config.match_name.filter(|_| !keep("match_name")) {
+            self.match_name = Some(name);
+        }
+        if let Some(index) = config.usb_config.filter(|_| !keep("usb_config")) {
+            self.usb_config = Some(index);
+        }
+        if let Some(address) = config.in_endpoint.filter(|_| !keep("in_endpoint")) {
+            self.in_endpoint = Some(address);
+        }
+        if let Some(address) = config.out_endpoint.filter(|_| !keep("out_endpoint")) {
+            self.out_endpoint = Some(address);
+        }
+        if let Some(delay) = config.command_delay.filter(|_| !keep("command_delay")) {
+            self.command_delay = delay;
+        }
+        if let Some(settle) = config.settle_ms.filter(|_| !keep("settle_ms")) {
+            self.settle_ms = settle;
+        }
+        if let Some(wait) = config.wait.filter(|_| !keep("wait")) {
+            self.wait = wait;
+        }
+        if let Some(debounce) = config
+            .plug_debounce_ms
+            .filter(|_| !keep("plug_debounce_ms"))
+        {
+            self.plug_debounce_ms = debounce;
+        }
+        if let Some(depth) = config.queue_depth.filter(|_| !keep("queue_depth")) {
+            self.queue_depth = depth;
+        }
+        if let Some(on_full) = config.on_full.filter(|_| !keep("on_full")) {
+            self.on_full = on_full;
+        }
+        if let Some(decay) = config.rssi_decay.filter(|_| !keep("rssi_decay")) {
+            self.rssi_decay = decay;
+        }
+        if let Some(top_talkers) = config.top_talkers.filter(|_| !keep("top_talkers")) {
+            self.top_talkers = top_talkers;
+        }
+        if let Some(resolution) = config.ts_resolution.filter(|_| !keep("ts_resolution")) {
+            self.ts_resolution = resolution;
+        }
+        if let Some(relative) = config.relative_time.filter(|_| !keep("relative_time")) {
+            self.relative_time = relative;
+        }
+        if let Some(size) = config.batch_size.filter(|_| !keep("batch_size")) {
+            self.batch_size = size;
+        }
+        if let Some(interval) = config.flush_interval.filter(|_| !keep("flush_interval")) {
+            self.flush_interval = interval;
+        }
+        if let Some(fragments) = config
+            .lowpan_fragments
+            .filter(|_| !keep("lowpan_fragments"))
+        {
+            self.lowpan_fragments = fragments;
+        }
+        if let Some(flags) = config.direction_flags.filter(|_| !keep("direction_flags")) {
+            self.direction_flags = flags;
+        }
+        if let Some(no_sniff_off) = config.no_sniff_off.filter(|_| !keep("no_sniff_off")) {
+            self.no_sniff_off = no_sniff_off;
+        }
+        if let Some(quiet) = config.quiet.filter(|_| !keep("quiet")) {
+            self.quiet = quiet;
+        }
+        if let Some(path) = config.log_file.filter(|_| !keep("log_file")) {
+            self.log_file = Some(path);
+        }
+        if let Some(mode) = config.log_mode.filter(|_| !keep("log_mode")) {
+            self.log_mode = mode;
+        }
+        if let Some(interactive) = config.interactive.filter(|_| !keep("interactive")) {
+            self.interactive = interactive;
+        }
+        if let Some(backoff) = config.idle_backoff.filter(|_| !keep("idle_backoff")) {
+            self.idle_backoff = backoff;
+        }
+        if let Some(check) = config.startup_check.filter(|_| !keep("startup_check")) {
+            self.startup_check = check;
+        }
+        if let Some(interval) = config
+            .keepalive_interval
+            .filter(|_| !keep("keepalive_interval"))
+        {
+            self.keepalive_interval = interval;
+        }
+        if let Some(heartbeat) = config.heartbeat.filter(|_| !keep("heartbeat")) {
+            self.heartbeat = heartbeat;
+        }
+    }
+}
 
-    let sniffer = sniffer;
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a file with raw frames to a capture file, without using a sniffer
+    FromFile {
+        input: PathBuf,
 
-    println!("Connected to {}", sniffer.get_product_name().unwrap());
+        #[arg(long, value_enum, default_value = "hex")]
+        format: InputFormat,
+    },
+    /// Print statistics for a capture file written with the TAP link type, applying the
+    /// same filters as a live capture
+    Analyze {
+        input: PathBuf,
 
-    // After repeated used there might be packets in the queue
-    // Drain by reading and ignoring errors
-    _ = sniffer.receive_packet();
+        /// Write the packets that pass the filters to this file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print what the sniffer can do and exit, without capturing
+    Capabilities,
+    /// Send any command to the sniffer and print the response as a hex dump, for
+    /// experimenting with the firmware
+    RawCmd {
+        /// Command code in hex, e.g. 00 for init
+        #[arg(value_parser = parse_hex_u8)]
+        code: u8,
 
-    println!("Send CmdInit");
-    sniffer.send_command(sniffer::CmdCodes::CmdInit, &[])?;
+        /// Payload bytes in hex, e.g. 0b
+        #[arg(value_parser = parse_hex_u8)]
+        payload: Vec<u8>,
+    },
+    /// Check the USB framing, checksum, TAP and FCS codecs without a sniffer and exit
+    Verify,
+    /// Print the TAP TLVs capture files can carry as JSON: type, name, length and
+    /// fields, for generating documentation
+    TapSchema,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let matches = Cli::command().get_matches();
+    let cli = parse_cli(&matches);
+    if cli.usb_info {
+        return usb_info(&cli);
+    }
+    if let Some(path) = &cli.log_file {
+        match RunLog::open(path, cli.log_mode) {
+            Ok(log) => {
+                let _ = RUN_LOG.set(Mutex::new(log));
+                log_message(&format!("Started {}", command_line(std::env::args())));
+            }
+            Err(e) => {
+                println!("Can't open the log file {}: {}", path.display(), e);
+                exit(1);
+            }
+        }
+    }
+    if let Err(e) = cli.check_output_paths() {
+        diag!("Invalid output file: {}", e);
+        exit(1);
+    }
 
-    println!("Send CmdSetChannel {}", cli.channel);
-    sniffer.send_command(CmdCodes::CmdSetChannel, vec![cli.channel].as_slice())?;
+    let result = match &cli.command {
+        Some(Command::FromFile { input, format }) => from_file(&cli, input, *format),
+        Some(Command::Analyze { input, .. }) => analyze(&cli, input),
+        Some(Command::Capabilities) => capabilities(&cli),
+        Some(Command::RawCmd { code, payload }) => raw_cmd(&cli, *code, payload),
+        Some(Command::Verify) => verify_codecs(),
+        Some(Command::TapSchema) => Ok(write_schema_json(&mut std::io::stdout())?),
+        None => capture(&cli),
+    };
+    if let Err(e) = &result {
+        log_message(&format!("Error: {}", e));
+    }
+    result
+}
 
-    println!("Send CmdSniffOn");
-    sniffer.send_command(CmdCodes::CmdSniffOn, &[])?;
+/// Writes to the --log-file, if there is one. A log that can't be written doesn't stop
+/// the capture.
+fn log_message(message: &str) {
+    if let Some(log) = RUN_LOG.get() {
+        let _ = log.lock().unwrap().write(now(), message);
+    }
+}
 
-    println!("Looping over received packets");
-    let mut received_packets = 0;
+/// The attached sniffers, by --match-name or else by their ids
+fn find_sniffers(cli: &Cli) -> Result<Vec<Device<GlobalContext>>, SnifferError> {
+    match &cli.match_name {
+        Some(name) => SnifferDevice::find_devices_named(name),
+        None => SnifferDevice::find_devices(VENDOR, PRODUCT),
+    }
+}
 
-    loop {
-        if break_received_me.load(Ordering::Relaxed) {
-            // Stop sniffing
-            break;
+/// The sniffer's USB device, exits when there is none
+fn find_sniffer(cli: &Cli) -> Device<GlobalContext> {
+    match find_sniffers(cli) {
+        Ok(devices) => devices.into_iter().next().unwrap(),
+        Err(e @ SnifferError::Bootloader(_, _)) => {
+            diag!("Found a CC2531, but {}.", e);
+            diag!("Flash the firmware in cc2531-sniffer-firmware and reconnect the stick.");
+            exit(1);
+        }
+        Err(e) => {
+            diag!("No suitable devices found: {}", e);
+            exit(1);
         }
+    }
+}
 
-        match sniffer.receive_packet() {
-            Ok(n) => {
-                let duration_since_epoch =
-                    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                        Ok(dt) => dt,
-                        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-                    };
-
-                // First two bytes are RSSI (dbm) and link quality index
-                let mut packet_data = n.to_vec();
-                let metadata: Vec<u8> = packet_data.drain(..2).collect();
-                let rssi = i8::from_le_bytes([metadata[0]]) as f32;
-                let lqi = metadata[1];
-
-                let mut epd_data: Vec<u8> = vec![];
-
-                // TAP
-                TapBlock::Header(3).write_to(&mut epd_data)?;
-                TapBlock::TlvRssi(rssi).write_to(&mut epd_data)?;
-                TapBlock::ChannelAssignment(cli.channel as u16).write_to(&mut epd_data)?;
-                TapBlock::TlvLqi(lqi).write_to(&mut epd_data)?;
-
-                epd_data.append(&mut packet_data);
-
-                let packet = EnhancedPacketBlock {
-                    interface_id: 0,
-                    timestamp: duration_since_epoch,
-                    original_len: epd_data.len() as u32,
-                    data: Cow::from(epd_data.as_slice()),
-                    options: vec![],
-                };
+/// The sniffer's USB device once it is plugged in and has settled for --plug-debounce-ms,
+/// exits on Ctrl-C
+fn wait_for_sniffer(cli: &Cli, signals: &mut Signals) -> Device<GlobalContext> {
+    let find = || find_sniffers(cli).ok()?.into_iter().next();
+    let stopped = || signals.pending().any(|s| s == SIGINT);
+    let debounce = Duration::from_millis(cli.plug_debounce_ms);
+    match wait_for_arrival(find, WAIT_POLL_INTERVAL, debounce, stopped) {
+        Some(device) => device,
+        None => exit(0),
+    }
+}
 
-                pcap_ng_writer.write_block(&packet.into_block()).unwrap();
-                received_packets += 1;
+/// Polls `find` until it returns a device. A device that wasn't there at the first poll
+/// has just arrived, so it is only taken when it is still there after `debounce`. One
+/// that drops off while settling is waited for anew.
+fn wait_for_arrival<T>(
+    mut find: impl FnMut() -> Option<T>,
+    interval: Duration,
+    debounce: Duration,
+    mut stopped: impl FnMut() -> bool,
+) -> Option<T> {
+    let mut arrived = false;
+    let mut waiting = false;
+    while !stopped() {
+        match find() {
+            Some(device) if !waiting || arrived => return Some(device),
+            Some(_) => {
+                diag!(
+                    "Sniffer plugged in, waiting {} ms for it to settle",
+                    debounce.as_millis()
+                );
+                thread::sleep(debounce);
+                arrived = true;
             }
-            Err(e) => match e {
-                SnifferError::TimeOut => {}
-                _ => {
-                    println!("read failed with error: {e}");
-                    break;
+            None => {
+                if !waiting {
+                    diag!("Waiting for the sniffer to be plugged in");
+                    waiting = true;
                 }
-            },
-        };
+                arrived = false;
+                thread::sleep(interval);
+            }
+        }
+    }
+    None
+}
+
+fn usb_info(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let device = find_sniffer(cli);
+    print!("{}", SnifferDevice::describe(&device)?);
+    Ok(())
+}
+
+/// The firmware can't be asked for its capabilities, so after checking that the attached
+/// sniffer answers, the ones known for the CC2531 are printed
+fn capabilities(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    match find_sniffers(cli).map(|devices| devices.into_iter().next().unwrap()) {
+        Ok(device) => {
+            let mut sniffer = SnifferDevice::with_options(device, &cli.usb_options())?;
+            sniffer.set_checksum_seed(cli.checksum_seed);
+            let product_name = sniffer.get_product_name();
+            diag!("Device: {}", product_name.as_deref().unwrap_or("unknown"));
+            match sniffer.verify() {
+                Ok(Some(version)) => diag!(
+                    "Firmware: answers like a sniffer, protocol version {}",
+                    version
+                ),
+                Ok(None) => {
+                    diag!("Firmware: answers like a sniffer, without a protocol version")
+                }
+                Err(e) => diag!("Firmware: {}", e),
+            }
+        }
+        Err(e) => diag!("Device: {}", e),
     }
+    diag!("Reported by the firmware: nothing, it has no capabilities command");
+    diag!();
+    diag!("Known for the CC2531:");
+    for line in Capabilities::cc2531().to_string().lines() {
+        diag!("  {}", line);
+    }
+    Ok(())
+}
+
+/// Sends the command as given, the response isn't checked
+fn raw_cmd(cli: &Cli, code: u8, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+    let device = find_sniffer(cli);
+    let mut sniffer = SnifferDevice::with_options(device, &cli.usb_options())?;
+    if cli.debug {
+        sniffer.set_debug();
+    }
+    sniffer.set_checksum_seed(cli.checksum_seed);
+
+    let response = sniffer.raw_command(code, payload)?;
+    let message = Message::from_slice(&response, false);
+    match message {
+        Ok(message) => diag!(
+            "Response code {:#04x} ({:?}), {} bytes",
+            message.code,
+            CmdCodes::from(message.code),
+            response.len()
+        ),
+        Err(e) => diag!("Response doesn't parse: {}, {} bytes", e, response.len()),
+    }
+    dump(&response, response.len());
+    Ok(())
+}
+
+/// Runs the self checks, exits with 1 when one fails
+fn verify_codecs() -> Result<(), Box<dyn Error>> {
+    let mut failed = false;
+    for check in selfcheck::run() {
+        match check.result {
+            Ok(()) => diag!("PASS {}", check.name),
+            Err(e) => {
+                diag!("FAIL {}: {}", check.name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(1);
+    }
+    Ok(())
+}
 
-    println!("Send CmdSniffOff");
-    sniffer.send_command(CmdCodes::CmdSniffOff, &[])?;
+fn capture(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    if !cli.channels.is_empty() {
+        return capture_channels(cli);
+    }
 
-    println!("Captured {} packets", received_packets);
-    return Ok(());
+    let break_received = Arc::new(AtomicBool::new(false));
+    let break_received_me = break_received.clone();
+
+    diag!("CCSniffer");
+    diag!("------------------");
+    diag!("  Channel: {}", cli.channel);
+    diag!("  FCS: {:?}", cli.fcs);
+    if let Some(capture_file) = cli.capture_file.as_ref().filter(|_| !cli.summary_only) {
+        let capture_file = cli.run_path(capture_file, None);
+        diag!("  Capture file: {}", capture_file.display())
+    }
+    if let Some(summary) = &cli.summary {
+        diag!("  Summary file: {}", summary.display())
+    }
+    if let Some(state) = &cli.resume_state {
+        diag!(
+            "  Run: {}, {} packets captured before",
+            state.file_index,
+            state.packets
+        );
+    }
+    diag!();
+
+    // Registered early so a break during setup isn't lost, handled once the capture runs
+    let mut signals = Signals::new([SIGINT].into_iter().chain(STATS_SIGNAL))?;
+
+    let device = if cli.wait {
+        wait_for_sniffer(cli, &mut signals)
+    } else {
+        find_sniffer(cli)
+    };
+
+    let mut sniffer = match SnifferDevice::with_options(device, &cli.usb_options()) {
+        Ok(n) => n,
+        Err(e) => {
+            if let Some(SnifferError::AccessDenied(vendor, product)) = e.downcast_ref() {
+                diag!(
+                    "No permission to open the sniffer {:04x}:{:04x}.",
+                    vendor,
+                    product
+                );
+                diag!("Add a udev rule, e.g. in /etc/udev/rules.d/99-ccsniffer.rules:");
+                diag!("  {}", udev_rule(*vendor, *product));
+                diag!("and reconnect the sniffer.");
+                if cli.debug {
+                    diag!("usb error: {}", rusb::Error::Access);
+                }
+            } else if let Some(SnifferError::Busy(_, _)) = e.downcast_ref() {
+                diag!("The sniffer is already in use, another ccsniffer-rust or a Wireshark");
+                diag!("capture may be running. Stop it and try again.");
+                if !cli.force {
+                    diag!("If a kernel driver holds the sniffer, --force detaches it.");
+                }
+            } else {
+                diag!("Failed to open sniffer device for communication: {}", e);
+            }
+            exit(1);
+        }
+    };
+
+    if cli.debug {
+        sniffer.set_debug();
+    }
+    sniffer.set_checksum_seed(cli.checksum_seed);
+    sniffer.set_stop_flag(break_received_me.clone());
+    if cli.strict {
+        sniffer.set_strict();
+    }
+    sniffer.set_max_frame_len(cli.max_frame_len);
+
+    match sniffer.verify() {
+        Ok(version) => check_version(cli, version),
+        Err(e) => {
+            diag!("Failed to initialize the sniffer: {}", e);
+            exit(1);
+        }
+    }
+
+    let product_name = sniffer.get_product_name().unwrap();
+    let sinks = match cli.packet_sinks(&product_name, None) {
+        Ok(s) => s,
+        Err(e) => {
+            diag!("Failed to write capture file: {}", e);
+            exit(1);
+        }
+    };
+
+    diag!("Connected to {}", product_name);
+
+    diag!("Starting capture on channel {}", cli.channel);
+    let capture_started = now();
+    let mut session =
+        match CaptureSession::start(sniffer, cli.channel, sinks, &cli.session_options()) {
+            Ok(session) => session,
+            Err(e @ SessionError::DrainFailed(_)) => {
+                diag!("Can't start the capture, {}", e);
+                exit(1);
+            }
+            Err(e) => return Err(e.into()),
+        };
+    diag!("Sniffer {}", session.device_state());
+    save_resume_state(cli, 0);
+    settle(cli);
+
+    let progress_stats = session.stats();
+    let stats = session.stats();
+    let started = Instant::now();
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            if Some(sig) == STATS_SIGNAL {
+                let report = stats.lock().unwrap().report(started.elapsed());
+                eprintln!("{}", report);
+                log_message(&report);
+                continue;
+            }
+
+            diag!("Received signal {:?}", sig);
+            if sig == 2 {
+                // CTRLC
+                if break_received.load(Ordering::Relaxed) {
+                    // Received twice, just die
+                    std::process::exit(2);
+                } else {
+                    diag!("Attempting to stop sniffer");
+                    break_received.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+
+    let pause_requested = Arc::new(AtomicBool::new(false));
+    if cli.interactive {
+        let pause_requested = pause_requested.clone();
+        thread::spawn(move || {
+            for _ in std::io::stdin().lines() {
+                pause_requested.fetch_xor(true, Ordering::Relaxed);
+            }
+        });
+        diag!("Press Enter to pause or resume the capture");
+    }
+
+    diag!("Looping over received packets");
+
+    // The line is overwritten in place, which only works when someone is watching and
+    // nothing else is printed per frame
+    let show_progress = !cli.quiet && !cli.live && !cli.beacons && std::io::stdout().is_terminal();
+    let mut last_progress = Instant::now();
+    let mut last_resume_save = Instant::now();
+    let mut backoff = IdleBackoff::new(Duration::from_millis(cli.idle_backoff));
+    let mut startup_check = StartupCheck::new(Duration::from_secs(cli.startup_check));
+    let mut keepalive = KeepaliveTimer::new(Duration::from_secs(cli.keepalive_interval));
+    let rules = FrameRules::new(cli);
+
+    loop {
+        if break_received_me.load(Ordering::Relaxed) {
+            // Stop sniffing
+            break;
+        }
+        if rules.stop_reached() {
+            diag!("Stopping after the --stop-on frame");
+            break;
+        }
+
+        if pause_requested.load(Ordering::Relaxed) != session.is_paused() {
+            let toggled = if session.is_paused() {
+                session.resume().map(|_| vec![])
+            } else {
+                session.pause()
+            };
+            // Packets received before the sniffer stopped
+            let pending = match toggled {
+                Ok(pending) => pending,
+                Err(e) => {
+                    diag!("Failed to pause or resume the sniffer: {e}");
+                    break;
+                }
+            };
+            let kept = pending
+                .into_iter()
+                .try_for_each(|p| keep_packet(cli, &rules, &session, p));
+            if kept.is_err() {
+                break;
+            }
+            if session.is_paused() {
+                startup_check.stop();
+                diag!("Paused");
+            } else {
+                diag!("Resumed");
+            }
+        }
+
+        if show_progress && last_progress.elapsed() >= PROGRESS_INTERVAL {
+            let mut report = progress_stats.lock().unwrap().report(started.elapsed());
+            if session.is_paused() {
+                report.push_str(", paused");
+            }
+            print!("\r{}\x1b[K", report);
+            _ = std::io::stdout().flush();
+            last_progress = Instant::now();
+        }
+
+        if last_resume_save.elapsed() >= RESUME_SAVE_INTERVAL {
+            save_resume_state(cli, progress_stats.lock().unwrap().packets);
+            last_resume_save = Instant::now();
+        }
+
+        if session.is_paused() {
+            thread::sleep(PAUSE_POLL_INTERVAL);
+            continue;
+        }
+
+        let received = session.receive_packet();
+        if !matches!(received, Err(SnifferError::TimeOut)) {
+            backoff.reset();
+        }
+        match received {
+            Ok(Some(packet)) => {
+                startup_check.stop();
+                keepalive.reset();
+                if keep_packet(cli, &rules, &session, packet).is_err() {
+                    // The writer stopped, the error is reported by finish
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => match e {
+                SnifferError::TimeOut => {
+                    if startup_check.timed_out() {
+                        diag!(
+                            "No frames in the first {} s on channel {}, the channel may be idle or sniffing didn't start",
+                            cli.startup_check, cli.channel
+                        );
+                    }
+                    if check_alive(cli, &rules, &session, &mut keepalive, None).is_err() {
+                        break;
+                    }
+                    thread::sleep(backoff.timed_out())
+                }
+                SnifferError::NonPacketFrame(_) | SnifferError::FrameTooLong(_) => {
+                    if cli.debug {
+                        diag!("Skipping {e}");
+                    }
+                }
+                SnifferError::Disconnected => {
+                    diag!("The sniffer was unplugged, saving what was captured");
+                    break;
+                }
+                _ => {
+                    diag!("read failed with error: {e}");
+                    break;
+                }
+            },
+        };
+    }
+
+    if show_progress {
+        diag!();
+    }
+    diag!("Stopping capture");
+    stop_session(cli, &rules, &mut session);
+    let stats = match session.finish() {
+        Ok(stats) => stats,
+        Err(SessionError::WriterError(e)) => {
+            diag!("Writing the capture failed: {}", e);
+            diag!("The sniffer was stopped, the files keep what was written before the error");
+            exit(EXIT_WRITE_FAILED);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    save_resume_state(cli, stats.packets);
+
+    diag!("Captured {} packets", stats.packets);
+    if stats.frame_times.is_some() {
+        diag!(
+            "The packets span {:.1} s, the longest gap between two was {:.1} s",
+            stats.duration().as_secs_f64(),
+            stats.longest_gap.as_secs_f64()
+        );
+    }
+    if stats.dropped > 0 {
+        diag!(
+            "Dropped {} packets because the writer fell behind",
+            stats.dropped
+        );
+    }
+    if stats.keepalive_failures > 0 {
+        diag!(
+            "The sniffer didn't answer {} keepalives",
+            stats.keepalive_failures
+        );
+    }
+    if stats.too_long > 0 {
+        diag!(
+            "Dropped {} frames longer than {} bytes",
+            stats.too_long,
+            cli.max_frame_len
+        );
+    }
+    if stats.length_mismatches > 0 {
+        diag!(
+            "Read {} frames with differing length fields using the smaller one",
+            stats.length_mismatches
+        );
+    }
+    if cli.drop_unparseable {
+        diag!(
+            "Left out {} frames with a MAC header that didn't parse",
+            stats.unparseable
+        );
+    }
+    if cli.drop_inconsistent {
+        diag!(
+            "Left out {} frames too short for their MAC header",
+            stats.inconsistent
+        );
+    }
+    print_fcs_stats(cli, &stats);
+    print_filter_stats(cli, &stats);
+    print_source_stats(&stats);
+    print_top_talkers(cli, &stats);
+    print_type_chart(&stats)?;
+    write_histograms(cli, &stats)?;
+    write_summary(cli, product_name, capture_started, stats)
+}
+
+/// Records the file number and the packets of all runs so far for the next --resume
+fn save_resume_state(cli: &Cli, packets: u64) {
+    let (Some(path), Some(state)) = (&cli.resume, &cli.resume_state) else {
+        return;
+    };
+    let state = ResumeState {
+        packets: state.packets + packets,
+        ..state.clone()
+    };
+    if let Err(e) = state.save(path) {
+        diag!("Failed to save {}: {}", path.display(), e);
+    }
+}
+
+/// Warns about a protocol version outside the tested ones, exits with --strict-version
+fn check_version(cli: &Cli, version: Option<u8>) {
+    if let Err(e) = check_protocol_version(version) {
+        if cli.strict_version {
+            diag!("Refusing the sniffer: {}", e);
+            exit(1);
+        }
+        diag!("warning: {}, the capture may not work", e);
+    }
+}
+
+/// Captures every channel of --channels with its own sniffer into its own files, each
+/// read on its own thread
+fn capture_channels(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let devices = match find_sniffers(cli) {
+        Ok(devices) => devices,
+        Err(e) => {
+            diag!("No suitable devices found: {}", e);
+            exit(1);
+        }
+    };
+    if cli.channels.len() > devices.len() {
+        diag!(
+            "{} channels requested, but only {} sniffers are attached",
+            cli.channels.len(),
+            devices.len()
+        );
+        exit(1);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut signals = Signals::new([SIGINT])?;
+    let stop_signal = stop.clone();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if stop_signal.swap(true, Ordering::Relaxed) {
+                // Received twice, just die
+                exit(2);
+            }
+            diag!("Attempting to stop the sniffers");
+        }
+    });
+
+    let mut sessions = vec![];
+    for (&channel, device) in cli.channels.iter().zip(devices) {
+        let mut sniffer = SnifferDevice::with_options(device, &cli.usb_options())?;
+        if cli.debug {
+            sniffer.set_debug();
+        }
+        sniffer.set_checksum_seed(cli.checksum_seed);
+        sniffer.set_stop_flag(stop.clone());
+        if cli.strict {
+            sniffer.set_strict();
+        }
+        sniffer.set_max_frame_len(cli.max_frame_len);
+        check_version(cli, sniffer.verify()?);
+
+        let product_name = sniffer.get_product_name().unwrap_or_default();
+        let sinks = cli.packet_sinks(&product_name, Some(channel))?;
+        let session = CaptureSession::start(sniffer, channel, sinks, &cli.session_options())?;
+        diag!("Capturing channel {} with {}", channel, product_name);
+        sessions.push((channel, session));
+    }
+
+    settle(cli);
+    let started = Instant::now();
+    let rules = FrameRules::new(cli);
+    let results: Vec<_> = thread::scope(|scope| {
+        let readers: Vec<_> = sessions
+            .into_iter()
+            .map(|(channel, session)| {
+                let (rules, stop) = (&rules, &stop);
+                scope.spawn(move || (channel, read_channel(cli, rules, channel, session, stop)))
+            })
+            .collect();
+        readers.into_iter().map(|r| r.join().unwrap()).collect()
+    });
+
+    diag!("Stopping capture");
+    let mut failed = false;
+    let mut write_failed = false;
+    for (channel, result) in results {
+        let file = cli.output_file().map(|path| channel_path(path, channel));
+        let file = file.as_deref().unwrap_or(Path::new("no capture file"));
+        match result {
+            Ok(stats) => diag!(
+                "Channel {}: {}, {}",
+                channel,
+                file.display(),
+                stats.report(started.elapsed())
+            ),
+            Err(e) => {
+                diag!("Channel {}: {}, failed: {}", channel, file.display(), e);
+                failed = true;
+                write_failed |= matches!(e, SessionError::WriterError(_));
+            }
+        }
+    }
+    if write_failed {
+        exit(EXIT_WRITE_FAILED);
+    }
+    if failed {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// Queues the packets of one channel of --channels until `stop` is set or the sniffer
+/// fails, then finishes the session
+fn read_channel(
+    cli: &Cli,
+    rules: &FrameRules,
+    channel: u8,
+    mut session: CaptureSession,
+    stop: &AtomicBool,
+) -> Result<CaptureStats, SessionError> {
+    let mut backoff = IdleBackoff::new(Duration::from_millis(cli.idle_backoff));
+    let mut startup_check = StartupCheck::new(Duration::from_secs(cli.startup_check));
+    let mut keepalive = KeepaliveTimer::new(Duration::from_secs(cli.keepalive_interval));
+    while !stop.load(Ordering::Relaxed) && !rules.stop_reached() {
+        let received = session.receive_packet();
+        if !matches!(received, Err(SnifferError::TimeOut)) {
+            backoff.reset();
+        }
+        match received {
+            Ok(Some(packet)) => {
+                startup_check.stop();
+                keepalive.reset();
+                if keep_packet(cli, rules, &session, packet).is_err() {
+                    // The writer stopped, the error is reported by finish
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(e @ (SnifferError::NonPacketFrame(_) | SnifferError::FrameTooLong(_))) => {
+                if cli.debug {
+                    diag!("Channel {channel}: skipping {e}");
+                }
+            }
+            Err(SnifferError::TimeOut) => {
+                if startup_check.timed_out() {
+                    diag!(
+                        "Channel {}: no frames in the first {} s, the channel may be idle or sniffing didn't start",
+                        channel, cli.startup_check
+                    );
+                }
+                if check_alive(cli, rules, &session, &mut keepalive, Some(channel)).is_err() {
+                    break;
+                }
+                thread::sleep(backoff.timed_out())
+            }
+            Err(e) => {
+                diag!("Channel {}: read failed with error: {}", channel, e);
+                break;
+            }
+        }
+    }
+    stop_session(cli, rules, &mut session);
+    session.finish()
+}
+
+/// Sends a keepalive when --keepalive-interval passed without a frame and warns when the
+/// sniffer doesn't answer, `channel` is given with --channels. Fails if the writer stopped.
+fn check_alive(
+    cli: &Cli,
+    rules: &FrameRules,
+    session: &CaptureSession,
+    keepalive: &mut KeepaliveTimer,
+    channel: Option<u8>,
+) -> Result<(), SessionError> {
+    if !keepalive.due() {
+        return Ok(());
+    }
+    match session.keepalive() {
+        Ok(Some(packet)) => keep_packet(cli, rules, session, packet),
+        Ok(None) => Ok(()),
+        Err(e) => {
+            let warning = format!("the sniffer didn't answer a keepalive: {}", e);
+            match channel {
+                Some(channel) => diag!("Channel {}: {}", channel, warning),
+                None => diag!("Warning: {}", warning),
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The file for `channel` of --channels: capture.pcap becomes capture-ch15.pcap
+fn channel_path(path: &Path, channel: u8) -> PathBuf {
+    suffixed_path(path, &format!("-ch{}", channel))
+}
+
+/// Adds the suffix to the file name, before the extension
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+fn from_file(cli: &Cli, input: &Path, format: InputFormat) -> Result<(), Box<dyn Error>> {
+    let reader = BufReader::new(File::open(input)?);
+    let description = format!("replay of {}", input.display());
+    let mut sinks = cli.packet_sinks(&description, None)?;
+    let started = now();
+
+    let channel = cli.channel;
+    let live = cli.live;
+    let beacons = cli.beacons;
+    let filter = cli.frame_filter();
+    let mut stop_condition = cli.stop_condition();
+    // Replayed frames are never dropped
+    let (tx, rx) = queue::bounded(cli.queue_depth, OnFull::Block);
+    let reader_thread = thread::spawn(move || -> Result<u64, ReplayError> {
+        let mut filtered = 0;
+        for frame in read_frames(reader, format)? {
+            // There is no radio metadata for raw frames
+            let packet = CapturedPacket {
+                timestamp: now(),
+                channel,
+                rssi: 0,
+                lqi: 0,
+                payload: frame,
+            };
+
+            if !show_packet(&packet, live, beacons, &filter) {
+                filtered += 1;
+                continue;
+            }
+
+            let last = stop_condition.as_mut().is_some_and(|c| c.record(&packet));
+            if tx.send(packet).is_err() || last {
+                break;
+            }
+        }
+        Ok(filtered)
+    });
+
+    let mut sinks: Vec<&mut dyn PacketSink> = sinks.iter_mut().map(|s| s.as_mut() as _).collect();
+    let stats = Mutex::new(cli.capture_stats());
+    write_packets(rx, cli.fcs, &mut sinks, &stats, cli.flush_interval())?;
+    let mut stats: CaptureStats = stats.into_inner().unwrap();
+    stats.filtered = reader_thread.join().unwrap()?;
+
+    diag!("Converted {} packets", stats.packets);
+    print_fcs_stats(cli, &stats);
+    print_filter_stats(cli, &stats);
+    print_type_chart(&stats)?;
+    write_histograms(cli, &stats)?;
+    write_summary(cli, description, started, stats)
+}
+
+fn analyze(cli: &Cli, input: &Path) -> Result<(), Box<dyn Error>> {
+    let packets = match read_tap_capture(BufReader::new(File::open(input)?)) {
+        Ok(p) => p,
+        Err(e) => {
+            diag!("Can't analyze {}: {}", input.display(), e);
+            exit(1);
+        }
+    };
+    let description = format!("analysis of {}", input.display());
+    let mut sinks = cli.packet_sinks(&description, None)?;
+    let started = now();
+
+    let total = packets.len() as u64;
+    let filter = cli.frame_filter();
+    let packets: Vec<CapturedPacket> = packets
+        .into_iter()
+        .filter(|p| show_packet(p, cli.live, cli.beacons, &filter))
+        .collect();
+    let first = packets.first().map(|p| p.timestamp).unwrap_or_default();
+    let last = packets.last().map(|p| p.timestamp).unwrap_or_default();
+
+    let mut sinks: Vec<&mut dyn PacketSink> = sinks.iter_mut().map(|s| s.as_mut() as _).collect();
+    let stats = Mutex::new(cli.capture_stats());
+    let kept = packets.len() as u64;
+    write_packets(packets, cli.fcs, &mut sinks, &stats, cli.flush_interval())?;
+    let mut stats: CaptureStats = stats.into_inner().unwrap();
+    stats.filtered = total - kept;
+
+    let report = CaptureSummary {
+        device: description.clone(),
+        capture_id: None,
+        started: first,
+        ended: last,
+        stats: stats.clone(),
+    };
+    report.write_text(&mut std::io::stdout())?;
+    print_fcs_stats(cli, &stats);
+    print_filter_stats(cli, &stats);
+    print_type_chart(&stats)?;
+    write_histograms(cli, &stats)?;
+    write_summary(cli, description, started, stats)
+}
+
+fn write_histograms(cli: &Cli, stats: &CaptureStats) -> Result<(), Box<dyn Error>> {
+    let path = match &cli.histograms {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+
+    let mut file = BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|e| e == "json") {
+        stats.histograms.write_json(&mut file)?;
+    } else {
+        stats.histograms.write_csv(&mut file)?;
+    }
+    Ok(file.flush()?)
+}
+
+fn write_summary(
+    cli: &Cli,
+    device: String,
+    started: Duration,
+    stats: CaptureStats,
+) -> Result<(), Box<dyn Error>> {
+    let path = match &cli.summary {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+
+    let summary = CaptureSummary {
+        device,
+        capture_id: cli.capture_uuid.clone(),
+        started,
+        ended: now(),
+        stats,
+    };
+    let mut file = BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|e| e == "json") {
+        summary.write_json(&mut file)?;
+    } else {
+        summary.write_text(&mut file)?;
+    }
+    Ok(file.flush()?)
+}
+
+fn parse_cli(matches: &ArgMatches) -> Cli {
+    let mut cli = Cli::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+
+    #[cfg(feature = "config")]
+    if let Some(path) = cli.config.clone() {
+        match Config::load(&path) {
+            Ok(config) => cli.apply_config(config, matches),
+            Err(e) => {
+                diag!("Invalid config file {}: {}", path.display(), e);
+                exit(1);
+            }
+        }
+    }
+
+    if cli.compress != Compression::None && cli.out_format != OutputFormat::Json {
+        diag!("--compress only works with --out-format json");
+        exit(1);
+    }
+
+    if cli.relative_time && cli.out_format != OutputFormat::Pcapng {
+        diag!("--relative-time only works with --out-format pcapng");
+        exit(1);
+    }
+    if cli.relative_time {
+        cli.time_origin = Some(now());
+    }
+
+    let phy = cli.phy.unwrap_or_default();
+    if phy != Phy::Oqpsk2450 && !matches!(cli.command, Some(Command::FromFile { .. })) {
+        diag!("The CC2531 only receives oqpsk-2450, other PHYs only work with from-file");
+        exit(1);
+    }
+    if !phy.channels().contains(&cli.channel) {
+        let (first, last) = phy.channels().into_inner();
+        diag!(
+            "Channel {} isn't on the PHY, use {}-{}",
+            cli.channel,
+            first,
+            last
+        );
+        exit(1);
+    }
+
+    // Named after the channel, so captures of different channels don't overwrite each other
+    if cli.capture_file.is_none() {
+        cli.capture_file = Some(if cli.channels.is_empty() {
+            PathBuf::from(format!("capture-ch{}.pcap", cli.channel))
+        } else {
+            // Gets the channel added for every channel of --channels
+            PathBuf::from("capture.pcap")
+        });
+    }
+
+    #[cfg(feature = "nmea")]
+    if let Some(path) = &cli.nmea {
+        let source = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => {
+                diag!("Can't read {}: {}", path.display(), e);
+                exit(1);
+            }
+        };
+        let fix = LatestFix::default();
+        let reader = fix.clone();
+        thread::spawn(move || {
+            if let Err(e) = reader.follow(source) {
+                diag!("Reading NMEA sentences failed: {e}");
+            }
+        });
+        cli.nmea_fix = Some(fix);
+    }
+
+    #[cfg(feature = "capture-id")]
+    if cli.capture_id {
+        cli.capture_uuid = Some(uuid::Uuid::new_v4().to_string());
+    }
+
+    if let Some(path) = cli.resume.as_ref().filter(|_| cli.command.is_none()) {
+        let mut state = ResumeState::load(path).unwrap_or_else(|| {
+            if path.exists() {
+                diag!("Can't read {}, starting fresh", path.display());
+            }
+            ResumeState::default()
+        });
+        state.file_index += 1;
+        // Every run of a resumed capture stores the id of the first one
+        if cli.capture_uuid.is_some() && state.capture_id.is_some() {
+            cli.capture_uuid = state.capture_id.clone();
+        }
+        state.capture_id = cli.capture_uuid.clone();
+        cli.resume_state = Some(state);
+    }
+    cli
+}
+
+/// Waits --settle-ms after sniff on, before the first read
+fn settle(cli: &Cli) {
+    if cli.settle_ms > 0 {
+        thread::sleep(Duration::from_millis(cli.settle_ms));
+    }
+}
+
+/// Stops sniffing and queues the packets the sniffer still had, so the frames received
+/// just before the stop are written too
+fn stop_session(cli: &Cli, rules: &FrameRules, session: &mut CaptureSession) {
+    match session.stop() {
+        Ok(pending) => {
+            // A stopped writer is reported by finish
+            _ = pending
+                .into_iter()
+                .try_for_each(|p| keep_packet(cli, rules, session, p));
+        }
+        // An unplugged sniffer has stopped anyway
+        Err(SnifferError::Disconnected) => {}
+        Err(e) => diag!("Failed to stop the sniffer: {e}"),
+    }
+}
+
+/// Which frames a live capture keeps: the filters and the --stop-on condition, built once
+/// when it starts. The channels of --channels share it, the first channel that reaches the
+/// condition stops all of them.
+struct FrameRules {
+    filter: FrameFilter,
+    stop_condition: Option<Mutex<StopCondition>>,
+}
+
+impl FrameRules {
+    fn new(cli: &Cli) -> Self {
+        FrameRules {
+            filter: cli.frame_filter(),
+            stop_condition: cli.stop_condition().map(Mutex::new),
+        }
+    }
+
+    /// Whether the last frame before --stop-on ends the capture was written
+    fn stop_reached(&self) -> bool {
+        let condition = self.stop_condition.as_ref();
+        condition.is_some_and(|c| c.lock().unwrap().reached())
+    }
+}
+
+/// Prints the packet and queues it for the writer unless a filter leaves it out, fails if
+/// the writer stopped
+fn keep_packet(
+    cli: &Cli,
+    rules: &FrameRules,
+    session: &CaptureSession,
+    packet: CapturedPacket,
+) -> Result<(), SessionError> {
+    if cli.drop_unparseable && !mac_header_parses(&packet.payload) {
+        session.count_unparseable();
+        return Ok(());
+    }
+    if cli.drop_inconsistent
+        && FrameQuality::classify(&packet.payload, false) == FrameQuality::Inconsistent
+    {
+        session.count_inconsistent();
+        return Ok(());
+    }
+    if !show_packet(&packet, cli.live, cli.beacons, &rules.filter) {
+        session.count_filtered();
+        return Ok(());
+    }
+    if let Some(condition) = &rules.stop_condition {
+        let mut condition = condition.lock().unwrap();
+        // Frames that were still in flight when the capture stopped
+        if condition.reached() {
+            return Ok(());
+        }
+        condition.record(&packet);
+    }
+    session.queue_packet(packet)
+}
+
+/// Prints the packet as requested, returns false if the frame filter or --beacons leaves
+/// it out
+fn show_packet(packet: &CapturedPacket, live: bool, beacons: bool, filter: &FrameFilter) -> bool {
+    if !filter.keep_packet(packet) {
+        return false;
+    }
+    if beacons {
+        return match describe_beacon(packet) {
+            Some(description) => {
+                println!("{}", description);
+                true
+            }
+            None => false,
+        };
+    }
+
+    if live {
+        println!("{}", summarize(packet));
+    }
+    true
+}
+
+fn print_filter_stats(cli: &Cli, stats: &CaptureStats) {
+    if !cli.frame_filter().is_empty() {
+        diag!("Left out {} packets by the filters", stats.filtered);
+    } else if cli.beacons {
+        diag!("Left out {} packets that aren't beacons", stats.filtered);
+    }
+    if let Some(fragments) = stats.lowpan_fragments {
+        diag!("Found {} 6LoWPAN fragments", fragments);
+    }
+}
+
+/// Prints the smoothed RSSI per source address, strongest first
+fn print_source_stats(stats: &CaptureStats) {
+    for (address, signal) in stats.sources.sorted() {
+        diag!(
+            "{:>23}: {:6.1} dBm, {} packets",
+            address.to_string(),
+            signal.rssi,
+            signal.packets
+        );
+    }
+}
+
+/// Prints the source addresses that sent the most frames, with the frames without one
+fn print_top_talkers(cli: &Cli, stats: &CaptureStats) {
+    if cli.top_talkers == 0 || stats.packets == 0 {
+        return;
+    }
+    diag!("Top talkers:");
+    for (address, packets) in stats.sources.top_talkers(cli.top_talkers) {
+        diag!("{:>23}: {} packets", address.to_string(), packets);
+    }
+    diag!("{:>23}: {} packets", "unknown", stats.sources.unknown);
+}
+
+/// Prints the frame type chart, only on a terminal so redirected output stays plain
+fn print_type_chart(stats: &CaptureStats) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    if stats.packets == 0 || !stdout.is_terminal() {
+        return Ok(());
+    }
+    diag!("Frame types:");
+    stats.write_type_chart(&mut stdout)
+}
+
+fn print_fcs_stats(cli: &Cli, stats: &CaptureStats) {
+    if cli.fcs == FcsMode::Validate {
+        diag!("Dropped {} packets with a bad FCS", stats.bad_fcs);
+    }
+}
+
+fn parse_hex_u8(s: &str) -> Result<u8, ParseIntError> {
+    u8::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+/// 16 hex digits, optionally separated by colons or dashes
+fn parse_eui(s: &str) -> Result<u64, String> {
+    let digits: String = s.chars().filter(|c| *c != ':' && *c != '-').collect();
+    if digits.len() != 16 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("must be 8 bytes in hex".to_string());
+    }
+    u64::from_str_radix(&digits, 16).map_err(|e| format!("{}", e))
+}
+
+/// Options whose values are never stored
+const SECRET_OPTIONS: [&str; 1] = ["--key"];
+
+/// The arguments as they can be typed into a shell again, with the values of
+/// `SECRET_OPTIONS` replaced
+fn command_line(args: impl IntoIterator<Item = String>) -> String {
+    let mut words = vec![];
+    let mut secret_next = false;
+    for arg in args {
+        if secret_next {
+            words.push("<redacted>".to_string());
+            secret_next = false;
+            continue;
+        }
+        if let Some((option, _)) = arg
+            .split_once('=')
+            .filter(|(o, _)| SECRET_OPTIONS.contains(o))
+        {
+            words.push(format!("{}=<redacted>", option));
+            continue;
+        }
+        secret_next = SECRET_OPTIONS.contains(&arg.as_str());
+        let plain = !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.,:/=@+".contains(c));
+        words.push(if plain {
+            arg
+        } else {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        });
+    }
+    words.join(" ")
+}
+
+fn parse_rssi_decay(s: &str) -> Result<f64, String> {
+    let decay: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !valid_rssi_decay(decay) {
+        return Err("must be greater than 0 and at most 1".to_string());
+    }
+    Ok(decay)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        channel_path, command_line, parse_cli, parse_eui, parse_hex_u8, wait_for_arrival, Cli,
+        FrameRules,
+    };
+    use ccsniffer_rust::fcs::{TAP_FCS_CRC16, TAP_FCS_NONE};
+    use ccsniffer_rust::packet::CapturedPacket;
+    use clap::CommandFactory;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    #[cfg(feature = "config")]
+    use {crate::FcsMode, clap::FromArgMatches};
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert()
+    }
+
+    #[test]
+    fn parse_hex() {
+        assert_eq!(parse_hex_u8("ff"), Ok(0xff));
+        assert_eq!(parse_hex_u8("0x5a"), Ok(0x5a));
+        assert!(parse_hex_u8("100").is_err());
+    }
+
+    #[test]
+    fn parse_eui_address() {
+        assert_eq!(parse_eui("00:12:4b:00:01:02:03:04"), Ok(0x00124b0001020304));
+        assert_eq!(parse_eui("00124B0001020304"), Ok(0x00124b0001020304));
+        assert!(parse_eui("00:12:4b").is_err());
+        assert!(parse_eui("+0124b0001020304").is_err());
+    }
+
+    #[test]
+    fn recorded_command_line() {
+        let args = |args: &[&str]| command_line(args.iter().map(|a| a.to_string()));
+        assert_eq!(
+            args(&["x", "-c", "15", "--filter", "type==data && rssi>-60"]),
+            "x -c 15 --filter 'type==data && rssi>-60'"
+        );
+        assert_eq!(args(&["x", "--comment", "it's"]), "x --comment 'it'\\''s'");
+
+        // The key is never stored, in either form
+        let key = "000102030405060708090a0b0c0d0e0f";
+        let separate = args(&["x", "--key", key, "-l"]);
+        assert_eq!(separate, "x --key <redacted> -l");
+        let joined = args(&["x", &format!("--key={}", key)]);
+        assert_eq!(joined, "x --key=<redacted>");
+        assert!(!separate.contains(key) && !joined.contains(key));
+
+        // A real command line comes back as it was parsed
+        let real = [
+            "ccsniffer",
+            "-c",
+            "15",
+            "--comment",
+            "it's",
+            "--location=-33.8568,151.2153",
+            "--record-args",
+        ];
+        let cli = parse_cli(&Cli::command().try_get_matches_from(real).unwrap());
+        assert!(cli.record_args);
+        assert_eq!(cli.comment, ["it's"]);
+        assert_eq!(
+            args(&real),
+            "ccsniffer -c 15 --comment 'it'\\''s' --location=-33.8568,151.2153 --record-args"
+        );
+    }
+
+    #[test]
+    fn plug_debounce() {
+        let wait = |polls: Vec<Option<u8>>| {
+            let mut polls = polls.into_iter();
+            let find = || polls.next().flatten();
+            wait_for_arrival(find, Duration::ZERO, Duration::ZERO, || false)
+        };
+        // Already plugged in, no debounce
+        assert_eq!(wait(vec![Some(1)]), Some(1));
+        // Taken after the debounce, from the poll after it
+        assert_eq!(wait(vec![None, Some(1), Some(2)]), Some(2));
+        // A bounce during the debounce starts the wait over
+        assert_eq!(wait(vec![None, Some(1), None, Some(2), Some(3)]), Some(3));
+
+        assert_eq!(
+            wait_for_arrival(|| None::<u8>, Duration::ZERO, Duration::ZERO, || true),
+            None
+        );
+    }
+
+    #[test]
+    fn channel_files() {
+        let args = ["ccsniffer", "--channels", "11,15"];
+        let cli = parse_cli(&Cli::command().try_get_matches_from(args).unwrap());
+        assert_eq!(cli.channels, [11, 15]);
+        let path = cli.capture_file.unwrap();
+        assert_eq!(channel_path(&path, 15), PathBuf::from("capture-ch15.pcap"));
+
+        let path = Path::new("out/raw");
+        assert_eq!(channel_path(path, 11), PathBuf::from("out/raw-ch11"));
+
+        let args = ["ccsniffer", "--channels", "11,27"];
+        assert!(Cli::command().try_get_matches_from(args).is_err());
+    }
+
+    #[test]
+    fn resumed_files() {
+        let state = std::env::temp_dir().join(format!("ccsniffer-state-{}", std::process::id()));
+        let args = ["ccsniffer", "--resume", state.to_str().unwrap()];
+        let cli = parse_cli(&Cli::command().try_get_matches_from(args).unwrap());
+        assert_eq!(cli.resume_state.as_ref().unwrap().file_index, 1);
+
+        std::fs::write(&state, "file-index=2\npackets=5\n").unwrap();
+        let cli = parse_cli(&Cli::command().try_get_matches_from(args).unwrap());
+        std::fs::remove_file(&state).unwrap();
+        assert_eq!(cli.resume_state.as_ref().unwrap().packets, 5);
+        let path = cli.capture_file.as_ref().unwrap();
+        assert_eq!(
+            cli.run_path(path, None),
+            PathBuf::from("capture-ch13-3.pcap")
+        );
+    }
+
+    #[test]
+    fn default_capture_file() {
+        let matches = Cli::command().try_get_matches_from(["ccsniffer", "-c", "20"]);
+        let cli = parse_cli(&matches.unwrap());
+        assert_eq!(cli.capture_file, Some(PathBuf::from("capture-ch20.pcap")));
+
+        let matches = Cli::command().try_get_matches_from(["ccsniffer", "-f", "capture.pcap"]);
+        let cli = parse_cli(&matches.unwrap());
+        assert_eq!(cli.capture_file, Some(PathBuf::from("capture.pcap")));
+    }
+
+    #[test]
+    fn frame_rules() {
+        let args = ["ccsniffer", "--stop-on", "seq==42", "--stop-after", "1"];
+        let cli = parse_cli(&Cli::command().try_get_matches_from(args).unwrap());
+        let rules = FrameRules::new(&cli);
+        // Imm-Ack for sequence number 42
+        let packet = CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 11,
+            rssi: -50,
+            lqi: 100,
+            payload: vec![0x02, 0x00, 0x2a, 0xe0, 0x3b],
+        };
+        for reached in [false, true] {
+            let condition = rules.stop_condition.as_ref().unwrap();
+            condition.lock().unwrap().record(&packet);
+            assert_eq!(rules.stop_reached(), reached);
+        }
+
+        let cli = parse_cli(&Cli::command().try_get_matches_from(["ccsniffer"]).unwrap());
+        assert!(!FrameRules::new(&cli).stop_reached());
+    }
+
+    #[test]
+    fn default_fcs_type() {
+        let matches = Cli::command().try_get_matches_from(["ccsniffer", "-c", "20"]);
+        let cli = parse_cli(&matches.unwrap());
+        assert_eq!(cli.writer_options().fcs.tap_fcs_type(), TAP_FCS_NONE);
+
+        let matches = Cli::command().try_get_matches_from(["ccsniffer", "--fcs", "keep"]);
+        let cli = parse_cli(&matches.unwrap());
+        assert_eq!(cli.writer_options().fcs.tap_fcs_type(), TAP_FCS_CRC16);
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn command_line_overrides_config() {
+        let config = "channel = 15\nfcs = \"validate\"\nsnaplen = 128\n"
+            .parse()
+            .unwrap();
+        let args = ["ccsniffer", "from-file", "in.hex", "--channel", "20"];
+        let matches = Cli::command().try_get_matches_from(args).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        cli.apply_config(config, &matches);
+
+        assert_eq!(cli.channel, 20);
+        assert_eq!(cli.fcs, FcsMode::Validate);
+        assert_eq!(cli.snaplen, 128);
+    }
 }
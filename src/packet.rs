@@ -0,0 +1,185 @@
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::time::{Duration, SystemTime};
+
+// The CC2531 reports a correlation value between about 110 for the best and about 50 for
+// the worst frames that can still be received, it is scaled linearly onto 0-255:
+// lqi = (correlation - 50) * 255 / (110 - 50), clamped
+const CORRELATION_MIN: u16 = 50;
+const CORRELATION_MAX: u16 = 110;
+// The upper bit of the byte is the CRC OK flag on the radio, the correlation is bits 0-6
+const CORRELATION_MASK: u8 = 0x7f;
+
+/// Which value goes in the LQI fields of the capture file
+///
+/// - raw: the correlation byte as reported by the sniffer.
+/// - estimated: an LQI in 0-255 estimated from the correlation value.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum LqiMode {
+    Raw,
+    Estimated,
+}
+
+impl LqiMode {
+    pub fn lqi(&self, packet: &CapturedPacket) -> u8 {
+        match self {
+            LqiMode::Raw => packet.lqi,
+            LqiMode::Estimated => packet.estimated_lqi(),
+        }
+    }
+}
+
+/// A frame as received from the sniffer, with the metadata the firmware prepends split off
+#[derive(Clone)]
+pub struct CapturedPacket {
+    /// Time of reception since the UNIX epoch
+    pub timestamp: Duration,
+    pub channel: u8,
+    /// Received signal strength in dBm
+    pub rssi: i8,
+    /// The link quality byte from the sniffer, on the CC2531 this is the correlation value
+    pub lqi: u8,
+    /// The MPDU
+    pub payload: Vec<u8>,
+}
+
+impl CapturedPacket {
+    /// Builds a packet from the data returned by `SnifferDevice::receive_packet`,
+    /// the first two bytes are RSSI (dbm) and link quality index
+    pub fn from_sniffer_data(mut data: Vec<u8>, channel: u8, timestamp: Duration) -> Option<Self> {
+        if data.len() < 2 {
+            return None;
+        }
+
+        let metadata: Vec<u8> = data.drain(..2).collect();
+        Some(CapturedPacket {
+            timestamp,
+            channel,
+            rssi: i8::from_le_bytes([metadata[0]]),
+            lqi: metadata[1],
+            payload: data,
+        })
+    }
+
+    /// The correlation value scaled to the 0-255 LQI range
+    pub fn estimated_lqi(&self) -> u8 {
+        let correlation = (self.lqi & CORRELATION_MASK) as u16;
+        let scaled =
+            correlation.saturating_sub(CORRELATION_MIN) * 255 / (CORRELATION_MAX - CORRELATION_MIN);
+        scaled.min(255) as u8
+    }
+}
+
+/// A `CapturedPacket` that borrows its payload, to process frames without an allocation
+/// per frame
+///
+/// Built by `SnifferDevice::receive_packet_into`, the payload then points into the buffer
+/// passed to it. The packet can't outlive that buffer and the next read into the buffer
+/// needs it back, so a packet that has to be kept is copied out with `to_owned`.
+pub struct CapturedPacketRef<'a> {
+    pub timestamp: Duration,
+    pub channel: u8,
+    pub rssi: i8,
+    pub lqi: u8,
+    pub payload: Cow<'a, [u8]>,
+}
+
+impl<'a> CapturedPacketRef<'a> {
+    /// Like `CapturedPacket::from_sniffer_data`, without copying the payload
+    pub fn from_sniffer_data(data: &'a [u8], channel: u8, timestamp: Duration) -> Option<Self> {
+        match data {
+            [rssi, lqi, payload @ ..] => Some(CapturedPacketRef {
+                timestamp,
+                channel,
+                rssi: *rssi as i8,
+                lqi: *lqi,
+                payload: Cow::from(payload),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Copies the payload into a packet that doesn't borrow the read buffer
+    pub fn to_owned(&self) -> CapturedPacket {
+        CapturedPacket {
+            timestamp: self.timestamp,
+            channel: self.channel,
+            rssi: self.rssi,
+            lqi: self.lqi,
+            payload: self.payload.to_vec(),
+        }
+    }
+}
+
+/// Time since the UNIX epoch, as used for packet timestamps
+pub fn now() -> Duration {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(dt) => dt,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::{CapturedPacket, CapturedPacketRef, LqiMode};
+    use std::borrow::Cow;
+    use std::time::Duration;
+
+    #[test]
+    fn borrowed_sniffer_data() {
+        let data = [0xd3, 120, 0x02, 0x00, 0x2a];
+        let packet = CapturedPacketRef::from_sniffer_data(&data, 13, Duration::ZERO).unwrap();
+        assert!(matches!(packet.payload, Cow::Borrowed(_)));
+        assert_eq!(packet.payload.as_ptr(), data[2..].as_ptr());
+
+        let owned = packet.to_owned();
+        assert_eq!(owned.rssi, -45);
+        assert_eq!(owned.lqi, 120);
+        assert_eq!(owned.channel, 13);
+        assert_eq!(owned.payload, [0x02, 0x00, 0x2a]);
+
+        assert!(CapturedPacketRef::from_sniffer_data(&[0xd3], 13, Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn from_sniffer_data() {
+        let packet = CapturedPacket::from_sniffer_data(
+            vec![0xd3, 120, 0x02, 0x00, 0x2a],
+            13,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(packet.rssi, -45);
+        assert_eq!(packet.lqi, 120);
+        assert_eq!(packet.channel, 13);
+        assert_eq!(packet.payload, [0x02, 0x00, 0x2a]);
+
+        assert!(CapturedPacket::from_sniffer_data(vec![0xd3], 13, Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn estimated_lqi() {
+        let packet = |lqi| CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 13,
+            rssi: -45,
+            lqi,
+            payload: vec![],
+        };
+        assert_eq!(packet(40).estimated_lqi(), 0);
+        assert_eq!(packet(50).estimated_lqi(), 0);
+        assert_eq!(packet(80).estimated_lqi(), 127);
+        assert_eq!(packet(110).estimated_lqi(), 255);
+        assert_eq!(packet(120).estimated_lqi(), 255);
+        // CRC OK flag set
+        assert_eq!(packet(0x80 | 80).estimated_lqi(), 127);
+
+        assert_eq!(LqiMode::Raw.lqi(&packet(80)), 80);
+        assert_eq!(LqiMode::Estimated.lqi(&packet(80)), 127);
+    }
+}
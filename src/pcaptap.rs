@@ -1,86 +1,773 @@
+use crate::stats::json_string;
 use byteorder_slice::byteorder::WriteBytesExt;
 use byteorder_slice::LittleEndian;
+use clap::ValueEnum;
 use std::io;
 use std::io::Write;
+use std::ops::RangeInclusive;
 
+#[derive(Debug, PartialEq)]
 pub enum TapBlock {
+    /// Length of the whole header in bytes, the TLVs that follow included, see `header_len`
     Header(usize),
+    FcsType(u8),
     TlvRssi(f32),
     TlvLqi(u8),
-    ChannelAssignment(u16)
+    /// Bit rate of the PHY in bits per second
+    BitRate(u32),
+    ChannelAssignment(u16),
+    /// Band, PHY type and mode of a SUN PHY, as numbered by the TAP spec
+    SunPhy {
+        band: u8,
+        phy_type: u8,
+        mode: u8,
+    },
+    /// Start of frame timestamp, the spec fixes the unit to nanoseconds since the epoch
+    SofTimestamp(u64),
+    /// End of frame timestamp, in nanoseconds like the start of frame
+    EofTimestamp(u64),
+    /// TSCH absolute slot number
+    Asn(u64),
+    /// Start of the TSCH timeslot the frame was sent in, in nanoseconds since the epoch
+    SlotStart(u64),
+    /// Length of a TSCH timeslot in microseconds
+    TimeslotLength(u32),
 }
 
+/// TLV types from the IEEE 802.15.4 TAP spec that can be written
 #[repr(u16)]
-enum Tlv {
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tlv {
+    FcsType = 0,
     RSSI = 1,
+    BitRate = 2,
     ChannelAssignment = 3,
+    SunPhy = 4,
+    SofTimestamp = 5,
+    EofTimestamp = 6,
+    ASN = 7,
+    SlotStart = 8,
+    TimeslotLength = 9,
     LQI = 10,
 }
 
+pub const SUPPORTED_TLVS: [Tlv; 11] = [
+    Tlv::FcsType,
+    Tlv::RSSI,
+    Tlv::BitRate,
+    Tlv::ChannelAssignment,
+    Tlv::SunPhy,
+    Tlv::SofTimestamp,
+    Tlv::EofTimestamp,
+    Tlv::ASN,
+    Tlv::SlotStart,
+    Tlv::TimeslotLength,
+    Tlv::LQI,
+];
+
+/// A field of a TLV value, the fields are written in order and little endian
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TlvField {
+    pub name: &'static str,
+    /// u8, u16, u32, u64 or f32
+    pub kind: &'static str,
+    pub unit: Option<&'static str>,
+}
+
+impl TlvField {
+    /// Length of the field in bytes
+    pub const fn size(&self) -> u16 {
+        match self.kind.as_bytes() {
+            b"u8" => 1,
+            b"u16" => 2,
+            b"u32" | b"f32" => 4,
+            _ => 8,
+        }
+    }
+}
+
+/// How a TLV is written, for generating documentation of the wire format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TlvSchema {
+    pub tlv: Tlv,
+    /// Name in the TAP spec
+    pub name: &'static str,
+    /// Value of the length field, without the padding to 4 bytes
+    pub len: u16,
+    pub fields: &'static [TlvField],
+}
+
+const fn field(name: &'static str, kind: &'static str, unit: Option<&'static str>) -> TlvField {
+    TlvField { name, kind, unit }
+}
+
+const fn schema(tlv: Tlv, name: &'static str, fields: &'static [TlvField]) -> TlvSchema {
+    let mut len = 0;
+    let mut i = 0;
+    while i < fields.len() {
+        len += fields[i].size();
+        i += 1;
+    }
+    TlvSchema {
+        tlv,
+        name,
+        len,
+        fields,
+    }
+}
+
+/// The layout of every TLV in `SUPPORTED_TLVS`, in the same order. `TapBlock::write_to`
+/// writes the length fields from it.
+pub const TLV_SCHEMA: [TlvSchema; 11] = [
+    schema(Tlv::FcsType, "FCS Type", &[field("fcs_type", "u8", None)]),
+    schema(
+        Tlv::RSSI,
+        "Receive Signal Strength",
+        &[field("rss", "f32", Some("dBm"))],
+    ),
+    schema(
+        Tlv::BitRate,
+        "Bit Rate",
+        &[field("bit_rate", "u32", Some("bit/s"))],
+    ),
+    schema(
+        Tlv::ChannelAssignment,
+        "Channel Assignment",
+        &[field("channel", "u16", None), field("page", "u8", None)],
+    ),
+    schema(
+        Tlv::SunPhy,
+        "SUN PHY Information",
+        &[
+            field("band", "u8", None),
+            field("phy_type", "u8", None),
+            field("mode", "u8", None),
+        ],
+    ),
+    schema(Tlv::SofTimestamp, "Start of Frame Timestamp", &[TIMESTAMP]),
+    schema(Tlv::EofTimestamp, "End of Frame Timestamp", &[TIMESTAMP]),
+    schema(
+        Tlv::ASN,
+        "Absolute Slot Number",
+        &[field("asn", "u64", None)],
+    ),
+    schema(Tlv::SlotStart, "Start of Slot Timestamp", &[TIMESTAMP]),
+    schema(
+        Tlv::TimeslotLength,
+        "Timeslot Length",
+        &[field("length", "u32", Some("us"))],
+    ),
+    schema(
+        Tlv::LQI,
+        "Link Quality Indicator",
+        &[field("lqi", "u8", None)],
+    ),
+];
+
+const TIMESTAMP: TlvField = field("timestamp", "u64", Some("ns"));
+
+impl Tlv {
+    pub fn schema(&self) -> &'static TlvSchema {
+        let found = TLV_SCHEMA.iter().find(|s| s.tlv == *self);
+        found.expect("every TLV has a schema")
+    }
+}
+
+/// Writes `TLV_SCHEMA` as a JSON array, one object per TLV
+pub fn write_schema_json<W: Write>(w: &mut W) -> io::Result<()> {
+    let mut tlvs = vec![];
+    for schema in &TLV_SCHEMA {
+        let fields: Vec<String> = schema
+            .fields
+            .iter()
+            .map(|f| {
+                format!(
+                    "{{\"name\":{},\"type\":{},\"unit\":{}}}",
+                    json_string(f.name),
+                    json_string(f.kind),
+                    f.unit.map_or("null".to_string(), json_string)
+                )
+            })
+            .collect();
+        tlvs.push(format!(
+            "{{\"type\":{},\"name\":{},\"length\":{},\"fields\":[{}]}}",
+            schema.tlv as u16,
+            json_string(schema.name),
+            schema.len,
+            fields.join(",")
+        ));
+    }
+    writeln!(w, "[{}]", tlvs.join(","))
+}
+
+// Channel page of the 2.4 GHz O-QPSK PHY, the only one of the CC2531. Channels 11-26 are
+// numbered the same on this page in every revision of 802.15.4. The BPSK PHYs of `Phy`
+// are on page 0 as well, with channels 0-10.
+const CHANNEL_PAGE_OQPSK_2450: u8 = 0;
+
+/// The PHY the frames were received with, for the bit rate TLV. The CC2531 only has the
+/// 2.4 GHz O-QPSK PHY, the others are for converting frames captured by other radios.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+pub enum Phy {
+    // 2.4 GHz O-QPSK, 250 kb/s
+    #[default]
+    #[value(name = "oqpsk-2450")]
+    #[cfg_attr(feature = "config", serde(rename = "oqpsk-2450"))]
+    Oqpsk2450,
+    // 868 MHz BPSK, 20 kb/s
+    #[value(name = "bpsk-868")]
+    #[cfg_attr(feature = "config", serde(rename = "bpsk-868"))]
+    Bpsk868,
+    // 915 MHz BPSK, 40 kb/s
+    #[value(name = "bpsk-915")]
+    #[cfg_attr(feature = "config", serde(rename = "bpsk-915"))]
+    Bpsk915,
+}
+
+impl Phy {
+    /// Bit rate in bits per second
+    pub fn bit_rate(&self) -> u32 {
+        match self {
+            Phy::Oqpsk2450 => 250_000,
+            Phy::Bpsk868 => 20_000,
+            Phy::Bpsk915 => 40_000,
+        }
+    }
+
+    /// The channels of the PHY on channel page 0
+    pub fn channels(&self) -> RangeInclusive<u8> {
+        match self {
+            Phy::Oqpsk2450 => 11..=26,
+            Phy::Bpsk868 => 0..=0,
+            Phy::Bpsk915 => 1..=10,
+        }
+    }
+}
+
+/// Length of a TAP header carrying the given TLVs
+pub fn header_len<'a>(blocks: impl IntoIterator<Item = &'a TapBlock>) -> usize {
+    4 + blocks.into_iter().map(TapBlock::padded_len).sum::<usize>()
+}
+
 impl TapBlock {
+    /// TLV type number of this block, None for the header
+    pub fn tlv_type(&self) -> Option<u16> {
+        self.tlv().map(|tlv| tlv as u16)
+    }
+
+    /// Number of bytes `write_to` writes, the padding of a TLV included
+    pub fn padded_len(&self) -> usize {
+        match self.tlv() {
+            Some(tlv) => {
+                let len = tlv.schema().len as usize;
+                4 + len + (4 - len % 4) % 4
+            }
+            None => 4,
+        }
+    }
+
+    fn tlv(&self) -> Option<Tlv> {
+        let tlv = match self {
+            TapBlock::Header(_) => return None,
+            TapBlock::FcsType(_) => Tlv::FcsType,
+            TapBlock::TlvRssi(_) => Tlv::RSSI,
+            TapBlock::TlvLqi(_) => Tlv::LQI,
+            TapBlock::BitRate(_) => Tlv::BitRate,
+            TapBlock::ChannelAssignment(_) => Tlv::ChannelAssignment,
+            TapBlock::SunPhy { .. } => Tlv::SunPhy,
+            TapBlock::SofTimestamp(_) => Tlv::SofTimestamp,
+            TapBlock::EofTimestamp(_) => Tlv::EofTimestamp,
+            TapBlock::Asn(_) => Tlv::ASN,
+            TapBlock::SlotStart(_) => Tlv::SlotStart,
+            TapBlock::TimeslotLength(_) => Tlv::TimeslotLength,
+        };
+        Some(tlv)
+    }
+
     pub fn write_to<W: Write>(self, w: &mut W) -> io::Result<usize> {
         match self {
+            TapBlock::FcsType(v) => {
+                write_tlv_header(w, Tlv::FcsType)?;
+                w.write_u8(v)?;
+                Ok(4 + 1 + write_padding(w, 1)?)
+            }
             TapBlock::TlvRssi(v) => {
-                w.write_u16::<LittleEndian>(Tlv::RSSI as u16)?;
-                w.write_u16::<LittleEndian>(4)?;
+                write_tlv_header(w, Tlv::RSSI)?;
                 w.write_f32::<LittleEndian>(v)?;
-                Ok(8)
+                Ok(4 + 4 + write_padding(w, 4)?)
             }
             TapBlock::TlvLqi(v) => {
-                w.write_u16::<LittleEndian>(Tlv::LQI as u16)?;
-                w.write_u16::<LittleEndian>(1)?;
+                write_tlv_header(w, Tlv::LQI)?;
                 w.write_u8(v)?;
-                let padding = [0 as u8, 0, 0];
-                w.write(&padding)?; // padding
-                Ok(8)
+                Ok(4 + 1 + write_padding(w, 1)?)
             }
-            TapBlock::Header(blocks) => {
+            TapBlock::Header(len) => {
                 w.write_u8(0)?; // version
                 w.write_u8(0)?;
-                w.write_u16::<LittleEndian>(4 + 8 * (blocks as u16))?;
+                w.write_u16::<LittleEndian>(len as u16)?;
                 Ok(4)
             }
             TapBlock::ChannelAssignment(channel) => {
-                w.write_u16::<LittleEndian>(Tlv::ChannelAssignment as u16)?;
-                w.write_u16::<LittleEndian>(3)?;
+                write_tlv_header(w, Tlv::ChannelAssignment)?;
                 w.write_u16::<LittleEndian>(channel)?;
-                w.write_u8(0)?; // Channel page?
-                w.write_u8(0)?; // padding
-                Ok(8)
+                w.write_u8(CHANNEL_PAGE_OQPSK_2450)?;
+                Ok(4 + 3 + write_padding(w, 3)?)
+            }
+            TapBlock::BitRate(bps) => {
+                write_tlv_header(w, Tlv::BitRate)?;
+                w.write_u32::<LittleEndian>(bps)?;
+                Ok(4 + 4)
+            }
+            TapBlock::SunPhy {
+                band,
+                phy_type,
+                mode,
+            } => {
+                write_tlv_header(w, Tlv::SunPhy)?;
+                w.write_all(&[band, phy_type, mode])?;
+                Ok(4 + 3 + write_padding(w, 3)?)
+            }
+            TapBlock::SofTimestamp(ns) => {
+                write_tlv_header(w, Tlv::SofTimestamp)?;
+                w.write_u64::<LittleEndian>(ns)?;
+                Ok(4 + 8)
+            }
+            TapBlock::EofTimestamp(ns) => {
+                write_tlv_header(w, Tlv::EofTimestamp)?;
+                w.write_u64::<LittleEndian>(ns)?;
+                Ok(4 + 8)
+            }
+            TapBlock::Asn(asn) => {
+                write_tlv_header(w, Tlv::ASN)?;
+                w.write_u64::<LittleEndian>(asn)?;
+                Ok(4 + 8)
+            }
+            TapBlock::SlotStart(ns) => {
+                write_tlv_header(w, Tlv::SlotStart)?;
+                w.write_u64::<LittleEndian>(ns)?;
+                Ok(4 + 8)
+            }
+            TapBlock::TimeslotLength(us) => {
+                write_tlv_header(w, Tlv::TimeslotLength)?;
+                w.write_u32::<LittleEndian>(us)?;
+                Ok(4 + 4)
             }
         }
     }
 }
 
+/// Splits a packet with a TAP header into the TLVs and the MPDU. TLVs of types that are
+/// not in `SUPPORTED_TLVS` are skipped.
+pub fn read_header(data: &[u8]) -> io::Result<(Vec<TapBlock>, &[u8])> {
+    let invalid = |detail: &str| io::Error::new(io::ErrorKind::InvalidData, detail.to_string());
+
+    if data.len() < 4 {
+        return Err(invalid("truncated TAP header"));
+    }
+    if data[0] != 0 {
+        return Err(invalid("unsupported TAP version"));
+    }
+    let header_len = u16::from_le_bytes([data[2], data[3]]) as usize;
+    if header_len < 4 || header_len > data.len() {
+        return Err(invalid("TAP header length out of range"));
+    }
+
+    let mut blocks = vec![];
+    let mut tlvs = &data[4..header_len];
+    while !tlvs.is_empty() {
+        if tlvs.len() < 4 {
+            return Err(invalid("truncated TLV"));
+        }
+        let tlv_type = u16::from_le_bytes([tlvs[0], tlvs[1]]);
+        let len = u16::from_le_bytes([tlvs[2], tlvs[3]]) as usize;
+        let padded = 4 + len + (4 - len % 4) % 4;
+        let value = match tlvs.get(4..4 + len) {
+            Some(v) => v,
+            None => return Err(invalid("truncated TLV")),
+        };
+
+        let tlv = SUPPORTED_TLVS.iter().find(|t| **t as u16 == tlv_type);
+        let block = match (tlv, value) {
+            (Some(Tlv::FcsType), [v]) => Some(TapBlock::FcsType(*v)),
+            (Some(Tlv::RSSI), [a, b, c, d]) => {
+                Some(TapBlock::TlvRssi(f32::from_le_bytes([*a, *b, *c, *d])))
+            }
+            (Some(Tlv::LQI), [v]) => Some(TapBlock::TlvLqi(*v)),
+            (Some(Tlv::BitRate), v) => {
+                let bps = v.try_into().ok().map(u32::from_le_bytes);
+                bps.map(TapBlock::BitRate)
+            }
+            (Some(Tlv::ChannelAssignment), [lo, hi, _page]) => {
+                Some(TapBlock::ChannelAssignment(u16::from_le_bytes([*lo, *hi])))
+            }
+            (Some(Tlv::SunPhy), [band, phy_type, mode]) => Some(TapBlock::SunPhy {
+                band: *band,
+                phy_type: *phy_type,
+                mode: *mode,
+            }),
+            (Some(Tlv::SofTimestamp), v) => {
+                let ns = v.try_into().ok().map(u64::from_le_bytes);
+                ns.map(TapBlock::SofTimestamp)
+            }
+            (Some(Tlv::EofTimestamp), v) => {
+                let ns = v.try_into().ok().map(u64::from_le_bytes);
+                ns.map(TapBlock::EofTimestamp)
+            }
+            (Some(Tlv::ASN), v) => v.try_into().ok().map(u64::from_le_bytes).map(TapBlock::Asn),
+            (Some(Tlv::SlotStart), v) => {
+                let ns = v.try_into().ok().map(u64::from_le_bytes);
+                ns.map(TapBlock::SlotStart)
+            }
+            (Some(Tlv::TimeslotLength), v) => {
+                let us = v.try_into().ok().map(u32::from_le_bytes);
+                us.map(TapBlock::TimeslotLength)
+            }
+            _ => None,
+        };
+        blocks.extend(block);
+        tlvs = tlvs.get(padded..).unwrap_or_default();
+    }
+    Ok((blocks, &data[header_len..]))
+}
+
+fn write_tlv_header<W: Write>(w: &mut W, tlv: Tlv) -> io::Result<()> {
+    w.write_u16::<LittleEndian>(tlv as u16)?;
+    w.write_u16::<LittleEndian>(tlv.schema().len)
+}
+
+// The TAP spec requires every TLV to end on a 4 byte boundary, the length field
+// excludes this padding
+fn write_padding<W: Write>(w: &mut W, value_len: usize) -> io::Result<usize> {
+    let padding = (4 - value_len % 4) % 4;
+    w.write_all(&[0u8; 3][..padding])?;
+    Ok(padding)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::pcaptap::TapBlock;
+    use crate::pcaptap::{
+        header_len, read_header, write_schema_json, Phy, TapBlock, SUPPORTED_TLVS, TLV_SCHEMA,
+    };
 
     #[test]
     fn serialize_header() {
-        let mut v = vec![1 as u8; 0];
-        TapBlock::Header(2).write_to(&mut v).expect("Failed");
+        let mut v = vec![1_u8; 0];
+        TapBlock::Header(20).write_to(&mut v).expect("Failed");
         assert_eq!(v, [0, 0, 20, 0])
     }
 
+    #[test]
+    fn serialize_fcs_type() {
+        let mut v = vec![1_u8; 0];
+        TapBlock::FcsType(1).write_to(&mut v).expect("Failed");
+        assert_eq!(v, [0, 0, 1, 0, 1, 0, 0, 0])
+    }
+
     #[test]
     fn serialize_rssi() {
-        let mut v = vec![1 as u8; 0];
+        let mut v = vec![1_u8; 0];
         TapBlock::TlvRssi(5.0).write_to(&mut v).expect("Failed");
         assert_eq!(v, [1, 0, 4, 0, 0, 0, 160, 64])
     }
 
     #[test]
     fn serialize_lqi() {
-        let mut v = vec![1 as u8; 0];
+        let mut v = vec![1_u8; 0];
         TapBlock::TlvLqi(5).write_to(&mut v).expect("Failed");
         assert_eq!(v, [10, 0, 1, 0, 5, 0, 0, 0])
     }
 
     #[test]
     fn serialize_ca() {
-        let mut v = vec![1 as u8; 0];
-        TapBlock::ChannelAssignment(11).write_to(&mut v).expect("Failed");
-        assert_eq!(v, [3, 0, 3, 0, 11, 0, 0, 0])
+        let mut v = vec![1_u8; 0];
+        TapBlock::ChannelAssignment(11)
+            .write_to(&mut v)
+            .expect("Failed");
+        assert_eq!(v, [3, 0, 3, 0, 11, 0, 0, 0]);
+
+        // The channel as u16, then the page: 0 for 2.4 GHz, then a padding byte
+        let mut v = vec![];
+        TapBlock::ChannelAssignment(26).write_to(&mut v).unwrap();
+        assert_eq!(v[4..], [26, 0, 0, 0]);
+    }
+
+    #[test]
+    fn serialize_bit_rate() {
+        let mut v = vec![];
+        let written = TapBlock::BitRate(250_000).write_to(&mut v).unwrap();
+        assert_eq!(written, 8);
+        assert_eq!(v, [2, 0, 4, 0, 0x90, 0xd0, 0x03, 0]);
+    }
+
+    #[test]
+    fn serialize_sun_phy() {
+        let mut v = vec![];
+        let sun_phy = TapBlock::SunPhy {
+            band: 3,
+            phy_type: 0,
+            mode: 1,
+        };
+        let written = sun_phy.write_to(&mut v).unwrap();
+        assert_eq!(written, 8);
+        assert_eq!(v, [4, 0, 3, 0, 3, 0, 1, 0]);
+    }
+
+    #[test]
+    fn phys() {
+        assert_eq!(Phy::default(), Phy::Oqpsk2450);
+        assert_eq!(Phy::default().bit_rate(), 250_000);
+        assert_eq!(Phy::default().channels(), 11..=26);
+
+        assert_eq!(Phy::Bpsk915.bit_rate(), 40_000);
+        assert!(Phy::Bpsk915.channels().contains(&5));
+        assert!(!Phy::Bpsk915.channels().contains(&11));
+    }
+
+    #[test]
+    fn serialize_timestamps() {
+        let mut v = vec![1_u8; 0];
+        TapBlock::SofTimestamp(1_000_000_000)
+            .write_to(&mut v)
+            .expect("Failed");
+        assert_eq!(v, [5, 0, 8, 0, 0, 0xca, 0x9a, 0x3b, 0, 0, 0, 0]);
+
+        let mut v = vec![1_u8; 0];
+        TapBlock::EofTimestamp(1).write_to(&mut v).expect("Failed");
+        assert_eq!(v, [6, 0, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn serialize_asn() {
+        let mut v = vec![];
+        let written = TapBlock::Asn(0x0102030405).write_to(&mut v).unwrap();
+        assert_eq!(written, 12);
+        assert_eq!(v, [7, 0, 8, 0, 5, 4, 3, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn serialize_slot_start() {
+        let mut v = vec![];
+        let written = TapBlock::SlotStart(1_000_000_000).write_to(&mut v).unwrap();
+        assert_eq!(written, 12);
+        assert_eq!(v, [8, 0, 8, 0, 0, 0xca, 0x9a, 0x3b, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn serialize_timeslot_length() {
+        let mut v = vec![];
+        let written = TapBlock::TimeslotLength(10_000).write_to(&mut v).unwrap();
+        assert_eq!(written, 8);
+        assert_eq!(v, [9, 0, 4, 0, 0x10, 0x27, 0, 0]);
+    }
+
+    #[test]
+    fn read_tsch_tlvs() {
+        let mut v = vec![];
+        let tlvs = [
+            TapBlock::Asn(42),
+            TapBlock::SlotStart(1_000_000_000),
+            TapBlock::TimeslotLength(10_000),
+        ];
+        let len = header_len(&tlvs);
+        TapBlock::Header(len).write_to(&mut v).unwrap();
+        for tlv in tlvs {
+            tlv.write_to(&mut v).unwrap();
+        }
+        v.extend_from_slice(&[0x02, 0x00, 0x2a]);
+
+        let (blocks, payload) = read_header(&v).unwrap();
+        assert_eq!(
+            blocks,
+            [
+                TapBlock::Asn(42),
+                TapBlock::SlotStart(1_000_000_000),
+                TapBlock::TimeslotLength(10_000)
+            ]
+        );
+        assert_eq!(payload, [0x02, 0x00, 0x2a]);
+    }
+
+    #[test]
+    fn mixed_tlv_lengths() {
+        // The timestamps and the ASN take 12 bytes, the others 8
+        let tlvs = [
+            TapBlock::FcsType(1),
+            TapBlock::SofTimestamp(1_000_000_000),
+            TapBlock::Asn(42),
+            TapBlock::TlvLqi(120),
+            TapBlock::SlotStart(1_000_000_000),
+            TapBlock::EofTimestamp(1_000_100_000),
+            TapBlock::TimeslotLength(10_000),
+        ];
+        assert_eq!(header_len(&tlvs), 4 + 8 + 12 + 12 + 8 + 12 + 12 + 8);
+
+        let mut v = vec![];
+        let len = header_len(&tlvs);
+        TapBlock::Header(len).write_to(&mut v).unwrap();
+        for tlv in tlvs {
+            tlv.write_to(&mut v).unwrap();
+        }
+        assert_eq!(v[..4], [0, 0, 76, 0]);
+        assert_eq!(v.len(), 76);
+        v.extend_from_slice(&[0x02, 0x00, 0x2a]);
+
+        let (blocks, payload) = read_header(&v).unwrap();
+        assert_eq!(blocks.len(), 7);
+        assert_eq!(blocks[6], TapBlock::TimeslotLength(10_000));
+        assert_eq!(payload, [0x02, 0x00, 0x2a]);
+    }
+
+    #[test]
+    fn tlv_types() {
+        assert_eq!(TapBlock::Header(4).tlv_type(), None);
+        assert_eq!(TapBlock::FcsType(1).tlv_type(), Some(0));
+        assert_eq!(TapBlock::TlvRssi(-45.0).tlv_type(), Some(1));
+        assert_eq!(TapBlock::BitRate(250_000).tlv_type(), Some(2));
+        assert_eq!(TapBlock::ChannelAssignment(11).tlv_type(), Some(3));
+        let sun_phy = TapBlock::SunPhy {
+            band: 0,
+            phy_type: 0,
+            mode: 0,
+        };
+        assert_eq!(sun_phy.tlv_type(), Some(4));
+        assert_eq!(TapBlock::SofTimestamp(0).tlv_type(), Some(5));
+        assert_eq!(TapBlock::EofTimestamp(0).tlv_type(), Some(6));
+        assert_eq!(TapBlock::Asn(0).tlv_type(), Some(7));
+        assert_eq!(TapBlock::SlotStart(0).tlv_type(), Some(8));
+        assert_eq!(TapBlock::TimeslotLength(0).tlv_type(), Some(9));
+        assert_eq!(TapBlock::TlvLqi(120).tlv_type(), Some(10));
+
+        let supported: Vec<u16> = SUPPORTED_TLVS.iter().map(|t| *t as u16).collect();
+        assert_eq!(supported, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn schema_matches_serialization() {
+        let blocks = [
+            TapBlock::FcsType(1),
+            TapBlock::TlvRssi(-45.0),
+            TapBlock::BitRate(250_000),
+            TapBlock::ChannelAssignment(11),
+            TapBlock::SunPhy {
+                band: 0,
+                phy_type: 0,
+                mode: 0,
+            },
+            TapBlock::SofTimestamp(1),
+            TapBlock::EofTimestamp(1),
+            TapBlock::Asn(1),
+            TapBlock::SlotStart(1),
+            TapBlock::TimeslotLength(1),
+            TapBlock::TlvLqi(1),
+        ];
+        assert_eq!(TLV_SCHEMA.map(|s| s.tlv), SUPPORTED_TLVS);
+        assert_eq!(blocks.len(), TLV_SCHEMA.len());
+        for (block, schema) in blocks.into_iter().zip(&TLV_SCHEMA) {
+            assert_eq!(block.tlv_type(), Some(schema.tlv as u16));
+            let mut v = vec![];
+            let written = block.write_to(&mut v).unwrap();
+            assert_eq!(written, 4 + (schema.len as usize).next_multiple_of(4));
+            assert_eq!(u16::from_le_bytes([v[2], v[3]]), schema.len);
+        }
+    }
+
+    #[test]
+    fn schema_json() {
+        let mut out = vec![];
+        write_schema_json(&mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.starts_with(
+            "[{\"type\":0,\"name\":\"FCS Type\",\"length\":1,\
+             \"fields\":[{\"name\":\"fcs_type\",\"type\":\"u8\",\"unit\":null}]},"
+        ));
+        assert!(json.contains(
+            "{\"type\":3,\"name\":\"Channel Assignment\",\"length\":3,\"fields\":[\
+             {\"name\":\"channel\",\"type\":\"u16\",\"unit\":null},\
+             {\"name\":\"page\",\"type\":\"u8\",\"unit\":null}]}"
+        ));
+        assert!(json.ends_with("]}]\n"));
+    }
+
+    #[test]
+    fn tlvs_are_aligned() {
+        let tlvs = [
+            TapBlock::FcsType(1),
+            TapBlock::TlvRssi(-45.0),
+            TapBlock::ChannelAssignment(11),
+            TapBlock::TlvLqi(120),
+        ];
+        let len = header_len(&tlvs);
+
+        let mut v = vec![1_u8; 0];
+        let mut written = TapBlock::Header(len).write_to(&mut v).expect("Failed");
+        for tlv in tlvs {
+            let padded_len = tlv.padded_len();
+            let len = tlv.write_to(&mut v).expect("Failed");
+            assert_eq!(len % 4, 0);
+            assert_eq!(len, padded_len);
+            written += len;
+        }
+
+        assert_eq!(written, v.len());
+        assert_eq!(v.len() % 4, 0);
+        assert_eq!(v.len(), len);
+        assert_eq!(u16::from_le_bytes([v[2], v[3]]) as usize, v.len());
+    }
+
+    #[test]
+    fn read_written_header() {
+        let mut v = vec![];
+        TapBlock::Header(28).write_to(&mut v).unwrap();
+        TapBlock::FcsType(1).write_to(&mut v).unwrap();
+        TapBlock::ChannelAssignment(15).write_to(&mut v).unwrap();
+        TapBlock::TlvLqi(120).write_to(&mut v).unwrap();
+        v.extend_from_slice(&[0x02, 0x00, 0x2a]);
+
+        let (blocks, payload) = read_header(&v).unwrap();
+        assert_eq!(
+            blocks,
+            [
+                TapBlock::FcsType(1),
+                TapBlock::ChannelAssignment(15),
+                TapBlock::TlvLqi(120)
+            ]
+        );
+        assert_eq!(payload, [0x02, 0x00, 0x2a]);
+
+        assert!(read_header(&v[..3]).is_err());
+        assert!(read_header(&v[..10]).is_err());
+    }
+
+    #[test]
+    fn read_phy_tlvs() {
+        let mut v = vec![];
+        let tlvs = [
+            TapBlock::BitRate(40_000),
+            TapBlock::SunPhy {
+                band: 3,
+                phy_type: 0,
+                mode: 1,
+            },
+        ];
+        let len = header_len(&tlvs);
+        TapBlock::Header(len).write_to(&mut v).unwrap();
+        for tlv in tlvs {
+            tlv.write_to(&mut v).unwrap();
+        }
+        v.extend_from_slice(&[0x02, 0x00, 0x2a]);
+
+        let (blocks, payload) = read_header(&v).unwrap();
+        assert_eq!(blocks[0], TapBlock::BitRate(40_000));
+        assert_eq!(
+            blocks[1],
+            TapBlock::SunPhy {
+                band: 3,
+                phy_type: 0,
+                mode: 1
+            }
+        );
+        assert_eq!(payload, [0x02, 0x00, 0x2a]);
     }
 }
@@ -2,21 +2,33 @@ use std::borrow::Cow;
 use std::error;
 use std::fmt::{Display, Formatter};
 
+/// A frame on the USB link with the sniffer firmware, the on-disk TAP framing is in
+/// `pcaptap`
+///
+/// Commands are sent as `[length, code, body.., checksum]`. Frames from the sniffer carry
+/// the length twice: `[usb length, length, code, body.., checksum]`.
 pub struct Message<'a> {
     pub code: u8,
+    /// Length of the whole frame as given in the length field
     pub length: u8,
     pub body: Cow<'a, [u8]>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ProtocolError {
-    Other
+    Empty,
+    Short,
+    SizeMismatch(u8, u8),
 }
 
 impl Display for ProtocolError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match &*self {
-            _ => write!(f, "protocol error")
+        match self {
+            ProtocolError::Empty => write!(f, "empty read"),
+            ProtocolError::Short => write!(f, "short frame"),
+            ProtocolError::SizeMismatch(usb, protocol) => {
+                write!(f, "length fields differ ({} and {})", usb, protocol)
+            }
         }
     }
 }
@@ -24,15 +36,100 @@ impl Display for ProtocolError {
 impl error::Error for ProtocolError {}
 
 impl<'a> Message<'a> {
-    fn from_slice(mut slice: &[u8]) -> Result<Self, ProtocolError> {
-        if slice.len() < 5 {
-            return Err(ProtocolError::Other)
+    pub fn new(code: u8, body: &'a [u8]) -> Self {
+        Message {
+            code,
+            length: (body.len() + 3) as u8,
+            body: Cow::from(body),
+        }
+    }
+
+    /// Parses a frame read from the sniffer. When the two length fields differ the frame
+    /// is rejected with `strict`, otherwise it is cut to the smaller of them.
+    pub fn from_slice(slice: &'a [u8], strict: bool) -> Result<Self, ProtocolError> {
+        if slice.is_empty() {
+            return Err(ProtocolError::Empty);
+        }
+        // Both lengths, the command code and the checksum
+        if slice.len() < 4 {
+            return Err(ProtocolError::Short);
+        }
+
+        let mut n = slice.len();
+        if slice[0] != slice[1] {
+            if strict {
+                return Err(ProtocolError::SizeMismatch(slice[0], slice[1]));
+            }
+            n = n.min(slice[0].min(slice[1]) as usize);
+            if n < 4 {
+                return Err(ProtocolError::Short);
+            }
         }
 
-        Ok(Message{
-            code: 0,
-            length: 0,
-            body: Default::default(),
+        Ok(Message {
+            code: slice[2],
+            length: slice[1],
+            body: Cow::from(&slice[3..n - 1]),
         })
     }
-}
\ No newline at end of file
+
+    /// The command frame, with the checksum started from `seed`
+    pub fn to_bytes(&self, seed: u8) -> Vec<u8> {
+        let mut bytes = vec![self.length, self.code];
+        bytes.extend_from_slice(&self.body);
+        bytes.push(calculate_crc(&bytes, bytes.len(), seed));
+        bytes
+    }
+}
+
+// Procedure copied from the firmware
+pub(crate) fn calculate_crc(buffer: &[u8], len: usize, seed: u8) -> u8 {
+    let mut checksum = seed;
+    for b in &buffer[0..len] {
+        checksum ^= b;
+    }
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{Message, ProtocolError};
+
+    #[test]
+    fn command_bytes() {
+        assert_eq!(Message::new(0x00, &[]).to_bytes(0xff), [0x03, 0x00, 0xfc]);
+        assert_eq!(
+            Message::new(0x02, &[15]).to_bytes(0xff),
+            [0x04, 0x02, 0x0f, 0xf6]
+        );
+    }
+
+    #[test]
+    fn parse_frame() {
+        let frame = [0x09, 0x09, 0x0a, 0xd3, 120, 0x02, 0x00, 0x2a, 0x00];
+        let message = Message::from_slice(&frame, true).unwrap();
+        assert_eq!(message.code, 0x0a);
+        assert_eq!(message.length, 9);
+        assert_eq!(message.body.as_ref(), [0xd3, 120, 0x02, 0x00, 0x2a]);
+
+        assert_eq!(
+            Message::from_slice(&[], false).err(),
+            Some(ProtocolError::Empty)
+        );
+        assert_eq!(
+            Message::from_slice(&frame[..3], false).err(),
+            Some(ProtocolError::Short)
+        );
+    }
+
+    #[test]
+    fn parse_size_mismatch() {
+        let frame = [0x09, 0x06, 0x0a, 0xd3, 120, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(
+            Message::from_slice(&frame, true).err(),
+            Some(ProtocolError::SizeMismatch(9, 6))
+        );
+        let message = Message::from_slice(&frame, false).unwrap();
+        assert_eq!(message.body.as_ref(), [0xd3, 120]);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::packet::CapturedPacket;
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_QUEUE_DEPTH: usize = 1024;
+
+/// What the sender does when the queue is full
+///
+/// - block: wait until the writer has taken a packet, USB reads stall meanwhile.
+/// - drop: discard the oldest queued packet to make room for the new one.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum OnFull {
+    Block,
+    Drop,
+}
+
+struct State {
+    packets: VecDeque<CapturedPacket>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    depth: usize,
+}
+
+/// Creates a queue holding at most `depth` packets between the capture and the writer
+pub fn bounded(depth: usize, on_full: OnFull) -> (PacketSender, PacketReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            packets: VecDeque::with_capacity(depth),
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        depth: depth.max(1),
+    });
+    (
+        PacketSender {
+            shared: shared.clone(),
+            on_full,
+        },
+        PacketReceiver { shared },
+    )
+}
+
+pub struct PacketSender {
+    shared: Arc<Shared>,
+    on_full: OnFull,
+}
+
+impl PacketSender {
+    /// Queues the packet, returns the packet that was dropped to make room for it, if any.
+    /// Fails when the receiver is gone.
+    pub fn send(
+        &self,
+        packet: CapturedPacket,
+    ) -> Result<Option<CapturedPacket>, SendError<CapturedPacket>> {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut dropped = None;
+        while state.receiver_alive && state.packets.len() >= self.shared.depth {
+            match self.on_full {
+                OnFull::Block => state = self.shared.not_full.wait(state).unwrap(),
+                OnFull::Drop => dropped = state.packets.pop_front(),
+            }
+        }
+        if !state.receiver_alive {
+            return Err(SendError(packet));
+        }
+
+        state.packets.push_back(packet);
+        self.shared.not_empty.notify_one();
+        Ok(dropped)
+    }
+}
+
+impl Drop for PacketSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender_alive = false;
+        self.shared.not_empty.notify_all();
+    }
+}
+
+/// Yields the queued packets in order until the sender is dropped and the queue is empty
+pub struct PacketReceiver {
+    shared: Arc<Shared>,
+}
+
+impl Iterator for PacketReceiver {
+    type Item = CapturedPacket;
+
+    fn next(&mut self) -> Option<CapturedPacket> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(packet) = state.packets.pop_front() {
+                self.shared.not_full.notify_one();
+                return Some(packet);
+            }
+            if !state.sender_alive {
+                return None;
+            }
+            state = self.shared.not_empty.wait(state).unwrap();
+        }
+    }
+}
+
+impl PacketReceiver {
+    /// Yields None after `timeout` without a packet, so the receiver can do periodic work
+    /// while the channel is idle. Ends like the plain iterator.
+    pub fn ticking(self, timeout: Duration) -> Ticking {
+        Ticking {
+            receiver: self,
+            timeout,
+        }
+    }
+}
+
+/// The packets of a `PacketReceiver` with a None for every `timeout` without one
+pub struct Ticking {
+    receiver: PacketReceiver,
+    timeout: Duration,
+}
+
+impl Iterator for Ticking {
+    type Item = Option<CapturedPacket>;
+
+    fn next(&mut self) -> Option<Option<CapturedPacket>> {
+        let shared = &self.receiver.shared;
+        let deadline = Instant::now() + self.timeout;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some(packet) = state.packets.pop_front() {
+                shared.not_full.notify_one();
+                return Some(Some(packet));
+            }
+            if !state.sender_alive {
+                return None;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Some(None);
+            }
+            state = shared.not_empty.wait_timeout(state, left).unwrap().0;
+        }
+    }
+}
+
+impl Drop for PacketReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_alive = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::CapturedPacket;
+    use crate::queue::{bounded, OnFull};
+    use std::thread;
+    use std::time::Duration;
+
+    fn packet(lqi: u8) -> CapturedPacket {
+        CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 13,
+            rssi: -45,
+            lqi,
+            payload: vec![],
+        }
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (tx, rx) = bounded(2, OnFull::Drop);
+        assert!(tx.send(packet(1)).unwrap().is_none());
+        assert!(tx.send(packet(2)).unwrap().is_none());
+        assert_eq!(tx.send(packet(3)).unwrap().unwrap().lqi, 1);
+        drop(tx);
+
+        let lqis: Vec<u8> = rx.map(|p| p.lqi).collect();
+        assert_eq!(lqis, [2, 3]);
+    }
+
+    #[test]
+    fn block_until_received() {
+        let (tx, rx) = bounded(1, OnFull::Block);
+        let sender = thread::spawn(move || {
+            for i in 0..10 {
+                assert!(tx.send(packet(i)).unwrap().is_none());
+            }
+        });
+
+        let lqis: Vec<u8> = rx.map(|p| p.lqi).collect();
+        sender.join().unwrap();
+        assert_eq!(lqis, (0..10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn ticking() {
+        let (tx, rx) = bounded(4, OnFull::Block);
+        let mut ticking = rx.ticking(Duration::from_millis(10));
+        assert!(matches!(ticking.next(), Some(None)));
+        tx.send(packet(1)).unwrap();
+        assert_eq!(ticking.next().unwrap().unwrap().lqi, 1);
+        tx.send(packet(2)).unwrap();
+        drop(tx);
+        assert_eq!(ticking.next().unwrap().unwrap().lqi, 2);
+        assert!(ticking.next().is_none());
+    }
+
+    #[test]
+    fn receiver_gone() {
+        let (tx, rx) = bounded(1, OnFull::Block);
+        tx.send(packet(1)).unwrap();
+        drop(rx);
+        assert!(tx.send(packet(2)).is_err());
+    }
+}
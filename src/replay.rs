@@ -0,0 +1,180 @@
+use clap::ValueEnum;
+use std::io::{BufRead, ErrorKind, Read};
+use std::num::ParseIntError;
+use std::{error, fmt, io};
+
+/// Layout of a file with raw MPDUs
+///
+/// - hex: one frame per line as hex bytes, whitespace is ignored. Empty lines and lines
+///   starting with `#` are skipped.
+/// - binary: records of a little endian u16 length followed by the frame.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum InputFormat {
+    Hex,
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    IoError(io::Error),
+    InvalidHex(usize, ParseIntError),
+    OddLength(usize),
+    TruncatedRecord,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::IoError(e) => write!(f, "io error: {}", e),
+            ReplayError::InvalidHex(line, e) => write!(f, "line {}: invalid hex: {}", line, e),
+            ReplayError::OddLength(line) => write!(f, "line {}: odd number of hex digits", line),
+            ReplayError::TruncatedRecord => write!(f, "truncated record at end of file"),
+        }
+    }
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> Self {
+        ReplayError::IoError(e)
+    }
+}
+
+impl error::Error for ReplayError {}
+
+/// Reads all frames from the input in the given format
+pub fn read_frames<R: BufRead>(r: R, format: InputFormat) -> Result<Vec<Vec<u8>>, ReplayError> {
+    match format {
+        InputFormat::Hex => read_hex_frames(r),
+        InputFormat::Binary => read_binary_frames(r),
+    }
+}
+
+fn read_hex_frames<R: BufRead>(r: R) -> Result<Vec<Vec<u8>>, ReplayError> {
+    let mut frames = vec![];
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let digits: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(ReplayError::OddLength(i + 1));
+        }
+
+        let frame = (0..digits.len())
+            .step_by(2)
+            .map(|n| u8::from_str_radix(&digits[n..n + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|e| ReplayError::InvalidHex(i + 1, e))?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+fn read_binary_frames<R: Read>(mut r: R) -> Result<Vec<Vec<u8>>, ReplayError> {
+    let mut frames = vec![];
+    loop {
+        let mut length = [0u8; 2];
+        match r.read_exact(&mut length) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut frame = vec![0u8; u16::from_le_bytes(length) as usize];
+        r.read_exact(&mut frame).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => ReplayError::TruncatedRecord,
+            _ => e.into(),
+        })?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fcs::FcsMode;
+    use crate::packet::CapturedPacket;
+    use crate::replay::{read_frames, InputFormat, ReplayError};
+    use crate::sink::{write_packets, DEFAULT_FLUSH_INTERVAL};
+    use crate::stats::CaptureStats;
+    use crate::writer::{CaptureWriter, WriterOptions};
+    use pcap_file::pcapng::{Block, PcapNgReader};
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/frames.hex");
+
+    #[test]
+    fn read_hex() {
+        let frames = read_frames(FIXTURE.as_bytes(), InputFormat::Hex).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0], [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+    }
+
+    #[test]
+    fn read_hex_invalid() {
+        let result = read_frames("02 00 2\n".as_bytes(), InputFormat::Hex);
+        assert!(matches!(result, Err(ReplayError::OddLength(1))));
+        let result = read_frames("# comment\n02 0g\n".as_bytes(), InputFormat::Hex);
+        assert!(matches!(result, Err(ReplayError::InvalidHex(2, _))));
+    }
+
+    #[test]
+    fn read_binary() {
+        let data = [3, 0, 0x02, 0x00, 0x2a, 1, 0, 0xff];
+        let frames = read_frames(&data[..], InputFormat::Binary).unwrap();
+        assert_eq!(frames, [vec![0x02, 0x00, 0x2a], vec![0xff]]);
+
+        let result = read_frames(&data[..7], InputFormat::Binary);
+        assert!(matches!(result, Err(ReplayError::TruncatedRecord)));
+    }
+
+    #[test]
+    fn replay_into_writer() {
+        let frames = read_frames(FIXTURE.as_bytes(), InputFormat::Hex).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let sender = thread::spawn(move || {
+            for (i, frame) in frames.into_iter().enumerate() {
+                let packet = CapturedPacket {
+                    timestamp: Duration::from_secs(i as u64),
+                    channel: 13,
+                    rssi: 0,
+                    lqi: 0,
+                    payload: frame,
+                };
+                tx.send(packet).unwrap();
+            }
+        });
+
+        let options = WriterOptions::default();
+        let mut writer = CaptureWriter::new(Vec::new(), "replay", &options).unwrap();
+        let stats = Mutex::default();
+        write_packets(
+            rx,
+            FcsMode::Keep,
+            &mut [&mut writer],
+            &stats,
+            DEFAULT_FLUSH_INTERVAL,
+        )
+        .unwrap();
+        sender.join().unwrap();
+        let stats: CaptureStats = stats.into_inner().unwrap();
+        assert_eq!(stats.packets, 3);
+
+        let data = writer.into_inner().unwrap();
+        let mut reader = PcapNgReader::new(data.as_slice()).unwrap();
+        let mut packets = vec![];
+        while let Some(block) = reader.next_block() {
+            if let Block::EnhancedPacket(epb) = block.unwrap() {
+                packets.push(epb.data[36..].to_vec());
+            }
+        }
+        assert_eq!(packets[0], [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+        assert_eq!(packets.len(), 3);
+    }
+}
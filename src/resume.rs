@@ -0,0 +1,122 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What a capture with `--resume` keeps on disk, so a run after a crash continues the
+/// file numbering instead of overwriting the files of the previous run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResumeState {
+    /// Number of the last run's files, the first run has number 1
+    pub file_index: u32,
+    /// Packets written by all runs
+    pub packets: u64,
+    /// The id the first run stored in its files, the later runs reuse it
+    pub capture_id: Option<String>,
+}
+
+impl ResumeState {
+    /// Reads the state written by `save`, None when the file is missing or doesn't parse
+    pub fn load(path: &Path) -> Option<ResumeState> {
+        fs::read_to_string(path).ok()?.parse().ok()
+    }
+
+    /// Writes a temporary file and renames it, so a crash while saving leaves the old
+    /// state rather than half a file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, self.to_string())?;
+        fs::rename(temporary, path)
+    }
+}
+
+/// One `key=value` line per field
+impl Display for ResumeState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "file-index={}", self.file_index)?;
+        writeln!(f, "packets={}", self.packets)?;
+        if let Some(id) = &self.capture_id {
+            writeln!(f, "capture-id={}", id)?;
+        }
+        Ok(())
+    }
+}
+
+/// The state file is missing a field or has a line that isn't `key=value`
+#[derive(Debug, PartialEq)]
+pub struct ParseStateError;
+
+impl std::str::FromStr for ResumeState {
+    type Err = ParseStateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut file_index, mut packets, mut capture_id) = (None, None, None);
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let (key, value) = line.split_once('=').ok_or(ParseStateError)?;
+            match key {
+                "file-index" => file_index = Some(value.parse().map_err(|_| ParseStateError)?),
+                "packets" => packets = Some(value.parse().map_err(|_| ParseStateError)?),
+                "capture-id" => capture_id = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(ResumeState {
+            file_index: file_index.ok_or(ParseStateError)?,
+            packets: packets.ok_or(ParseStateError)?,
+            capture_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resume::{ParseStateError, ResumeState};
+    use std::fs;
+
+    #[test]
+    fn round_trip() {
+        let state = ResumeState {
+            file_index: 3,
+            packets: 1234,
+            capture_id: Some("5f0c".to_string()),
+        };
+        assert_eq!(state.to_string().parse(), Ok(state));
+        assert_eq!(
+            "file-index=1\npackets=0\n".parse(),
+            Ok(ResumeState {
+                file_index: 1,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn corrupt() {
+        for corrupt in [
+            "",
+            "file-index=1\n",
+            "file-index=x\npackets=0\n",
+            "packets\n",
+        ] {
+            assert_eq!(corrupt.parse::<ResumeState>(), Err(ParseStateError));
+        }
+    }
+
+    #[test]
+    fn load_and_save() {
+        let path = std::env::temp_dir().join(format!("ccsniffer-resume-{}", std::process::id()));
+        assert_eq!(ResumeState::load(&path), None);
+
+        let state = ResumeState {
+            file_index: 2,
+            packets: 10,
+            capture_id: None,
+        };
+        state.save(&path).unwrap();
+        assert_eq!(ResumeState::load(&path), Some(state));
+
+        fs::write(&path, "garbage").unwrap();
+        assert_eq!(ResumeState::load(&path), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use clap::ValueEnum;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What happens to an existing log file when a run starts
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum LogMode {
+    // The runs follow each other in one file
+    #[default]
+    Append,
+    // Every run starts with an empty file
+    Truncate,
+    // The previous run's log is kept as <path>.1
+    Rotate,
+}
+
+/// Diagnostic messages of a run, each line with the time it was written
+pub struct RunLog<W: Write> {
+    w: W,
+}
+
+impl RunLog<File> {
+    pub fn open(path: &Path, mode: LogMode) -> io::Result<Self> {
+        let file = match mode {
+            LogMode::Append => OpenOptions::new().create(true).append(true).open(path)?,
+            LogMode::Truncate => File::create(path)?,
+            LogMode::Rotate => {
+                if path.exists() {
+                    fs::rename(path, rotated_path(path))?;
+                }
+                File::create(path)?
+            }
+        };
+        Ok(RunLog::new(file))
+    }
+}
+
+impl<W: Write> RunLog<W> {
+    pub fn new(w: W) -> Self {
+        RunLog { w }
+    }
+
+    /// Writes every line of `message` after the time since the UNIX epoch, and flushes so
+    /// a crash doesn't lose the last lines. Empty lines are left out.
+    pub fn write(&mut self, time: Duration, message: &str) -> io::Result<()> {
+        for line in message.lines().filter(|l| !l.trim().is_empty()) {
+            writeln!(self.w, "[{:.3}] {}", time.as_secs_f64(), line)?;
+        }
+        self.w.flush()
+    }
+}
+
+/// Where `LogMode::Rotate` moves the previous log, `<path>.1`
+pub fn rotated_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runlog::{rotated_path, LogMode, RunLog};
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamped_lines() {
+        let mut log = RunLog::new(vec![]);
+        log.write(Duration::from_millis(1500), "Captured 3 packets")
+            .unwrap();
+        log.write(Duration::from_secs(2), "").unwrap();
+        log.write(Duration::from_secs(2), "Top talkers:\n 0x1234: 3 packets")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(log.w).unwrap(),
+            "[1.500] Captured 3 packets\n[2.000] Top talkers:\n[2.000]  0x1234: 3 packets\n"
+        );
+    }
+
+    #[test]
+    fn log_modes() {
+        let path = std::env::temp_dir().join(format!("ccsniffer-log-{}", std::process::id()));
+        let run = |mode: LogMode, message: &str| {
+            let mut log = RunLog::open(&path, mode).unwrap();
+            log.write(Duration::ZERO, message).unwrap();
+        };
+
+        run(LogMode::Truncate, "first");
+        run(LogMode::Append, "second");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[0.000] first\n[0.000] second\n"
+        );
+
+        run(LogMode::Rotate, "third");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[0.000] third\n");
+        let rotated = rotated_path(&path);
+        assert_eq!(
+            fs::read_to_string(&rotated).unwrap(),
+            "[0.000] first\n[0.000] second\n"
+        );
+
+        run(LogMode::Truncate, "fourth");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[0.000] fourth\n");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+        assert_eq!(
+            rotated_path(Path::new("logs/run.log")),
+            Path::new("logs/run.log.1")
+        );
+    }
+}
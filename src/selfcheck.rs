@@ -0,0 +1,155 @@
+use crate::fcs::fcs_valid;
+use crate::pcaptap::{header_len, read_header, TapBlock};
+use crate::protocol::{calculate_crc, Message, ProtocolError};
+use crate::sniffer::{CmdCodes, DEFAULT_CHECKSUM_SEED};
+
+// Imm-Ack for sequence number 42 with a correct FCS
+const ACK: [u8; 5] = [0x02, 0x00, 0x2a, 0xe0, 0x3b];
+
+type CheckFn = fn() -> Result<(), String>;
+
+/// The result of one check, the error says what didn't hold
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<(), String>,
+}
+
+/// Round trips synthetic commands, sniffer frames and TAP headers through the codecs,
+/// without a sniffer. For checking a build or an install with `ccsniffer-rust verify`.
+pub fn run() -> Vec<Check> {
+    let checks: [(&'static str, CheckFn); 6] = [
+        ("command framing", command_framing),
+        ("sniffer frame round trip", frame_round_trip),
+        ("checksum detects corruption", checksum),
+        ("length fields that differ", length_mismatch),
+        ("TAP header round trip", tap_round_trip),
+        ("FCS", fcs),
+    ];
+    checks
+        .into_iter()
+        .map(|(name, check)| Check {
+            name,
+            result: check(),
+        })
+        .collect()
+}
+
+fn ensure(condition: bool, problem: &str) -> Result<(), String> {
+    if condition {
+        Ok(())
+    } else {
+        Err(problem.to_string())
+    }
+}
+
+/// Commands as the firmware expects them
+fn command_framing() -> Result<(), String> {
+    let init = Message::new(CmdCodes::CmdInit as u8, &[]).to_bytes(DEFAULT_CHECKSUM_SEED);
+    ensure(init == [0x03, 0x00, 0xfc], "init isn't 03 00 fc")?;
+    let channel = Message::new(CmdCodes::CmdSetChannel as u8, &[15]);
+    let channel = channel.to_bytes(DEFAULT_CHECKSUM_SEED);
+    ensure(
+        channel == [0x04, 0x02, 0x0f, 0xf6],
+        "set channel 15 isn't 04 02 0f f6",
+    )
+}
+
+/// A frame from the sniffer, built like a command with the length in front again
+fn sniffer_frame(code: CmdCodes, body: &[u8]) -> Vec<u8> {
+    let bytes = Message::new(code as u8, body).to_bytes(DEFAULT_CHECKSUM_SEED);
+    let mut frame = vec![bytes[0] + 1, bytes[0] + 1];
+    frame.extend_from_slice(&bytes[1..bytes.len() - 1]);
+    frame.push(calculate_crc(&frame, frame.len(), DEFAULT_CHECKSUM_SEED));
+    frame
+}
+
+fn frame_round_trip() -> Result<(), String> {
+    let body = [[0xd3, 120].as_slice(), &ACK].concat();
+    let frame = sniffer_frame(CmdCodes::CmdGotPkt, &body);
+    let message = Message::from_slice(&frame, true).map_err(|e| e.to_string())?;
+    ensure(
+        message.code == CmdCodes::CmdGotPkt as u8,
+        "the command code changed",
+    )?;
+    ensure(message.body == body.as_slice(), "the body changed")
+}
+
+fn checksum() -> Result<(), String> {
+    let mut frame = sniffer_frame(CmdCodes::CmdInitAck, &[]);
+    let n = frame.len();
+    ensure(
+        frame[n - 1] == calculate_crc(&frame, n - 1, DEFAULT_CHECKSUM_SEED),
+        "a correct checksum doesn't match",
+    )?;
+    frame[2] ^= 0x01;
+    ensure(
+        frame[n - 1] != calculate_crc(&frame, n - 1, DEFAULT_CHECKSUM_SEED),
+        "a flipped bit isn't detected",
+    )
+}
+
+fn length_mismatch() -> Result<(), String> {
+    let mut frame = sniffer_frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x02, 0x00, 0x2a]);
+    frame[0] += 1;
+    frame.push(0x00);
+    ensure(
+        Message::from_slice(&frame, true).err()
+            == Some(ProtocolError::SizeMismatch(frame[0], frame[1])),
+        "strict parsing accepts differing lengths",
+    )?;
+    let message = Message::from_slice(&frame, false).map_err(|e| e.to_string())?;
+    ensure(
+        message.body == [0xd3, 120, 0x02, 0x00, 0x2a].as_slice(),
+        "the frame isn't cut to the smaller length",
+    )
+}
+
+fn tap_round_trip() -> Result<(), String> {
+    let blocks = || {
+        [
+            TapBlock::FcsType(1),
+            TapBlock::TlvRssi(-45.0),
+            TapBlock::ChannelAssignment(15),
+            TapBlock::TlvLqi(120),
+        ]
+    };
+    let mut data = vec![];
+    let written = (|| {
+        let mut written = TapBlock::Header(header_len(&blocks())).write_to(&mut data)?;
+        for block in blocks() {
+            written += block.write_to(&mut data)?;
+        }
+        std::io::Result::Ok(written)
+    })()
+    .map_err(|e| e.to_string())?;
+    ensure(
+        written == header_len(&blocks()),
+        "the header length doesn't match the TLVs",
+    )?;
+    data.extend_from_slice(&ACK);
+
+    let (read, payload) = read_header(&data).map_err(|e| e.to_string())?;
+    ensure(read == blocks(), "the TLVs changed")?;
+    ensure(payload == ACK, "the MPDU changed")
+}
+
+fn fcs() -> Result<(), String> {
+    ensure(fcs_valid(&ACK), "a correct FCS is rejected")?;
+    let mut corrupted = ACK;
+    corrupted[2] ^= 0x01;
+    ensure(!fcs_valid(&corrupted), "a flipped bit isn't detected")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selfcheck::run;
+
+    #[test]
+    fn all_checks_pass() {
+        let checks = run();
+        assert_eq!(checks.len(), 6);
+        for check in checks {
+            assert_eq!(check.result, Ok(()), "{}", check.name);
+        }
+    }
+}
@@ -0,0 +1,884 @@
+use crate::fcs::FcsMode;
+use crate::packet::{now, CapturedPacket, CapturedPacketRef};
+use crate::protocol::Message;
+use crate::queue::{self, OnFull, PacketSender, DEFAULT_QUEUE_DEPTH};
+use crate::sink::{write_packets_with_heartbeat, PacketSink, DEFAULT_FLUSH_INTERVAL};
+use crate::sniffer::{
+    CmdCodes, DeviceState, SnifferDevice, SnifferError, MAX_DRAIN_FRAMES, READ_BUFFER_SIZE,
+};
+use crate::stats::{CaptureStats, SourceSignals, DEFAULT_RSSI_DECAY};
+use crate::writer::WriterError;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+// First sleep of the idle backoff, doubled on every timeout after that
+const IDLE_BACKOFF_START: Duration = Duration::from_millis(10);
+
+/// Sleeps between reads after consecutive timeouts, so a quiet channel costs less CPU.
+/// The sleep doubles up to `max`, which bounds the added latency for the next frame.
+/// A zero `max` disables it.
+pub struct IdleBackoff {
+    max: Duration,
+    next: Duration,
+}
+
+impl IdleBackoff {
+    pub fn new(max: Duration) -> Self {
+        IdleBackoff {
+            max,
+            next: IDLE_BACKOFF_START,
+        }
+    }
+
+    /// How long to sleep after another read timed out
+    pub fn timed_out(&mut self) -> Duration {
+        let delay = self.next.min(self.max);
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Called when the sniffer returned data, the next timeout sleeps briefly again
+    pub fn reset(&mut self) {
+        self.next = IDLE_BACKOFF_START;
+    }
+}
+
+/// Warns once when no frame arrived within `window` after sniffing started, which means
+/// the channel is idle or the sniffer silently ignored the start commands. Checked on
+/// the same read timeouts as `IdleBackoff`. A zero `window` disables it.
+pub struct StartupCheck {
+    window: Duration,
+    started: Instant,
+    pending: bool,
+}
+
+impl StartupCheck {
+    pub fn new(window: Duration) -> Self {
+        StartupCheck {
+            window,
+            started: Instant::now(),
+            pending: !window.is_zero(),
+        }
+    }
+
+    /// Called when a read timed out, true the first time that happens after the window
+    /// without a frame
+    pub fn timed_out(&mut self) -> bool {
+        if self.pending && self.started.elapsed() >= self.window {
+            self.pending = false;
+            return true;
+        }
+        false
+    }
+
+    /// Called when a frame arrived or the capture was paused, there is no warning after
+    /// that
+    pub fn stop(&mut self) {
+        self.pending = false;
+    }
+}
+
+/// When to check that a sniffer on a quiet channel still answers, see
+/// `CaptureSession::keepalive`. Due after `interval` without a frame or another check. A
+/// zero `interval` disables it.
+pub struct KeepaliveTimer {
+    interval: Duration,
+    last: Instant,
+}
+
+impl KeepaliveTimer {
+    pub fn new(interval: Duration) -> Self {
+        KeepaliveTimer {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Called when a read timed out, true when a keepalive should be sent. Starts the
+    /// next interval.
+    pub fn due(&mut self) -> bool {
+        if self.interval.is_zero() || self.last.elapsed() < self.interval {
+            return false;
+        }
+        self.last = Instant::now();
+        true
+    }
+
+    /// Called when a frame arrived, which shows the sniffer is alive
+    pub fn reset(&mut self) {
+        self.last = Instant::now();
+    }
+}
+
+/// Settings for the writer side of a capture session
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+    pub fcs: FcsMode,
+    pub queue_depth: usize,
+    pub on_full: OnFull,
+    /// Pause between the startup commands, for firmwares that NAK when they come too fast
+    pub command_delay: Duration,
+    /// Weight of a new sample in the per-source RSSI average
+    pub rssi_decay: f64,
+    /// Leave the device sniffing when the session finishes
+    pub no_sniff_off: bool,
+    /// Count the frames carrying a 6LoWPAN fragment header
+    pub lowpan_fragments: bool,
+    /// How often the writer thread flushes the sinks, zero after every packet
+    pub flush_interval: Duration,
+    /// How often the sinks get a heartbeat, also on an idle channel, zero for none
+    pub heartbeat: Duration,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            fcs: FcsMode::default(),
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            on_full: OnFull::Block,
+            command_delay: Duration::ZERO,
+            rssi_decay: DEFAULT_RSSI_DECAY,
+            no_sniff_off: false,
+            lowpan_fragments: false,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            heartbeat: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// The sniffer failed while its queue was emptied at startup
+    DrainFailed(SnifferError),
+    SnifferError(SnifferError),
+    WriterError(WriterError),
+    WriterStopped,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::DrainFailed(e) => {
+                write!(f, "the sniffer failed while its queue was emptied: {}", e)
+            }
+            SessionError::SnifferError(e) => write!(f, "{}", e),
+            SessionError::WriterError(e) => write!(f, "{}", e),
+            SessionError::WriterStopped => write!(f, "writer thread stopped unexpectedly"),
+        }
+    }
+}
+
+impl From<SnifferError> for SessionError {
+    fn from(e: SnifferError) -> Self {
+        SessionError::SnifferError(e)
+    }
+}
+
+impl From<WriterError> for SessionError {
+    fn from(e: WriterError) -> Self {
+        SessionError::WriterError(e)
+    }
+}
+
+impl error::Error for SessionError {}
+
+/// A running capture: the sniffer, a writer thread feeding the sinks, and the stats
+pub struct CaptureSession {
+    sniffer: SnifferDevice,
+    channel: u8,
+    tx: PacketSender,
+    writer_thread: JoinHandle<Result<(), WriterError>>,
+    stats: Arc<Mutex<CaptureStats>>,
+    device_state: DeviceState,
+    no_sniff_off: bool,
+}
+
+impl CaptureSession {
+    /// Starts sniffing on `channel`. Packets are written to the sinks on a separate thread
+    /// so a slow disk doesn't stall the USB reads.
+    pub fn start(
+        sniffer: SnifferDevice,
+        channel: u8,
+        mut sinks: Vec<Box<dyn PacketSink>>,
+        options: &SessionOptions,
+    ) -> Result<Self, SessionError> {
+        // After repeated use there might be packets of an earlier run in the queue, they
+        // are dropped. A timeout means the queue is empty, other errors a broken device.
+        match drain_queue(&sniffer, channel) {
+            // The first frame can be cut off
+            Ok(_) | Err(SnifferError::ProtocolError(_)) => {}
+            Err(e) => return Err(SessionError::DrainFailed(e)),
+        }
+
+        let device_state = sniffer.start_sniffing(channel, options.command_delay)?;
+
+        let (tx, rx) = queue::bounded(options.queue_depth, options.on_full);
+        let stats = Arc::new(Mutex::new(CaptureStats {
+            sources: SourceSignals::new(options.rssi_decay),
+            lowpan_fragments: options.lowpan_fragments.then_some(0),
+            ..Default::default()
+        }));
+        let stats_writer = stats.clone();
+        let fcs = options.fcs;
+        let flush_interval = options.flush_interval;
+        let heartbeat = options.heartbeat;
+        let writer_thread = thread::spawn(move || {
+            let mut sinks: Vec<&mut dyn PacketSink> =
+                sinks.iter_mut().map(|s| s.as_mut() as _).collect();
+            let stats = &stats_writer;
+            write_packets_with_heartbeat(rx, fcs, &mut sinks, stats, flush_interval, heartbeat)
+        });
+
+        Ok(CaptureSession {
+            sniffer,
+            channel,
+            tx,
+            writer_thread,
+            stats,
+            device_state,
+            no_sniff_off: options.no_sniff_off,
+        })
+    }
+
+    /// The state the startup commands left the sniffer in, sniffing is cleared while the
+    /// capture is paused
+    pub fn device_state(&self) -> DeviceState {
+        self.device_state
+    }
+
+    pub fn is_paused(&self) -> bool {
+        !self.device_state.sniffing
+    }
+
+    /// Stops sniffing without ending the capture. Returns the packets the sniffer sent
+    /// before it stopped, so they can be queued like any other and nothing received
+    /// before the pause comes in after the resume.
+    pub fn pause(&mut self) -> Result<Vec<CapturedPacket>, SnifferError> {
+        self.sniffer.send_command(CmdCodes::CmdSniffOff, &[])?;
+        self.device_state.sniffing = false;
+        self.drain()
+    }
+
+    /// Stops sniffing at the end of the capture and returns the packets the sniffer still
+    /// had queued, to be queued like any other before `finish`, so the frames received
+    /// just before the stop aren't lost. Does nothing with `no_sniff_off` or while paused.
+    pub fn stop(&mut self) -> Result<Vec<CapturedPacket>, SnifferError> {
+        if self.no_sniff_off || self.is_paused() {
+            return Ok(vec![]);
+        }
+        self.pause()
+    }
+
+    /// Reads the packets the sniffer sent before it stopped sniffing
+    fn drain(&self) -> Result<Vec<CapturedPacket>, SnifferError> {
+        drain_queue(&self.sniffer, self.channel)
+    }
+
+    /// Checks that the sniffer still answers by sending sniff on again, which the firmware
+    /// acks without changing anything. A frame the sniffer sent just before the ack is
+    /// returned, to be queued like any other. A failure is counted in the stats. Does
+    /// nothing while paused.
+    pub fn keepalive(&self) -> Result<Option<CapturedPacket>, SnifferError> {
+        if self.is_paused() {
+            return Ok(None);
+        }
+        let answer = self
+            .sniffer
+            .raw_command(CmdCodes::CmdSniffOn as u8, &[])
+            .and_then(|response| {
+                let message = Message::from_slice(&response, false)?;
+                match message.code {
+                    c if c == CmdCodes::CmdSniffOnAck as u8 => Ok(None),
+                    // The ack follows and is skipped like any frame that isn't a packet
+                    c if c == CmdCodes::CmdGotPkt as u8 => Ok(CapturedPacket::from_sniffer_data(
+                        message.body.to_vec(),
+                        self.channel,
+                        now(),
+                    )),
+                    _ => Err(SnifferError::ProtocolError("unexpected response code")),
+                }
+            });
+        if answer.is_err() {
+            self.stats.lock().unwrap().keepalive_failures += 1;
+        }
+        answer
+    }
+
+    /// Starts sniffing again after `pause`
+    pub fn resume(&mut self) -> Result<(), SnifferError> {
+        self.sniffer.send_command(CmdCodes::CmdSniffOn, &[])?;
+        self.device_state.sniffing = true;
+        Ok(())
+    }
+
+    /// The stats of the running capture, e.g. to report them from a signal handler
+    pub fn stats(&self) -> Arc<Mutex<CaptureStats>> {
+        self.stats.clone()
+    }
+
+    /// Waits for the next packet from the sniffer, None if the frame was too short to
+    /// hold the metadata
+    pub fn receive_packet(&self) -> Result<Option<CapturedPacket>, SnifferError> {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        let packet = self.receive_packet_into(&mut buffer)?;
+        Ok(packet.map(|p| p.to_owned()))
+    }
+
+    /// Like `receive_packet`, with the payload borrowed from `buffer`, see
+    /// `CapturedPacketRef` for how long it stays valid. Counts the frames rejected with
+    /// `FrameTooLong` and the ones read despite differing length fields.
+    pub fn receive_packet_into<'b>(
+        &self,
+        buffer: &'b mut [u8],
+    ) -> Result<Option<CapturedPacketRef<'b>>, SnifferError> {
+        let data = match self.sniffer.receive_packet_into(buffer) {
+            Err(e @ SnifferError::FrameTooLong(_)) => {
+                self.stats.lock().unwrap().too_long += 1;
+                return Err(e);
+            }
+            result => result?,
+        };
+        if self.sniffer.take_length_mismatch() {
+            self.stats.lock().unwrap().length_mismatches += 1;
+        }
+        Ok(CapturedPacketRef::from_sniffer_data(
+            data,
+            self.channel,
+            now(),
+        ))
+    }
+
+    /// Hands the packet to the writer thread, fails if the writer has stopped
+    pub fn queue_packet(&self, packet: CapturedPacket) -> Result<(), SessionError> {
+        match self.tx.send(packet) {
+            Ok(None) => Ok(()),
+            Ok(Some(_)) => {
+                self.stats.lock().unwrap().dropped += 1;
+                Ok(())
+            }
+            Err(_) => Err(SessionError::WriterStopped),
+        }
+    }
+
+    /// Counts a packet that a filter kept out of the queue
+    pub fn count_filtered(&self) {
+        self.stats.lock().unwrap().filtered += 1;
+    }
+
+    /// Counts a packet left out because its MAC header didn't parse
+    pub fn count_unparseable(&self) {
+        self.stats.lock().unwrap().unparseable += 1;
+    }
+
+    /// Counts a packet left out because it is too short for its MAC header
+    pub fn count_inconsistent(&self) {
+        self.stats.lock().unwrap().inconsistent += 1;
+    }
+
+    /// Stops sniffing unless `no_sniff_off` is set or the capture is paused, `stop` does
+    /// that first to collect the last packets. Writes out the queued packets, flushes
+    /// the sinks and releases the device. Returns the final stats, or the first error in
+    /// that sequence.
+    pub fn finish(self) -> Result<CaptureStats, SessionError> {
+        let CaptureSession {
+            sniffer,
+            tx,
+            writer_thread,
+            stats,
+            device_state,
+            no_sniff_off,
+            ..
+        } = self;
+
+        let sniff_off = if no_sniff_off || !device_state.sniffing {
+            Ok(())
+        } else {
+            sniffer.send_command(CmdCodes::CmdSniffOff, &[])
+        };
+        drop(tx);
+        let written = writer_thread
+            .join()
+            .map_err(|_| SessionError::WriterStopped)?;
+        drop(sniffer);
+
+        match sniff_off {
+            // An unplugged sniffer has stopped anyway
+            Err(SnifferError::Disconnected) => {}
+            result => result?,
+        }
+        written?;
+        let stats = stats.lock().unwrap().clone();
+        Ok(stats)
+    }
+}
+
+/// Reads the frames queued on the sniffer until a read times out or `MAX_DRAIN_FRAMES`
+/// frames were read. The timeout is the empty queue, any other error is returned.
+fn drain_queue(sniffer: &SnifferDevice, channel: u8) -> Result<Vec<CapturedPacket>, SnifferError> {
+    let mut pending = vec![];
+    let mut buffer = [0; READ_BUFFER_SIZE];
+    for _ in 0..MAX_DRAIN_FRAMES {
+        let data = match sniffer.receive_queued_into(&mut buffer) {
+            Ok(data) => data,
+            Err(SnifferError::NonPacketFrame(_)) => continue,
+            Err(SnifferError::TimeOut) => break,
+            Err(e) => return Err(e),
+        };
+        if let Some(packet) = CapturedPacketRef::from_sniffer_data(data, channel, now()) {
+            pending.push(packet.to_owned());
+        }
+    }
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::CapturedPacket;
+    use crate::pcaptap::read_header;
+    use crate::session::{
+        CaptureSession, IdleBackoff, KeepaliveTimer, SessionError, SessionOptions, StartupCheck,
+    };
+    use crate::sink::{MemoryBuffer, PacketSink};
+    use crate::sniffer::tests::{frame, sniffer_with_writes};
+    use crate::sniffer::{CmdCodes, SnifferError};
+    use crate::writer::{CaptureWriter, WriterError, WriterOptions};
+    use pcap_file::pcapng::{Block, PcapNgReader};
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    type Payloads = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    struct Recorder {
+        payloads: Payloads,
+        finished: Arc<Mutex<bool>>,
+    }
+
+    impl PacketSink for Recorder {
+        fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+            self.payloads.lock().unwrap().push(packet.payload.clone());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), WriterError> {
+            *self.finished.lock().unwrap() = true;
+            Ok(())
+        }
+    }
+
+    // A disk that is full
+    struct FailingSink;
+
+    impl PacketSink for FailingSink {
+        fn write_packet(&mut self, _packet: &CapturedPacket) -> Result<(), WriterError> {
+            Err(io::Error::other("no space left on device").into())
+        }
+
+        fn finish(&mut self) -> Result<(), WriterError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_and_finish() {
+        let (sniffer, writes) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(
+                CmdCodes::CmdGotPkt,
+                &[0xd3, 120, 0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            )),
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+        ]);
+        let payloads = Payloads::default();
+        let finished = Arc::new(Mutex::new(false));
+        let recorder = Recorder {
+            payloads: payloads.clone(),
+            finished: finished.clone(),
+        };
+
+        let session = CaptureSession::start(
+            sniffer,
+            15,
+            vec![Box::new(recorder)],
+            &SessionOptions::default(),
+        )
+        .unwrap();
+        assert!(session.device_state().sniffing);
+        let packet = session.receive_packet().unwrap().unwrap();
+        assert_eq!(packet.channel, 15);
+        session.queue_packet(packet).unwrap();
+        let stats = session.finish().unwrap();
+
+        assert_eq!(stats.packets, 1);
+        assert!(*finished.lock().unwrap());
+        assert_eq!(*payloads.lock().unwrap(), [[0x02, 0x00, 0x2a, 0xe0, 0x3b]]);
+        let commands: Vec<u8> = writes.lock().unwrap().iter().map(|w| w[1]).collect();
+        assert_eq!(
+            commands,
+            [
+                CmdCodes::CmdInit as u8,
+                CmdCodes::CmdSetChannel as u8,
+                CmdCodes::CmdSniffOn as u8,
+                CmdCodes::CmdSniffOff as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn capture_to_memory() {
+        let sniffer = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(
+                CmdCodes::CmdGotPkt,
+                &[0xd3, 120, 0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            )),
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+        ])
+        .0;
+        let buffer = MemoryBuffer::new();
+        let writer =
+            CaptureWriter::new(buffer.clone(), "memory", &WriterOptions::default()).unwrap();
+
+        let session = CaptureSession::start(
+            sniffer,
+            15,
+            vec![Box::new(writer)],
+            &SessionOptions::default(),
+        )
+        .unwrap();
+        let packet = session.receive_packet().unwrap().unwrap();
+        session.queue_packet(packet).unwrap();
+        session.finish().unwrap();
+
+        let bytes = buffer.bytes();
+        let mut reader = PcapNgReader::new(bytes.as_slice()).unwrap();
+        let mut packets = vec![];
+        while let Some(block) = reader.next_block() {
+            if let Block::EnhancedPacket(epb) = block.unwrap() {
+                packets.push(read_header(&epb.data).unwrap().1.to_vec());
+            }
+        }
+        assert_eq!(packets, [[0x02, 0x00, 0x2a, 0xe0, 0x3b]]);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let (sniffer, writes) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+            // Sent before the sniffer stopped
+            Ok(frame(
+                CmdCodes::CmdGotPkt,
+                &[0xd3, 120, 0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            )),
+            Err(rusb::Error::Timeout),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+        ]);
+
+        let mut session =
+            CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default()).unwrap();
+        let pending = session.pause().unwrap();
+        assert!(session.is_paused());
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].payload, [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+
+        session.resume().unwrap();
+        assert!(!session.is_paused());
+        session.finish().unwrap();
+
+        let commands: Vec<u8> = writes.lock().unwrap().iter().map(|w| w[1]).collect();
+        assert_eq!(
+            commands[3..],
+            [
+                CmdCodes::CmdSniffOff as u8,
+                CmdCodes::CmdSniffOn as u8,
+                CmdCodes::CmdSniffOff as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_drains() {
+        let (mut sniffer, writes) = sniffer_with_writes(vec![
+            // Left over, drained at the start until the queue is empty
+            Ok(frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x01])),
+            Err(rusb::Error::Timeout),
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+            Ok(frame(
+                CmdCodes::CmdGotPkt,
+                &[0xd3, 120, 0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            )),
+            // Non-packet frames are skipped
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+            Ok(frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0xff])),
+        ]);
+        let stop = Arc::new(AtomicBool::new(false));
+        sniffer.set_stop_flag(stop.clone());
+
+        let mut session =
+            CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default()).unwrap();
+        // The queued frames are read even after Ctrl-C
+        stop.store(true, Ordering::Relaxed);
+        let pending = session.stop().unwrap();
+        let payloads: Vec<&[u8]> = pending.iter().map(|p| p.payload.as_slice()).collect();
+        assert_eq!(payloads, [&[0x02, 0x00, 0x2a, 0xe0, 0x3b][..], &[0xff]]);
+        session.finish().unwrap();
+
+        // Sniff off is only sent once
+        let commands: Vec<u8> = writes.lock().unwrap().iter().map(|w| w[1]).collect();
+        assert_eq!(commands[3..], [CmdCodes::CmdSniffOff as u8]);
+    }
+
+    #[test]
+    fn startup_drain() {
+        // An empty queue times out, the capture starts
+        let (sniffer, writes) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout),
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+        ]);
+        let session = CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default());
+        assert!(session.is_ok());
+        assert_eq!(writes.lock().unwrap().len(), 3);
+
+        // A USB error stops the startup before any command is sent
+        let (sniffer, writes) = sniffer_with_writes(vec![Err(rusb::Error::Io)]);
+        let result = CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default());
+        assert!(matches!(result, Err(SessionError::DrainFailed(_))));
+        assert!(writes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finish_paused() {
+        let (sniffer, writes) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+        ]);
+
+        let mut session =
+            CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default()).unwrap();
+        session.pause().unwrap();
+        session.finish().unwrap();
+        // The sniffer was already stopped by the pause
+        assert_eq!(writes.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn write_failed() {
+        let (sniffer, writes) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(
+                CmdCodes::CmdGotPkt,
+                &[0xd3, 120, 0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            )),
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+        ]);
+        let finished = Arc::new(Mutex::new(false));
+        let recorder = Recorder {
+            payloads: Payloads::default(),
+            finished: finished.clone(),
+        };
+
+        let sinks: Vec<Box<dyn PacketSink>> = vec![Box::new(FailingSink), Box::new(recorder)];
+        let session =
+            CaptureSession::start(sniffer, 15, sinks, &SessionOptions::default()).unwrap();
+        let packet = session.receive_packet().unwrap().unwrap();
+        session.queue_packet(packet).unwrap();
+        let result = session.finish();
+
+        assert!(matches!(
+            result,
+            Err(SessionError::WriterError(WriterError::IoError(_)))
+        ));
+        // The sniffer is stopped and the other sinks keep what they got
+        assert_eq!(writes.lock().unwrap()[3][1], CmdCodes::CmdSniffOff as u8);
+        assert!(*finished.lock().unwrap());
+    }
+
+    #[test]
+    fn finish_disconnected() {
+        let sniffer = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Err(rusb::Error::NoDevice),
+            Err(rusb::Error::NoDevice),
+        ])
+        .0;
+
+        let session =
+            CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default()).unwrap();
+        assert!(matches!(
+            session.receive_packet(),
+            Err(SnifferError::Disconnected)
+        ));
+        // The sniff off command fails the same way, the packets are still written
+        session.finish().unwrap();
+    }
+
+    #[test]
+    fn no_sniff_off() {
+        let (sniffer, writes) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+        ]);
+        let options = SessionOptions {
+            no_sniff_off: true,
+            ..Default::default()
+        };
+
+        let session = CaptureSession::start(sniffer, 15, vec![], &options).unwrap();
+        session.finish().unwrap();
+
+        let last = writes.lock().unwrap().last().unwrap()[1];
+        assert_eq!(last, CmdCodes::CmdSniffOn as u8);
+    }
+
+    #[test]
+    fn idle_backoff() {
+        let ms = Duration::from_millis;
+        let mut backoff = IdleBackoff::new(ms(50));
+        let delays: Vec<Duration> = (0..5).map(|_| backoff.timed_out()).collect();
+        assert_eq!(delays, [ms(10), ms(20), ms(40), ms(50), ms(50)]);
+        backoff.reset();
+        assert_eq!(backoff.timed_out(), ms(10));
+
+        let mut disabled = IdleBackoff::new(Duration::ZERO);
+        assert_eq!(disabled.timed_out(), Duration::ZERO);
+        assert_eq!(disabled.timed_out(), Duration::ZERO);
+    }
+
+    #[test]
+    fn keepalive() {
+        let (sniffer, writes) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            // A frame that came in before the ack
+            Ok(frame(
+                CmdCodes::CmdGotPkt,
+                &[0xd3, 120, 0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            )),
+            // No answer to the third
+        ]);
+        let session =
+            CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default()).unwrap();
+
+        assert!(session.keepalive().unwrap().is_none());
+        let packet = session.keepalive().unwrap().unwrap();
+        assert_eq!(packet.payload, [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+        assert!(session.keepalive().is_err());
+
+        assert_eq!(session.stats().lock().unwrap().keepalive_failures, 1);
+        let commands: Vec<u8> = writes.lock().unwrap().iter().map(|w| w[1]).collect();
+        assert_eq!(commands[3..], [CmdCodes::CmdSniffOn as u8; 3]);
+    }
+
+    #[test]
+    fn count_too_long() {
+        let (mut sniffer, _) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(frame(
+                CmdCodes::CmdGotPkt,
+                &[0xd3, 120, 0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            )),
+            Ok(frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x02, 0x00, 0x2a])),
+        ]);
+        sniffer.set_max_frame_len(3);
+        let session =
+            CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default()).unwrap();
+
+        assert!(matches!(
+            session.receive_packet(),
+            Err(SnifferError::FrameTooLong(5))
+        ));
+        assert!(session.receive_packet().unwrap().is_some());
+        assert_eq!(session.stats().lock().unwrap().too_long, 1);
+    }
+
+    #[test]
+    fn count_length_mismatches() {
+        let mut mismatch = frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x02, 0x00, 0x2a]);
+        mismatch[0] += 1;
+        let (sniffer, _) = sniffer_with_writes(vec![
+            Err(rusb::Error::Timeout), // drain
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(mismatch),
+            Ok(frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x02, 0x00, 0x2a])),
+        ]);
+        let session =
+            CaptureSession::start(sniffer, 15, vec![], &SessionOptions::default()).unwrap();
+
+        assert!(session.receive_packet().unwrap().is_some());
+        assert!(session.receive_packet().unwrap().is_some());
+        assert_eq!(session.stats().lock().unwrap().length_mismatches, 1);
+    }
+
+    #[test]
+    fn keepalive_timer() {
+        let mut timer = KeepaliveTimer::new(Duration::from_millis(20));
+        assert!(!timer.due());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(timer.due());
+        // The next interval starts
+        assert!(!timer.due());
+
+        std::thread::sleep(Duration::from_millis(30));
+        timer.reset();
+        assert!(!timer.due());
+
+        let mut disabled = KeepaliveTimer::new(Duration::ZERO);
+        assert!(!disabled.due());
+    }
+
+    #[test]
+    fn startup_check() {
+        let mut check = StartupCheck::new(Duration::from_millis(20));
+        assert!(!check.timed_out());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(check.timed_out());
+        // Only once
+        assert!(!check.timed_out());
+
+        let mut received = StartupCheck::new(Duration::from_millis(1));
+        received.stop();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!received.timed_out());
+
+        let mut disabled = StartupCheck::new(Duration::ZERO);
+        assert!(!disabled.timed_out());
+    }
+}
@@ -0,0 +1,832 @@
+use crate::compress::{Compression, Encoder};
+use crate::expression::Expression;
+use crate::fcs::FcsMode;
+use crate::mac::MacHeader;
+use crate::packet::{now, CapturedPacket};
+use crate::queue::PacketReceiver;
+use crate::stats::{json_string, CaptureStats, FrameQuality};
+use crate::writer::WriterError;
+use byteorder_slice::byteorder::WriteBytesExt;
+use byteorder_slice::LittleEndian;
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often `write_packets` flushes the sinks by default
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+// Longest wait for a packet before checking whether a heartbeat is due
+const HEARTBEAT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Format of the capture file
+///
+/// - pcapng: the packets with the configured link type, for Wireshark and friends.
+/// - carray: every MPDU as a C `uint8_t[]` initializer, for firmware test suites.
+/// - json: one JSON object per line, a metadata line and then one line per frame.
+/// - csv: a header row and one row per frame with the radio metadata and MAC addresses.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum OutputFormat {
+    Pcapng,
+    #[value(name = "carray")]
+    CArray,
+    Json,
+    Csv,
+}
+
+/// Destination for captured packets
+pub trait PacketSink: Send {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError>;
+
+    /// Writes out buffered data, called every flush interval during the capture
+    fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(())
+    }
+
+    /// Marks that the capture is alive at `time` without writing a frame, called every
+    /// heartbeat interval. Formats without a place for such a mark ignore it.
+    fn heartbeat(&mut self, _time: Duration) -> Result<(), WriterError> {
+        Ok(())
+    }
+
+    /// Flushes buffered data, called once when the capture ends
+    fn finish(&mut self) -> Result<(), WriterError>;
+}
+
+/// Applies the FCS policy to every received packet and hands it to all sinks, until the
+/// senders are gone. The stats are shared so they can be reported while
+/// the capture is running.
+///
+/// The sinks are flushed when a packet arrives `flush_interval` after the last flush, zero
+/// flushes after every packet. They are always finished at the end, also when a write
+/// failed, so the other sinks keep what they got. Returns the first error.
+pub fn write_packets(
+    rx: impl IntoIterator<Item = CapturedPacket>,
+    fcs: FcsMode,
+    sinks: &mut [&mut dyn PacketSink],
+    stats: &Mutex<CaptureStats>,
+    flush_interval: Duration,
+) -> Result<(), WriterError> {
+    let packets = rx.into_iter().map(Some);
+    // Dropping `rx` on an error lets the senders know the writer stopped
+    let written = write_to_sinks(packets, fcs, sinks, stats, flush_interval, None);
+    finish_sinks(sinks, written)
+}
+
+/// Like `write_packets`, and gives the sinks a heartbeat every `heartbeat`, also while no
+/// packets arrive. A zero `heartbeat` gives none.
+pub fn write_packets_with_heartbeat(
+    rx: PacketReceiver,
+    fcs: FcsMode,
+    sinks: &mut [&mut dyn PacketSink],
+    stats: &Mutex<CaptureStats>,
+    flush_interval: Duration,
+    heartbeat: Duration,
+) -> Result<(), WriterError> {
+    if heartbeat.is_zero() {
+        return write_packets(rx, fcs, sinks, stats, flush_interval);
+    }
+    let packets = rx.ticking(heartbeat.min(HEARTBEAT_POLL_INTERVAL));
+    let written = write_to_sinks(packets, fcs, sinks, stats, flush_interval, Some(heartbeat));
+    finish_sinks(sinks, written)
+}
+
+/// Finishes every sink, also when `written` failed, and returns the first error
+fn finish_sinks(
+    sinks: &mut [&mut dyn PacketSink],
+    written: Result<(), WriterError>,
+) -> Result<(), WriterError> {
+    let mut finished = Ok(());
+    for sink in sinks.iter_mut() {
+        let result = sink.finish();
+        if finished.is_ok() {
+            finished = result;
+        }
+    }
+    written.and(finished)
+}
+
+/// Writes the packets, a None only gives the heartbeat a chance on an idle channel
+fn write_to_sinks(
+    rx: impl IntoIterator<Item = Option<CapturedPacket>>,
+    fcs: FcsMode,
+    sinks: &mut [&mut dyn PacketSink],
+    stats: &Mutex<CaptureStats>,
+    flush_interval: Duration,
+    heartbeat: Option<Duration>,
+) -> Result<(), WriterError> {
+    let mut last_flush = Instant::now();
+    let mut last_heartbeat = Instant::now();
+    for received in rx {
+        if heartbeat.is_some_and(|interval| last_heartbeat.elapsed() >= interval) {
+            let time = now();
+            for sink in sinks.iter_mut() {
+                sink.heartbeat(time)?;
+            }
+            last_heartbeat = Instant::now();
+        }
+        let Some(mut packet) = received else {
+            continue;
+        };
+
+        let quality = FrameQuality::classify(&packet.payload, fcs == FcsMode::Validate);
+        stats.lock().unwrap().quality.record(quality);
+        packet.payload = match fcs.apply(packet.payload) {
+            Some(p) => p,
+            None => {
+                stats.lock().unwrap().bad_fcs += 1;
+                continue;
+            }
+        };
+
+        for sink in sinks.iter_mut() {
+            sink.write_packet(&packet)?;
+        }
+        stats.lock().unwrap().record(&packet);
+
+        if last_flush.elapsed() >= flush_interval {
+            for sink in sinks.iter_mut() {
+                sink.flush()?;
+            }
+            last_flush = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+/// Writes the bare MPDUs, each preceded by its length as a little endian u16. This is
+/// the binary format read by the from-file command.
+pub struct RawSink<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> RawSink<W> {
+    pub fn new(w: W) -> Self {
+        RawSink {
+            writer: BufWriter::new(w),
+        }
+    }
+}
+
+impl<W: Write + Send> PacketSink for RawSink<W> {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+        self.writer
+            .write_u16::<LittleEndian>(packet.payload.len() as u16)?;
+        self.writer.write_all(&packet.payload)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn finish(&mut self) -> Result<(), WriterError> {
+        self.flush()
+    }
+}
+
+/// Writes every MPDU as a C array initializer, preceded by a comment with the metadata
+pub struct CArraySink<W: Write> {
+    writer: BufWriter<W>,
+    count: u64,
+}
+
+impl<W: Write> CArraySink<W> {
+    pub fn new(w: W) -> Self {
+        CArraySink {
+            writer: BufWriter::new(w),
+            count: 0,
+        }
+    }
+}
+
+impl<W: Write + Send> PacketSink for CArraySink<W> {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+        let w = &mut self.writer;
+        writeln!(
+            w,
+            "/* frame {}: {}.{:09} channel {} rssi {} lqi {} */",
+            self.count,
+            packet.timestamp.as_secs(),
+            packet.timestamp.subsec_nanos(),
+            packet.channel,
+            packet.rssi,
+            packet.lqi
+        )?;
+        writeln!(w, "const uint8_t frame_{}[] = {{", self.count)?;
+        for line in packet.payload.chunks(12) {
+            let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02x},", b)).collect();
+            writeln!(w, "    {}", bytes.join(" "))?;
+        }
+        writeln!(w, "}};")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn finish(&mut self) -> Result<(), WriterError> {
+        self.flush()
+    }
+}
+
+/// Context for the frames, written as the first line of JSON output like the section
+/// header of a pcapng file
+pub struct CaptureMeta {
+    pub device: String,
+    pub channel: u8,
+    /// Start of the capture since the UNIX epoch
+    pub started: Duration,
+    pub capture_id: Option<String>,
+}
+
+/// Writes a `"type":"meta"` line with the `CaptureMeta`, then a `"type":"frame"` line for
+/// every packet with the MPDU in hex
+pub struct JsonSink<W: Write> {
+    writer: BufWriter<Encoder<W>>,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(w: W, meta: &CaptureMeta) -> Result<Self, WriterError> {
+        JsonSink::compressed(w, meta, Compression::None)
+    }
+
+    /// Like `new`, compresses the lines. The stream is ended by `finish`.
+    pub fn compressed(
+        w: W,
+        meta: &CaptureMeta,
+        compression: Compression,
+    ) -> Result<Self, WriterError> {
+        let mut writer = BufWriter::new(Encoder::new(w, compression)?);
+        write!(
+            writer,
+            "{{\"type\":\"meta\",\"tool\":\"{}\",\"version\":\"{}\",\"device\":{},\"channel\":{},\"start\":{:.3}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            json_string(&meta.device),
+            meta.channel,
+            meta.started.as_secs_f64()
+        )?;
+        if let Some(id) = &meta.capture_id {
+            write!(writer, ",\"capture_id\":{}", json_string(id))?;
+        }
+        writeln!(writer, "}}")?;
+        Ok(JsonSink { writer })
+    }
+}
+
+impl<W: Write + Send> PacketSink for JsonSink<W> {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+        let payload: String = packet
+            .payload
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        writeln!(
+            self.writer,
+            "{{\"type\":\"frame\",\"timestamp\":{}.{:09},\"channel\":{},\"rssi\":{},\"lqi\":{},\"payload\":\"{}\"}}",
+            packet.timestamp.as_secs(),
+            packet.timestamp.subsec_nanos(),
+            packet.channel,
+            packet.rssi,
+            packet.lqi,
+            payload
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Writes a `"type":"heartbeat"` line and flushes it, so a reader following the file
+    /// sees it
+    fn heartbeat(&mut self, time: Duration) -> Result<(), WriterError> {
+        writeln!(
+            self.writer,
+            "{{\"type\":\"heartbeat\",\"timestamp\":{}.{:09}}}",
+            time.as_secs(),
+            time.subsec_nanos()
+        )?;
+        self.flush()
+    }
+
+    fn finish(&mut self) -> Result<(), WriterError> {
+        self.flush()?;
+        Ok(self.writer.get_mut().finish()?)
+    }
+}
+
+/// Writes a header row and a row for every packet with the radio metadata, the frame type
+/// and addresses from the MAC header, and the MPDU in hex. The decoded columns are empty
+/// for frames the MAC header can't be parsed from.
+pub struct CsvSink<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(w: W) -> Result<Self, WriterError> {
+        let mut writer = BufWriter::new(w);
+        writeln!(
+            writer,
+            "timestamp,channel,rssi,lqi,length,frame_type,src,dst,payload"
+        )?;
+        Ok(CsvSink { writer })
+    }
+}
+
+impl<W: Write + Send> PacketSink for CsvSink<W> {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+        let (frame_type, src, dst) = match MacHeader::parse(&packet.payload) {
+            Ok(header) => (
+                header.frame_type.to_string(),
+                header.src.map(|a| a.to_string()).unwrap_or_default(),
+                header.dst.map(|a| a.to_string()).unwrap_or_default(),
+            ),
+            Err(_) => Default::default(),
+        };
+        let payload: String = packet
+            .payload
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        writeln!(
+            self.writer,
+            "{}.{:09},{},{},{},{},{},{},{},{}",
+            packet.timestamp.as_secs(),
+            packet.timestamp.subsec_nanos(),
+            packet.channel,
+            packet.rssi,
+            packet.lqi,
+            packet.payload.len(),
+            frame_type,
+            src,
+            dst,
+            payload
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn finish(&mut self) -> Result<(), WriterError> {
+        self.flush()
+    }
+}
+
+/// Holds the packets back until one matches the trigger expression, then writes the
+/// last `pretrigger` packets before it, the trigger and everything after it to the
+/// sinks. The packets from before the trigger are kept in memory, older ones are dropped.
+pub struct TriggerSink {
+    sinks: Vec<Box<dyn PacketSink>>,
+    trigger: Expression,
+    pretrigger: usize,
+    held: VecDeque<CapturedPacket>,
+    fired: bool,
+}
+
+impl TriggerSink {
+    pub fn new(sinks: Vec<Box<dyn PacketSink>>, trigger: Expression, pretrigger: usize) -> Self {
+        TriggerSink {
+            sinks,
+            trigger,
+            pretrigger,
+            held: VecDeque::with_capacity(pretrigger),
+            fired: false,
+        }
+    }
+
+    /// Whether a packet matched the trigger
+    pub fn fired(&self) -> bool {
+        self.fired
+    }
+}
+
+impl PacketSink for TriggerSink {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+        if !self.fired {
+            if !self.trigger.matches(packet) {
+                if self.pretrigger > 0 {
+                    if self.held.len() == self.pretrigger {
+                        self.held.pop_front();
+                    }
+                    self.held.push_back(packet.clone());
+                }
+                return Ok(());
+            }
+            self.fired = true;
+            for held in self.held.drain(..) {
+                for sink in self.sinks.iter_mut() {
+                    sink.write_packet(&held)?;
+                }
+            }
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.write_packet(packet)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), WriterError> {
+        for sink in self.sinks.iter_mut() {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Heartbeats aren't frames, they are written also before the trigger fired
+    fn heartbeat(&mut self, time: Duration) -> Result<(), WriterError> {
+        for sink in self.sinks.iter_mut() {
+            sink.heartbeat(time)?;
+        }
+        Ok(())
+    }
+
+    /// Finishes all sinks, the held packets are dropped when the trigger never fired
+    fn finish(&mut self) -> Result<(), WriterError> {
+        let mut finished = Ok(());
+        for sink in self.sinks.iter_mut() {
+            let result = sink.finish();
+            if finished.is_ok() {
+                finished = result;
+            }
+        }
+        finished
+    }
+}
+
+/// An in-memory file for the sinks, for tests and for programs that store the capture
+/// themselves. Clones share the bytes: one is given to the sink, which moves to the
+/// writer thread, and the other is read once the capture is finished.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl MemoryBuffer {
+    pub fn new() -> Self {
+        MemoryBuffer::default()
+    }
+
+    /// A copy of everything written so far. Sinks buffer, the bytes are complete after
+    /// `finish`.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for MemoryBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expression::Expression;
+    use crate::fcs::FcsMode;
+    use crate::packet::CapturedPacket;
+    use crate::queue::{self, OnFull};
+    use crate::replay::{read_frames, InputFormat};
+    use crate::sink::{
+        write_packets, write_packets_with_heartbeat, CArraySink, CaptureMeta, CsvSink, JsonSink,
+        MemoryBuffer, PacketSink, RawSink, TriggerSink, DEFAULT_FLUSH_INTERVAL,
+    };
+    use crate::stats::CaptureStats;
+    use crate::writer::WriterError;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn packet(payload: &[u8]) -> CapturedPacket {
+        CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 13,
+            rssi: -45,
+            lqi: 120,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let (tx, rx) = mpsc::channel();
+        tx.send(packet(&[0x02, 0x00, 0x2a, 0xe0, 0x3b])).unwrap();
+        tx.send(packet(&[0x02, 0x00, 0x2a, 0x00, 0x00])).unwrap();
+        drop(tx);
+
+        let mut data = vec![];
+        let mut raw = RawSink::new(&mut data);
+        let stats = Mutex::default();
+        write_packets(
+            rx,
+            FcsMode::Validate,
+            &mut [&mut raw],
+            &stats,
+            DEFAULT_FLUSH_INTERVAL,
+        )
+        .unwrap();
+        drop(raw);
+        let stats: CaptureStats = stats.into_inner().unwrap();
+
+        assert_eq!(stats.packets, 1);
+        assert_eq!(stats.bad_fcs, 1);
+        assert_eq!(stats.quality.valid, 1);
+        assert_eq!(stats.quality.malformed, 1);
+        assert_eq!(data, [5, 0, 0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+        let frames = read_frames(data.as_slice(), InputFormat::Binary).unwrap();
+        assert_eq!(frames, [[0x02, 0x00, 0x2a, 0xe0, 0x3b]]);
+    }
+
+    /// Counts the flushes
+    #[derive(Default)]
+    struct Flushes(u64);
+
+    impl PacketSink for Flushes {
+        fn write_packet(&mut self, _packet: &CapturedPacket) -> Result<(), WriterError> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), WriterError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), WriterError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_interval() {
+        let packets = || (0..3).map(|_| packet(&[0x02, 0x00, 0x2a, 0xe0, 0x3b]));
+        let stats = Mutex::default();
+        let mut sink = Flushes::default();
+        let interval = Duration::ZERO;
+        write_packets(packets(), FcsMode::Keep, &mut [&mut sink], &stats, interval).unwrap();
+        assert_eq!(sink.0, 3);
+
+        let mut sink = Flushes::default();
+        let interval = Duration::from_secs(3600);
+        write_packets(packets(), FcsMode::Keep, &mut [&mut sink], &stats, interval).unwrap();
+        assert_eq!(sink.0, 0);
+    }
+
+    /// Counts the heartbeats and the packets
+    #[derive(Default)]
+    struct Heartbeats {
+        heartbeats: u64,
+        packets: u64,
+    }
+
+    impl PacketSink for Heartbeats {
+        fn write_packet(&mut self, _packet: &CapturedPacket) -> Result<(), WriterError> {
+            self.packets += 1;
+            Ok(())
+        }
+
+        fn heartbeat(&mut self, _time: Duration) -> Result<(), WriterError> {
+            self.heartbeats += 1;
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), WriterError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn heartbeat_cadence() {
+        // A packet and then a quiet channel, for a bit over 4 heartbeat intervals
+        let (tx, rx) = queue::bounded(4, OnFull::Block);
+        let sender = thread::spawn(move || {
+            tx.send(packet(&[0x02, 0x00, 0x2a, 0xe0, 0x3b])).unwrap();
+            thread::sleep(Duration::from_millis(225));
+        });
+
+        let stats = Mutex::default();
+        let mut sink = Heartbeats::default();
+        let interval = Duration::from_millis(50);
+        write_packets_with_heartbeat(
+            rx,
+            FcsMode::Keep,
+            &mut [&mut sink],
+            &stats,
+            DEFAULT_FLUSH_INTERVAL,
+            interval,
+        )
+        .unwrap();
+        sender.join().unwrap();
+        // One every 50 ms, a busy machine can miss the last one
+        assert!((3..=4).contains(&sink.heartbeats), "{}", sink.heartbeats);
+        // Heartbeats aren't frames
+        assert_eq!(sink.packets, 1);
+        assert_eq!(stats.into_inner().unwrap().packets, 1);
+
+        // None without an interval
+        let (tx, rx) = queue::bounded(4, OnFull::Block);
+        drop(tx);
+        let mut sink = Heartbeats::default();
+        let stats = Mutex::default();
+        let flush = DEFAULT_FLUSH_INTERVAL;
+        write_packets_with_heartbeat(
+            rx,
+            FcsMode::Keep,
+            &mut [&mut sink],
+            &stats,
+            flush,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(sink.heartbeats, 0);
+    }
+
+    #[test]
+    fn raw_flushed_on_finish() {
+        let mut data = vec![];
+        let mut raw = RawSink::new(&mut data);
+        raw.write_packet(&packet(&[0xff])).unwrap();
+        raw.finish().unwrap();
+        assert_eq!(raw.writer.get_ref().as_slice(), [1, 0, 0xff]);
+    }
+
+    #[test]
+    fn carray() {
+        let mut data = vec![];
+        let mut carray = CArraySink::new(&mut data);
+        carray
+            .write_packet(&packet(&[0x02, 0x00, 0x2a, 0xe0, 0x3b]))
+            .unwrap();
+        carray.write_packet(&packet(&[0xaa; 13])).unwrap();
+        carray.finish().unwrap();
+        drop(carray);
+
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "/* frame 0: 0.000000000 channel 13 rssi -45 lqi 120 */\n\
+             const uint8_t frame_0[] = {\n\
+             \x20   0x02, 0x00, 0x2a, 0xe0, 0x3b,\n\
+             };\n\
+             /* frame 1: 0.000000000 channel 13 rssi -45 lqi 120 */\n\
+             const uint8_t frame_1[] = {\n\
+             \x20   0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,\n\
+             \x20   0xaa,\n\
+             };\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let meta = CaptureMeta {
+            device: "CC2531 USB Dongle".to_string(),
+            channel: 13,
+            started: Duration::from_millis(1_000_500),
+            capture_id: None,
+        };
+        let mut data = vec![];
+        let mut json = JsonSink::new(&mut data, &meta).unwrap();
+        json.write_packet(&packet(&[0x02, 0x00, 0x2a, 0xe0, 0x3b]))
+            .unwrap();
+        json.write_packet(&packet(&[0xff])).unwrap();
+        json.heartbeat(Duration::from_millis(1_002_000)).unwrap();
+        json.finish().unwrap();
+        drop(json);
+
+        let output = String::from_utf8(data).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            format!(
+                "{{\"type\":\"meta\",\"tool\":\"ccsniffer-rust\",\"version\":\"{}\",\
+                 \"device\":\"CC2531 USB Dongle\",\"channel\":13,\"start\":1000.500}}",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(
+            lines[1],
+            "{\"type\":\"frame\",\"timestamp\":0.000000000,\"channel\":13,\"rssi\":-45,\
+             \"lqi\":120,\"payload\":\"02002ae03b\"}"
+        );
+        assert_eq!(
+            lines[3],
+            "{\"type\":\"heartbeat\",\"timestamp\":1002.000000000}"
+        );
+        let meta_lines = lines.iter().filter(|l| l.contains("\"type\":\"meta\""));
+        assert_eq!(meta_lines.count(), 1);
+    }
+
+    /// The JSON lines for two packets, written with `compression`
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn json_output(compression: crate::compress::Compression) -> Vec<u8> {
+        let meta = CaptureMeta {
+            device: "CC2531 USB Dongle".to_string(),
+            channel: 13,
+            started: Duration::from_millis(1_000_500),
+            capture_id: None,
+        };
+        let mut data = vec![];
+        let mut json = JsonSink::compressed(&mut data, &meta, compression).unwrap();
+        json.write_packet(&packet(&[0x02, 0x00, 0x2a, 0xe0, 0x3b]))
+            .unwrap();
+        json.write_packet(&packet(&[0xff])).unwrap();
+        json.finish().unwrap();
+        drop(json);
+        data
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn json_gzip() {
+        use crate::compress::Compression;
+        use std::io::Read;
+
+        let compressed = json_output(Compression::Gzip);
+        assert_eq!(compressed[..2], [0x1f, 0x8b]);
+        let mut lines = vec![];
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut lines)
+            .unwrap();
+        assert_eq!(lines, json_output(Compression::None));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn json_zstd() {
+        use crate::compress::Compression;
+
+        let compressed = json_output(Compression::Zstd);
+        assert_eq!(compressed[..4], [0x28, 0xb5, 0x2f, 0xfd]);
+        let lines = zstd::decode_all(compressed.as_slice()).unwrap();
+        assert_eq!(lines, json_output(Compression::None));
+        assert_eq!(String::from_utf8(lines).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn pretrigger() {
+        // Data frames with sequence numbers 0 to 9, the trigger is the one with 6
+        let frames =
+            (0..10).map(|seq| packet(&[0x41, 0x88, seq, 0x34, 0x12, 0xff, 0xff, 0x01, 0x00, 0, 0]));
+        let buffer = MemoryBuffer::new();
+        let trigger: Expression = "seq==6".parse().unwrap();
+        let mut sink = TriggerSink::new(vec![Box::new(RawSink::new(buffer.clone()))], trigger, 3);
+        for frame in frames {
+            sink.write_packet(&frame).unwrap();
+        }
+        sink.finish().unwrap();
+        assert!(sink.fired());
+
+        let frames = read_frames(buffer.bytes().as_slice(), InputFormat::Binary).unwrap();
+        let seqs: Vec<u8> = frames.iter().map(|f| f[2]).collect();
+        assert_eq!(seqs, [3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn trigger_never_fires() {
+        let buffer = MemoryBuffer::new();
+        let trigger: Expression = "type==beacon".parse().unwrap();
+        let mut sink = TriggerSink::new(vec![Box::new(RawSink::new(buffer.clone()))], trigger, 3);
+        sink.write_packet(&packet(&[0x02, 0x00, 0x2a, 0xe0, 0x3b]))
+            .unwrap();
+        sink.finish().unwrap();
+        assert!(!sink.fired());
+        assert!(buffer.bytes().is_empty());
+    }
+
+    #[test]
+    fn csv() {
+        let mut data = vec![];
+        let mut csv = CsvSink::new(&mut data).unwrap();
+        // Data frame 0xABCD -> 0x5678 on PAN 0x1234
+        let frame = [
+            0x61, 0x88, 0x2b, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0x00, 0x00,
+        ];
+        csv.write_packet(&packet(&frame)).unwrap();
+        csv.write_packet(&packet(&[0xff])).unwrap();
+        csv.finish().unwrap();
+        drop(csv);
+
+        let output = String::from_utf8(data).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "timestamp,channel,rssi,lqi,length,frame_type,src,dst,payload",
+                "0.000000000,13,-45,120,11,DATA,0xABCD,0x5678,61882b34127856cdab0000",
+                "0.000000000,13,-45,120,1,,,,ff",
+            ]
+        );
+    }
+}
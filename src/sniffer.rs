@@ -1,17 +1,24 @@
+use crate::fcs::FCS_LEN;
+use crate::protocol::{calculate_crc, Message, ProtocolError};
 use hxdmp::hexdump;
 use rusb::Direction::{In, Out};
 use rusb::{
-    Device, DeviceDescriptor, DeviceHandle, DeviceList, Direction, EndpointDescriptor,
-    GlobalContext, InterfaceDescriptor,
+    ConfigDescriptor, Device, DeviceHandle, DeviceList, Direction, GlobalContext,
+    InterfaceDescriptor, TransferType,
 };
 use std::fmt::Debug;
+use std::io::ErrorKind::{Other, TimedOut};
+use std::io::{Error, Read, Write};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use std::{error, fmt};
-use std::io::{Error, Read, Write};
-use std::io::ErrorKind::{Other, TimedOut};
 
 #[repr(u8)]
-#[derive(PartialEq, PartialOrd, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum CmdCodes {
     CmdInit = 0x00,
     CmdInitAck = 0x01,
@@ -29,7 +36,7 @@ pub enum CmdCodes {
 
 impl From<u8> for CmdCodes {
     fn from(orig: u8) -> Self {
-        return match orig {
+        match orig {
             0x00 => CmdCodes::CmdInit,
             0x01 => CmdCodes::CmdInitAck,
             0x02 => CmdCodes::CmdSetChannel,
@@ -42,36 +49,197 @@ impl From<u8> for CmdCodes {
             0x09 => CmdCodes::CmdSniffOffAck,
             0x0A => CmdCodes::CmdGotPkt,
             0xFF => CmdCodes::CmdErr,
-            _ => return CmdCodes::CmdErr,
-        };
+            _ => CmdCodes::CmdErr,
+        }
+    }
+}
+
+/// The USB operations used to talk to the sniffer, implemented for a real device handle
+/// and by fakes in the tests
+pub trait Transport: Send {
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+}
+
+impl Transport for DeviceHandle<GlobalContext> {
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout)
     }
 }
 
 pub struct SnifferDevice {
-    handle: DeviceHandle<GlobalContext>,
-    descriptor: DeviceDescriptor,
+    handle: Box<dyn Transport>,
+    product_name: Option<String>,
     out_address: u8,
     in_address: u8,
     debug: bool,
     timeout: Duration,
+    checksum_seed: u8,
+    strict: bool,
+    /// Set when a packet with differing length fields was returned, see `take_length_mismatch`
+    length_mismatch: AtomicBool,
+    max_frame_len: usize,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+/// How far the startup command sequence got
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceState {
+    pub initialized: bool,
+    /// The channel the sniffer acknowledged
+    pub channel: Option<u8>,
+    pub sniffing: bool,
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.initialized {
+            return write!(f, "not initialized");
+        }
+        write!(f, "initialized")?;
+        if let Some(channel) = self.channel {
+            write!(f, ", channel {}", channel)?;
+        }
+        if self.sniffing {
+            write!(f, ", sniffing")?;
+        }
+        Ok(())
+    }
+}
+
+/// A step of opening the device, to tell where the setup failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetupStage {
+    Open,
+    ReadDescriptors,
+    SetConfiguration,
+    DetachDriver,
+    ClaimInterface,
+}
+
+impl fmt::Display for SetupStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetupStage::Open => write!(f, "open the device"),
+            SetupStage::ReadDescriptors => write!(f, "read the device descriptor"),
+            SetupStage::SetConfiguration => write!(f, "set the usb configuration"),
+            SetupStage::DetachDriver => write!(f, "detach the kernel driver"),
+            SetupStage::ClaimInterface => write!(f, "claim the interface"),
+        }
+    }
+}
+
+/// USB settings for opening the sniffer, the ones left at `None` are picked automatically
+#[derive(Clone, Debug, Default)]
+pub struct UsbOptions {
+    /// Index of the configuration
+    pub config: Option<u8>,
+    /// Address of the bulk endpoint to read from, e.g. 0x83
+    pub in_endpoint: Option<u8>,
+    /// Address of the bulk endpoint to write to, e.g. 0x04
+    pub out_endpoint: Option<u8>,
+    /// When the interface is busy, detach the kernel driver holding it and claim it again
+    pub force: bool,
 }
 
 #[derive(Debug)]
 pub enum SnifferError {
+    AccessDenied(u16, u16),
+    BadEndpoint(u8, &'static str),
+    Bootloader(u16, u16),
+    Busy(u16, u16),
     DeviceError,
+    Disconnected,
+    FrameTooLong(usize),
+    NoUsbDevices,
+    NotFound(u16, u16),
+    NameNotFound(String),
+    NoBulkEndpoints,
+    /// The raw command code, unknown codes included
+    NonPacketFrame(u8),
+    NotASniffer(Box<SnifferError>),
     ProtocolError(&'static str),
+    ReconfigureFailed(DeviceState, Box<SnifferError>),
+    SetupFailed(SetupStage, rusb::Error),
+    /// The out endpoint took fewer bytes of a command than it has: written, expected
+    ShortWrite(usize, usize),
+    StartFailed(DeviceState, Box<SnifferError>),
     TimeOut,
+    UnsupportedVersion(u8),
     UsbError(rusb::Error),
 }
 
 impl fmt::Display for SnifferError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &*self {
+        match self {
+            SnifferError::AccessDenied(vendor, product) => {
+                write!(
+                    f,
+                    "access denied to usb device {:04x}:{:04x}",
+                    vendor, product
+                )
+            }
+            SnifferError::BadEndpoint(address, problem) => {
+                write!(f, "endpoint {:#04x} {}", address, problem)
+            }
+            SnifferError::Bootloader(vendor, product) => write!(
+                f,
+                "the device {:04x}:{:04x} is in bootloader mode, it has no sniffer firmware",
+                vendor, product
+            ),
+            SnifferError::Busy(vendor, product) => write!(
+                f,
+                "the interface of usb device {:04x}:{:04x} is claimed by another program",
+                vendor, product
+            ),
             SnifferError::DeviceError => write!(f, "module error in the sniffer module"),
+            SnifferError::Disconnected => write!(f, "the sniffer was disconnected"),
+            SnifferError::FrameTooLong(len) => {
+                write!(f, "frame of {} bytes is longer than the maximum", len)
+            }
+            SnifferError::NoUsbDevices => write!(f, "no usb devices visible, check usb access"),
+            SnifferError::NotFound(vendor, product) => {
+                write!(f, "no sniffer {:04x}:{:04x} found", vendor, product)
+            }
+            SnifferError::NameNotFound(name) => {
+                write!(f, "no usb device with \"{}\" in its product string", name)
+            }
+            SnifferError::NoBulkEndpoints => {
+                write!(f, "no usb configuration with bulk in and out endpoints")
+            }
+            SnifferError::NonPacketFrame(code) => {
+                write!(f, "unexpected frame with command code {:#04x}", code)
+            }
+            SnifferError::NotASniffer(e) => {
+                write!(f, "the device doesn't answer like a sniffer: {}", e)
+            }
             SnifferError::ProtocolError(detail) => write!(f, "protocol error: {}", detail),
+            SnifferError::ReconfigureFailed(state, e) => {
+                write!(f, "channel change failed: {} (sniffer {})", e, state)
+            }
+            SnifferError::SetupFailed(stage, e) => write!(f, "can't {}: {}", stage, e),
+            SnifferError::ShortWrite(written, expected) => write!(
+                f,
+                "the sniffer took {} of the {} bytes of a command",
+                written, expected
+            ),
+            SnifferError::StartFailed(state, e) => {
+                write!(f, "startup failed: {} (sniffer {})", e, state)
+            }
             SnifferError::TimeOut => write!(f, "time out"),
+            SnifferError::UnsupportedVersion(version) => write!(
+                f,
+                "firmware protocol version {} is outside the tested versions {}-{}",
+                version,
+                PROTOCOL_VERSIONS.start(),
+                PROTOCOL_VERSIONS.end()
+            ),
             SnifferError::UsbError(e) => {
-                write!(f, "usb error: {}", e.to_string())
+                write!(f, "usb error: {}", e)
             }
         }
     }
@@ -79,7 +247,17 @@ impl fmt::Display for SnifferError {
 
 impl From<rusb::Error> for SnifferError {
     fn from(e: rusb::Error) -> Self {
-        return SnifferError::UsbError(e);
+        SnifferError::UsbError(e)
+    }
+}
+
+impl From<ProtocolError> for SnifferError {
+    fn from(e: ProtocolError) -> Self {
+        SnifferError::ProtocolError(match e {
+            ProtocolError::Empty => "empty read",
+            ProtocolError::Short => "short frame",
+            ProtocolError::SizeMismatch(_, _) => "size mismatch",
+        })
     }
 }
 
@@ -87,62 +265,278 @@ impl error::Error for SnifferError {}
 
 impl SnifferDevice {
     pub fn new(device: Device<GlobalContext>) -> Result<SnifferDevice, Box<dyn error::Error>> {
-        let mut handle = device.open()?;
-        let descriptor = device.device_descriptor()?;
+        SnifferDevice::with_config(device, None)
+    }
+
+    /// Opens the device in the configuration at `config_index`, or by default the active
+    /// configuration if it has the sniffer endpoints and else the first one that has them
+    pub fn with_config(
+        device: Device<GlobalContext>,
+        config_index: Option<u8>,
+    ) -> Result<SnifferDevice, Box<dyn error::Error>> {
+        let options = UsbOptions {
+            config: config_index,
+            ..Default::default()
+        };
+        SnifferDevice::with_options(device, &options)
+    }
+
+    /// Like `with_config`, with the endpoints given in `options` instead of the first bulk
+    /// endpoint in each direction
+    pub fn with_options(
+        device: Device<GlobalContext>,
+        options: &UsbOptions,
+    ) -> Result<SnifferDevice, Box<dyn error::Error>> {
+        let setup = |stage| move |e| SnifferError::SetupFailed(stage, e);
 
-        handle.claim_interface(0)?;
+        let descriptor = device
+            .device_descriptor()
+            .map_err(setup(SetupStage::ReadDescriptors))?;
+        let mut handle = device.open().map_err(|e| match e {
+            rusb::Error::Access => {
+                SnifferError::AccessDenied(descriptor.vendor_id(), descriptor.product_id())
+            }
+            _ => SnifferError::SetupFailed(SetupStage::Open, e),
+        })?;
 
-        let config_desc = device.active_config_descriptor()?;
+        let configs: Vec<ConfigDescriptor> = (0..descriptor.num_configurations())
+            .filter_map(|i| device.config_descriptor(i).ok())
+            .collect();
+        let usable: Vec<(u8, bool)> = configs
+            .iter()
+            .map(|c| (c.number(), has_bulk_endpoints(c)))
+            .collect();
+        let active = handle.active_configuration().ok();
+        let config_desc = &configs[select_config(&usable, active, options.config)?];
+        if active != Some(config_desc.number()) {
+            handle
+                .set_active_configuration(config_desc.number())
+                .map_err(setup(SetupStage::SetConfiguration))?;
+        }
+
+        // Only some platforms can detach a kernel driver, elsewhere there is none to detach
+        match handle.set_auto_detach_kernel_driver(true) {
+            Ok(()) | Err(rusb::Error::NotSupported) => {}
+            Err(e) => return Err(Box::new(setup(SetupStage::DetachDriver)(e))),
+        }
 
         // Should have one interface
         let interface = config_desc.interfaces().next().unwrap();
-        let interface_descriptor = interface.descriptors().next().unwrap();
-        let in_endpoint = find_first_endpoint(&interface_descriptor, In)?;
-        let out_endpoint = find_first_endpoint(&interface_descriptor, Out)?;
+        let mut claimed = handle.claim_interface(interface.number());
+        if claimed == Err(rusb::Error::Busy) && options.force {
+            // Only a kernel driver can be detached, a claim by another process stays
+            match handle.detach_kernel_driver(interface.number()) {
+                Ok(()) => claimed = handle.claim_interface(interface.number()),
+                Err(rusb::Error::NotFound) | Err(rusb::Error::NotSupported) => {}
+                Err(e) => return Err(Box::new(setup(SetupStage::DetachDriver)(e))),
+            }
+        }
+        claimed.map_err(|e| claim_error(e, descriptor.vendor_id(), descriptor.product_id()))?;
+        let endpoints = endpoints(&interface.descriptors().next().unwrap());
+        let in_address = select_endpoint(&endpoints, In, options.in_endpoint)?;
+        let out_address = select_endpoint(&endpoints, Out, options.out_endpoint)?;
+
+        let product_name = handle.read_product_string_ascii(&descriptor).ok();
 
-        return Ok(SnifferDevice {
+        Ok(SnifferDevice::from_transport(
+            Box::new(handle),
+            product_name,
+            in_address,
+            out_address,
+        ))
+    }
+
+    /// Creates a sniffer that talks over the given transport instead of a USB device
+    pub fn from_transport(
+        handle: Box<dyn Transport>,
+        product_name: Option<String>,
+        in_address: u8,
+        out_address: u8,
+    ) -> SnifferDevice {
+        SnifferDevice {
             handle,
-            descriptor,
-            out_address: out_endpoint.address(),
-            in_address: in_endpoint.address(),
+            product_name,
+            out_address,
+            in_address,
             debug: false,
-            timeout: Duration::from_millis(250)
+            timeout: Duration::from_millis(250),
+            checksum_seed: DEFAULT_CHECKSUM_SEED,
+            strict: false,
+            length_mismatch: AtomicBool::new(false),
+            max_frame_len: MAX_FRAME_LEN,
+            stop: None,
+        }
+    }
+
+    pub fn find_device(vendor: u16, product: u16) -> Result<Device<GlobalContext>, SnifferError> {
+        let devices = SnifferDevice::find_devices(vendor, product)?;
+        Ok(devices.into_iter().next().unwrap())
+    }
+
+    /// Every attached device with the given ids, for capturing with several sticks at once
+    pub fn find_devices(
+        vendor: u16,
+        product: u16,
+    ) -> Result<Vec<Device<GlobalContext>>, SnifferError> {
+        // Without USB access (e.g. in a container) libusb can't initialise, which makes the
+        // global context panic, or the device list is empty
+        rusb::Context::new().map_err(|_| SnifferError::NoUsbDevices)?;
+        let list = DeviceList::new().map_err(|_| SnifferError::NoUsbDevices)?;
+        let devices = list.iter().filter_map(|d| {
+            let device_desc = d.device_descriptor().ok()?;
+            Some((d, device_desc.vendor_id(), device_desc.product_id()))
         });
+        select_devices(devices, vendor, product)
     }
 
-    pub fn find_device(vendor: u16, product: u16) -> Option<Device<GlobalContext>> {
-        DeviceList::new().unwrap().iter().find_map(|d| {
-            let device_desc = d.device_descriptor().expect(&*format!(
-                "Failed to get device descriptor for device {}:{}:{}",
-                d.bus_number(),
-                d.port_number(),
-                d.address()
-            ));
+    /// Every attached device whose product string contains `name`, for sticks whose ids
+    /// vary across a fleet. Devices that can't be opened to read the string are skipped.
+    pub fn find_devices_named(name: &str) -> Result<Vec<Device<GlobalContext>>, SnifferError> {
+        rusb::Context::new().map_err(|_| SnifferError::NoUsbDevices)?;
+        let list = DeviceList::new().map_err(|_| SnifferError::NoUsbDevices)?;
+        let devices = list.iter().map(|d| {
+            let product = product_string(&d);
+            (d, product)
+        });
+        select_named(devices, name)
+    }
 
-            if device_desc.vendor_id() == vendor && device_desc.product_id() == product {
-                return Some(d);
+    /// Lists the configurations, interfaces, alternate settings and endpoints of the device,
+    /// to diagnose sticks that `new` can't open. Only reads descriptors, the device isn't
+    /// opened.
+    pub fn describe(device: &Device<GlobalContext>) -> Result<String, SnifferError> {
+        let descriptor = device.device_descriptor()?;
+        let active = device.active_config_descriptor().ok().map(|c| c.number());
+
+        let mut out = format!(
+            "Bus {:03} Device {:03}: {:04x}:{:04x}, {} configuration(s)\n",
+            device.bus_number(),
+            device.address(),
+            descriptor.vendor_id(),
+            descriptor.product_id(),
+            descriptor.num_configurations()
+        );
+        for i in 0..descriptor.num_configurations() {
+            let config = match device.config_descriptor(i) {
+                Ok(c) => c,
+                Err(e) => {
+                    out.push_str(&format!("  configuration index {}: {}\n", i, e));
+                    continue;
+                }
+            };
+            let marker = if active == Some(config.number()) {
+                " (active)"
+            } else {
+                ""
+            };
+            let usable = if has_bulk_endpoints(&config) {
+                "yes"
+            } else {
+                "no"
+            };
+            out.push_str(&format!(
+                "  configuration {}{}, sniffer endpoints: {}\n",
+                config.number(),
+                marker,
+                usable
+            ));
+            for interface in config.interfaces() {
+                for alt in interface.descriptors() {
+                    out.push_str(&format!(
+                        "    interface {} alt {}, class {:02x}\n",
+                        alt.interface_number(),
+                        alt.setting_number(),
+                        alt.class_code()
+                    ));
+                    for endpoint in alt.endpoint_descriptors() {
+                        out.push_str(&format!(
+                            "      {}\n",
+                            describe_endpoint(
+                                endpoint.address(),
+                                endpoint.direction(),
+                                endpoint.transfer_type(),
+                                endpoint.max_packet_size()
+                            )
+                        ));
+                    }
+                }
             }
-            return None;
-        })
+        }
+        Ok(out)
     }
 
     pub fn get_product_name(&self) -> Option<String> {
-        match self.handle.read_product_string_ascii(&self.descriptor) {
-            Ok(n) => Some(n),
-            Err(_e) => None,
-        }
+        self.product_name.clone()
     }
 
     pub fn send_command(&self, command: CmdCodes, payload: &[u8]) -> Result<(), SnifferError> {
-        let mut buffer = vec![];
-
-        let payload_len = payload.len();
         let ack: CmdCodes = (command as u8 + 1).into(); // hack, ack is command + 1 in the enum
+        let response = self.exchange(command as u8, payload)?;
+        if Message::from_slice(&response, false)?.code != ack as u8 {
+            return Err(SnifferError::ProtocolError("unexpected response code"));
+        }
+        Ok(())
+    }
 
-        buffer.push((3 + payload_len) as u8); // length
-        buffer.push(command as u8); // command
-        buffer.append(&mut payload.to_vec());
-        buffer.push(calculate_crc(buffer.as_slice(), payload_len + 2)); //checksum
+    /// Asks the firmware to transmit `frame` on the current channel. The frame is the MPDU
+    /// without the FCS, the radio appends it.
+    pub fn send_packet(&self, frame: &[u8]) -> Result<(), SnifferError> {
+        if frame.len() > MAX_SEND_LEN {
+            return Err(SnifferError::ProtocolError("frame too long to send"));
+        }
+        self.send_command(CmdCodes::CmdSendPkt, frame)
+    }
+
+    /// Checks that the device answers init with a well formed ack, so an unrelated device
+    /// with the same USB ids is rejected before the capture starts. Returns the protocol
+    /// version in the ack, None for firmwares that send an empty one.
+    pub fn verify(&self) -> Result<Option<u8>, SnifferError> {
+        let not_a_sniffer = |e| SnifferError::NotASniffer(Box::new(e));
+        // A frame left over from an earlier run would be taken for the answer
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        for _ in 0..MAX_DRAIN_FRAMES {
+            match self.receive_queued_into(&mut buffer) {
+                Ok(_) | Err(SnifferError::NonPacketFrame(_)) => {}
+                Err(SnifferError::TimeOut) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let response = self
+            .exchange(CmdCodes::CmdInit as u8, &[])
+            .map_err(not_a_sniffer)?;
+
+        let message = Message::from_slice(&response, true).map_err(|e| not_a_sniffer(e.into()))?;
+        let n = response.len();
+        let problem = if message.length as usize != n {
+            Some("malformed init ack")
+        } else if message.code != CmdCodes::CmdInitAck as u8 {
+            Some("unexpected response code")
+        } else if response[n - 1] != calculate_crc(&response, n - 1, self.checksum_seed) {
+            Some("bad init ack checksum")
+        } else {
+            None
+        };
+        match problem {
+            Some(detail) => Err(not_a_sniffer(SnifferError::ProtocolError(detail))),
+            None => Ok(message.body.first().copied()),
+        }
+    }
+
+    /// Sends any command code with `payload` and returns the response frame as read,
+    /// without checking it, for experimenting with the firmware
+    pub fn raw_command(&self, code: u8, payload: &[u8]) -> Result<Vec<u8>, SnifferError> {
+        if payload.len() > MAX_COMMAND_PAYLOAD {
+            return Err(SnifferError::ProtocolError(
+                "payload too long for the length field",
+            ));
+        }
+        self.exchange(code, payload)
+    }
+
+    /// Sends a command and returns the response frame as read. The whole frame is written,
+    /// up to and including the checksum after the payload.
+    fn exchange(&self, code: u8, payload: &[u8]) -> Result<Vec<u8>, SnifferError> {
+        let buffer = Message::new(code, payload).to_bytes(self.checksum_seed);
 
         if self.debug {
             dump(buffer.as_slice(), buffer.len());
@@ -150,15 +544,11 @@ impl SnifferDevice {
 
         let bytes_written = self
             .handle
-            .write_bulk(
-                self.out_address,
-                &buffer[0..buffer[0] as usize],
-                Duration::from_millis(250),
-            )
-            .or_else(|e| return Err(SnifferError::UsbError(e)))?;
+            .write_bulk(self.out_address, &buffer, Duration::from_millis(250))
+            .map_err(transfer_error)?;
 
         if bytes_written != buffer.len() {
-            return Err(SnifferError::DeviceError);
+            return Err(SnifferError::ShortWrite(bytes_written, buffer.len()));
         }
 
         let mut read_buffer = vec![0; 256];
@@ -177,26 +567,44 @@ impl SnifferDevice {
                     // Byte extra for total length
                 }
 
-                if read_buffer[2] != ack as u8 {
-                    return Err(SnifferError::ProtocolError("unexpected response code"));
-                }
-
-                Ok(())
+                read_buffer.truncate(n);
+                Ok(read_buffer)
             }
-            Err(e) => Err(SnifferError::UsbError(e)),
+            Err(e) => Err(transfer_error(e)),
         }
     }
 
     pub fn receive_packet(&self) -> Result<Vec<u8>, SnifferError> {
-        let mut buffer = vec![0; 256];
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        self.receive_packet_into(&mut buffer)
+            .map(|data| data.to_vec())
+    }
 
-        let read_result = self.handle.read_bulk(
-            self.in_address,
-            buffer.as_mut_slice(),
-            Duration::from_millis(1000),
-        );
+    /// Like `receive_packet`, but reads into `buffer` and returns the packet data as a
+    /// slice of it instead of copying it out. The buffer must hold `READ_BUFFER_SIZE`
+    /// bytes to fit the largest frame.
+    pub fn receive_packet_into<'b>(&self, buffer: &'b mut [u8]) -> Result<&'b [u8], SnifferError> {
+        let read = self.read_frame(buffer);
+        self.packet_data(buffer, read)
+    }
+
+    /// Like `receive_packet_into`, for the frames the sniffer queued before it stopped
+    /// sniffing. Waits only `DRAIN_TIMEOUT` and ignores the stop flag, so they can still
+    /// be collected after a stop.
+    pub fn receive_queued_into<'b>(&self, buffer: &'b mut [u8]) -> Result<&'b [u8], SnifferError> {
+        let read = self
+            .handle
+            .read_bulk(self.in_address, buffer, DRAIN_TIMEOUT);
+        self.packet_data(buffer, read)
+    }
 
-        match read_result {
+    /// Checks the frame read into `buffer` and returns the packet data in it
+    fn packet_data<'b>(
+        &self,
+        buffer: &'b [u8],
+        read: rusb::Result<usize>,
+    ) -> Result<&'b [u8], SnifferError> {
+        match read {
             Ok(n) => {
                 // We should have received data in the following format
                 // [0] = USB data size
@@ -207,49 +615,151 @@ impl SnifferDevice {
                 // [..] = Raw packet
                 // [len-1] = Checksum - last byte is a checksum
 
-                if n == 0 {
-                    return Err(SnifferError::ProtocolError("empty read"));
-                }
-
-                if buffer[0] != buffer[1] {
-                    // Shouldn't happen with my version of the firmware
-                    return Err(SnifferError::ProtocolError("size mismatch"));
-                }
+                let frame: &'b [u8] = &buffer[..n];
+                let message = Message::from_slice(frame, self.strict)?;
 
                 if self.debug {
-                    dump(buffer.as_slice(), buffer[0] as usize);
+                    dump(frame, n);
                 }
 
-                if buffer[2] != CmdCodes::CmdGotPkt as u8 {
-                    println!("Unexpected result {:#04x}", buffer[2]);
-                    return Err(SnifferError::ProtocolError("Unexpected command code"));
+                if message.code != CmdCodes::CmdGotPkt as u8 {
+                    // Async acks and other non-packet frames can be interleaved with packets
+                    return Err(SnifferError::NonPacketFrame(message.code));
                 }
-
-                buffer.drain((n - 1)..); // Drop the unused part
-                buffer.drain(..3); // Drop the metadata
-                Ok(buffer)
+                // The RSSI and the LQI come before the frame
+                let frame_len = message.body.len().saturating_sub(2);
+                if frame_len > self.max_frame_len {
+                    return Err(SnifferError::FrameTooLong(frame_len));
+                }
+                if frame[0] != frame[1] {
+                    // Doesn't happen with the tested PROTOCOL_VERSIONS, but one of the
+                    // lengths is usually right. Only the lenient parse gets here with both.
+                    self.length_mismatch.store(true, Ordering::Relaxed);
+                }
+                // The body always borrows the frame, the lengths are at [0] and [1] and the
+                // code at [2]
+                Ok(&frame[3..3 + message.body.len()])
             }
             Err(e) => match e {
                 rusb::Error::Timeout => Err(SnifferError::TimeOut),
-                _ => Err(e.into()),
+                _ => Err(transfer_error(e)),
             },
         }
     }
 
+    /// Sends init, set channel and sniff on, waiting `delay` between them. Returns the
+    /// resulting state, or the error with the state reached before it.
+    pub fn start_sniffing(
+        &self,
+        channel: u8,
+        delay: Duration,
+    ) -> Result<DeviceState, SnifferError> {
+        let mut state = DeviceState::default();
+        let failed = |state, e| SnifferError::StartFailed(state, Box::new(e));
+
+        self.send_command(CmdCodes::CmdInit, &[])
+            .map_err(|e| failed(state, e))?;
+        state.initialized = true;
+        thread::sleep(delay);
+        self.send_command(CmdCodes::CmdSetChannel, &[channel])
+            .map_err(|e| failed(state, e))?;
+        state.channel = Some(channel);
+        thread::sleep(delay);
+        self.send_command(CmdCodes::CmdSniffOn, &[])
+            .map_err(|e| failed(state, e))?;
+        state.sniffing = true;
+        Ok(state)
+    }
+
+    /// Moves a sniffing device to another channel with sniff off, set channel and sniff on,
+    /// so the radio isn't retuned while it is receiving. Returns the resulting state, or
+    /// the error with the state reached before it.
+    pub fn reconfigure_channel(&self, channel: u8) -> Result<DeviceState, SnifferError> {
+        let mut state = DeviceState {
+            initialized: true,
+            channel: None,
+            sniffing: true,
+        };
+        let failed = |state, e| SnifferError::ReconfigureFailed(state, Box::new(e));
+
+        self.send_command(CmdCodes::CmdSniffOff, &[])
+            .map_err(|e| failed(state, e))?;
+        state.sniffing = false;
+        self.send_command(CmdCodes::CmdSetChannel, &[channel])
+            .map_err(|e| failed(state, e))?;
+        state.channel = Some(channel);
+        self.send_command(CmdCodes::CmdSniffOn, &[])
+            .map_err(|e| failed(state, e))?;
+        state.sniffing = true;
+        Ok(state)
+    }
+
+    /// Waits up to `RECEIVE_TIMEOUT` for a frame. With a stop flag the wait is split into
+    /// `STOP_POLL_INTERVAL` reads, so a stop is noticed without waiting out the timeout.
+    fn read_frame(&self, buffer: &mut [u8]) -> rusb::Result<usize> {
+        let Some(stop) = &self.stop else {
+            return self
+                .handle
+                .read_bulk(self.in_address, buffer, RECEIVE_TIMEOUT);
+        };
+
+        let mut waited = Duration::ZERO;
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                return Err(rusb::Error::Timeout);
+            }
+            match self
+                .handle
+                .read_bulk(self.in_address, buffer, STOP_POLL_INTERVAL)
+            {
+                Err(rusb::Error::Timeout) if waited + STOP_POLL_INTERVAL < RECEIVE_TIMEOUT => {
+                    waited += STOP_POLL_INTERVAL
+                }
+                result => return result,
+            }
+        }
+    }
+
     pub fn set_debug(&mut self) {
         self.debug = true;
     }
+
+    /// Rejects frames where the two length fields differ, instead of using the smaller one
+    pub fn set_strict(&mut self) {
+        self.strict = true;
+    }
+
+    /// Whether a packet returned since the last call had differing length fields. Frames
+    /// that are rejected or aren't packets don't count.
+    pub fn take_length_mismatch(&self) -> bool {
+        self.length_mismatch.swap(false, Ordering::Relaxed)
+    }
+
+    /// Rejects packets longer than `len` bytes with `FrameTooLong`, by default
+    /// `MAX_FRAME_LEN` as a longer one means the framing went wrong
+    pub fn set_max_frame_len(&mut self, len: usize) {
+        self.max_frame_len = len;
+    }
+
+    /// Sets the initial value of the XOR checksum for firmwares that don't use 0xff
+    pub fn set_checksum_seed(&mut self, seed: u8) {
+        self.checksum_seed = seed;
+    }
+
+    /// Makes `receive_packet` return `TimeOut` soon after `stop` is set, instead of after
+    /// the full receive timeout
+    pub fn set_stop_flag(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
 }
 
 impl Write for SnifferDevice {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.handle
             .write_bulk(self.out_address, buf, self.timeout)
-            .map_err(|e| {
-                match e {
-                    rusb::Error::Timeout => Error::new(TimedOut, e),
-                _ => Error::new(Other, e)
-                }
+            .map_err(|e| match e {
+                rusb::Error::Timeout => Error::new(TimedOut, e),
+                _ => Error::new(Other, e),
             })
     }
 
@@ -262,38 +772,955 @@ impl Read for SnifferDevice {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         self.handle
             .read_bulk(self.in_address, buf, self.timeout)
-            .map_err(|e| {
-                match e {
-                    rusb::Error::Timeout => Error::new(TimedOut, e),
-                    _ => Error::new(Other, e)
-                }
+            .map_err(|e| match e {
+                rusb::Error::Timeout => Error::new(TimedOut, e),
+                _ => Error::new(Other, e),
             })
     }
 }
 
-// Procedure copied from the firmware
-fn calculate_crc(buffer: &[u8], len: usize) -> u8 {
-    let mut checksum = 0xff;
-    for i in 0..len {
-        checksum ^= buffer[i as usize];
+/// Tells an unplugged sniffer, which won't come back by retrying, from other transfer
+/// errors
+fn transfer_error(e: rusb::Error) -> SnifferError {
+    match e {
+        rusb::Error::NoDevice | rusb::Error::NotFound => SnifferError::Disconnected,
+        _ => SnifferError::UsbError(e),
+    }
+}
+
+/// Picks every device with the given ids from (device, vendor, product) tuples, fails if
+/// there are none. A stick that only shows up with bootloader ids is reported as such.
+fn select_devices<T>(
+    devices: impl Iterator<Item = (T, u16, u16)>,
+    vendor: u16,
+    product: u16,
+) -> Result<Vec<T>, SnifferError> {
+    let mut selected = vec![];
+    let mut any_devices = false;
+    let mut bootloader = None;
+    for (device, device_vendor, device_product) in devices {
+        if device_vendor == vendor && device_product == product {
+            selected.push(device);
+            continue;
+        }
+        if BOOTLOADERS.contains(&(device_vendor, device_product)) {
+            bootloader = Some((device_vendor, device_product));
+        }
+        any_devices = true;
+    }
+
+    if !selected.is_empty() {
+        Ok(selected)
+    } else if let Some((vendor, product)) = bootloader {
+        Err(SnifferError::Bootloader(vendor, product))
+    } else if any_devices {
+        Err(SnifferError::NotFound(vendor, product))
+    } else {
+        Err(SnifferError::NoUsbDevices)
+    }
+}
+
+/// The product string of the device, None when it has none or the device can't be opened
+fn product_string(device: &Device<GlobalContext>) -> Option<String> {
+    let descriptor = device.device_descriptor().ok()?;
+    let handle = device.open().ok()?;
+    handle.read_product_string_ascii(&descriptor).ok()
+}
+
+/// Picks every device whose product string contains `name` from (device, product string)
+/// tuples, fails if there are none. Devices without a readable string never match.
+fn select_named<T>(
+    devices: impl Iterator<Item = (T, Option<String>)>,
+    name: &str,
+) -> Result<Vec<T>, SnifferError> {
+    let mut any_devices = false;
+    let mut selected = vec![];
+    for (device, product) in devices {
+        any_devices = true;
+        if product.is_some_and(|p| p.contains(name)) {
+            selected.push(device);
+        }
+    }
+
+    if !selected.is_empty() {
+        Ok(selected)
+    } else if any_devices {
+        Err(SnifferError::NameNotFound(name.to_string()))
+    } else {
+        Err(SnifferError::NoUsbDevices)
+    }
+}
+
+/// Suggested udev rule that gives the logged in user access to the device
+pub fn udev_rule(vendor: u16, product: u16) -> String {
+    format!(
+        "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", GROUP=\"plugdev\", TAG+=\"uaccess\"",
+        vendor, product
+    )
+}
+
+/// USB ids of the CC2531 dongle, for `SnifferDevice::find_device`
+pub const VENDOR: u16 = 0x0451; // Texas Instruments
+pub const PRODUCT: u16 = 0x16a8; // CC2531 USB Stick
+/// The TI USB bootloader, a stick that was flashed with it but no application
+pub const BOOTLOADER_PRODUCT: u16 = 0x16a0;
+
+/// Known (vendor, product) ids of sticks in bootloader mode
+const BOOTLOADERS: &[(u16, u16)] = &[(VENDOR, BOOTLOADER_PRODUCT)];
+
+pub const DEFAULT_CHECKSUM_SEED: u8 = 0xff;
+/// How long `receive_packet` waits for a frame
+pub const RECEIVE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// How often a stop flag is checked while waiting for a frame
+pub const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest command payload, the length byte also counts itself, the code and the checksum
+pub const MAX_COMMAND_PAYLOAD: usize = u8::MAX as usize - 3;
+/// How long `receive_queued_into` waits for a frame
+pub const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+/// Frames read while draining, in case the sniffer doesn't stop sending
+pub const MAX_DRAIN_FRAMES: usize = 256;
+/// Size of a bulk read from the sniffer, enough for the largest frame it sends
+pub const READ_BUFFER_SIZE: usize = 256;
+/// Channels of the 2.4 GHz O-QPSK PHY, the only band of the CC2531
+pub const CHANNELS: RangeInclusive<u8> = 11..=26;
+/// aMaxPhyPacketSize, the longest frame including the FCS
+pub const MAX_FRAME_LEN: usize = 127;
+/// Firmware protocol versions this was tested with, as reported in the init ack. The
+/// firmware it was written against sends an empty ack and counts as version 1.
+pub const PROTOCOL_VERSIONS: RangeInclusive<u8> = 1..=1;
+
+/// Checks the protocol version returned by `SnifferDevice::verify` against the tested
+/// versions
+pub fn check_protocol_version(version: Option<u8>) -> Result<(), SnifferError> {
+    let version = version.unwrap_or(*PROTOCOL_VERSIONS.start());
+    if PROTOCOL_VERSIONS.contains(&version) {
+        Ok(())
+    } else {
+        Err(SnifferError::UnsupportedVersion(version))
     }
-    return checksum;
 }
+/// Largest MPDU `send_packet` takes, the radio adds the FCS
+pub const MAX_SEND_LEN: usize = MAX_FRAME_LEN - FCS_LEN;
 
-fn dump(buffer: &[u8], len: usize) {
+/// What a sniffer can do. The firmware has no command to report this, so it is what is
+/// known about the CC2531 and the sniffer firmware.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub channels: RangeInclusive<u8>,
+    /// Longest frame including the FCS
+    pub max_frame_len: usize,
+    /// Commands the firmware answers
+    pub commands: Vec<CmdCodes>,
+}
+
+impl Capabilities {
+    pub fn cc2531() -> Self {
+        Capabilities {
+            channels: CHANNELS,
+            max_frame_len: MAX_FRAME_LEN,
+            commands: vec![
+                CmdCodes::CmdInit,
+                CmdCodes::CmdSetChannel,
+                CmdCodes::CmdSendPkt,
+                CmdCodes::CmdSniffOn,
+                CmdCodes::CmdSniffOff,
+            ],
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "channels: {}-{}",
+            self.channels.start(),
+            self.channels.end()
+        )?;
+        writeln!(f, "max frame length: {} bytes", self.max_frame_len)?;
+        writeln!(f, "frame metadata: rssi, correlation, fcs")?;
+        let commands: Vec<String> = self.commands.iter().map(|c| format!("{:?}", c)).collect();
+        writeln!(f, "commands: {}", commands.join(", "))
+    }
+}
+
+/// Prints the first `len` bytes of `buffer` as a hex dump
+pub fn dump(buffer: &[u8], len: usize) {
     let mut outbuf = Vec::new();
-    hexdump(&buffer[0..len as usize], &mut outbuf).expect("hexdump issue");
+    hexdump(&buffer[0..len], &mut outbuf).expect("hexdump issue");
     println!("{}", String::from_utf8_lossy(&outbuf))
 }
 
-fn find_first_endpoint<'a>(
-    interface_descriptor: &'a InterfaceDescriptor<'a>,
-    direction: Direction,
-) -> Result<EndpointDescriptor, Box<SnifferError>> {
-    return interface_descriptor
+/// Busy means another program, e.g. a second capture or a Wireshark extcap, has claimed
+/// the interface
+fn claim_error(e: rusb::Error, vendor: u16, product: u16) -> SnifferError {
+    match e {
+        rusb::Error::Busy => SnifferError::Busy(vendor, product),
+        _ => SnifferError::SetupFailed(SetupStage::ClaimInterface, e),
+    }
+}
+
+/// (address, direction, transfer type) of the endpoints of an interface
+fn endpoints(interface_descriptor: &InterfaceDescriptor) -> Vec<(u8, Direction, TransferType)> {
+    interface_descriptor
         .endpoint_descriptors()
-        .find(|endpoint| {
-            return endpoint.direction() == direction;
-        })
-        .ok_or_else(|| Box::new(SnifferError::DeviceError));
+        .map(|e| (e.address(), e.direction(), e.transfer_type()))
+        .collect()
+}
+
+/// Returns the address of the bulk endpoint in `direction`: `address` after checking it,
+/// or else the first one
+fn select_endpoint(
+    endpoints: &[(u8, Direction, TransferType)],
+    direction: Direction,
+    address: Option<u8>,
+) -> Result<u8, SnifferError> {
+    let Some(address) = address else {
+        return endpoints
+            .iter()
+            .find(|(_, d, t)| *d == direction && *t == TransferType::Bulk)
+            .map(|(a, _, _)| *a)
+            .ok_or(SnifferError::NoBulkEndpoints);
+    };
+
+    let bad = |problem| Err(SnifferError::BadEndpoint(address, problem));
+    match endpoints.iter().find(|(a, _, _)| *a == address) {
+        None => bad("is not on the interface"),
+        Some((_, d, _)) if *d != direction => match direction {
+            In => bad("is not an in endpoint"),
+            Out => bad("is not an out endpoint"),
+        },
+        Some((_, _, t)) if *t != TransferType::Bulk => bad("is not a bulk endpoint"),
+        Some(_) => Ok(address),
+    }
+}
+
+fn describe_endpoint(
+    address: u8,
+    direction: Direction,
+    transfer_type: TransferType,
+    max_packet_size: u16,
+) -> String {
+    let direction = match direction {
+        In => "in",
+        Out => "out",
+    };
+    let transfer_type = match transfer_type {
+        TransferType::Control => "control",
+        TransferType::Isochronous => "isochronous",
+        TransferType::Bulk => "bulk",
+        TransferType::Interrupt => "interrupt",
+    };
+    format!(
+        "endpoint {:#04x} {} {}, max packet size {}",
+        address, direction, transfer_type, max_packet_size
+    )
+}
+
+// The sniffer firmware has a single interface with a bulk endpoint in each direction
+fn has_bulk_endpoints(config: &ConfigDescriptor) -> bool {
+    let interface_descriptor = match config.interfaces().next() {
+        Some(i) => i.descriptors().next(),
+        None => None,
+    };
+    match interface_descriptor {
+        Some(d) => {
+            let endpoints = endpoints(&d);
+            select_endpoint(&endpoints, In, None).is_ok()
+                && select_endpoint(&endpoints, Out, None).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Picks a configuration from (configuration number, has bulk endpoints) pairs, returns
+/// its index
+fn select_config(
+    configs: &[(u8, bool)],
+    active: Option<u8>,
+    index: Option<u8>,
+) -> Result<usize, SnifferError> {
+    let usable = |i: &usize| configs.get(*i).is_some_and(|(_, bulk)| *bulk);
+    match index {
+        Some(i) => Some(i as usize).filter(usable),
+        None => configs
+            .iter()
+            .position(|(number, _)| Some(*number) == active)
+            .filter(usable)
+            .or_else(|| (0..configs.len()).find(usable)),
+    }
+    .ok_or(SnifferError::NoBulkEndpoints)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protocol::Message;
+    use crate::sniffer::{
+        calculate_crc, check_protocol_version, claim_error, describe_endpoint, select_config,
+        select_devices, select_endpoint, select_named, udev_rule, Capabilities, CmdCodes,
+        DeviceState, SetupStage, SnifferDevice, SnifferError, Transport, DEFAULT_CHECKSUM_SEED,
+        MAX_COMMAND_PAYLOAD, MAX_FRAME_LEN, MAX_SEND_LEN, READ_BUFFER_SIZE, RECEIVE_TIMEOUT,
+        STOP_POLL_INTERVAL,
+    };
+    use rusb::{Direction, TransferType};
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    pub(crate) type Writes = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    /// Transport that replays canned reads, and times out when they run out. Writes are
+    /// recorded.
+    struct FakeTransport {
+        reads: Mutex<VecDeque<rusb::Result<Vec<u8>>>>,
+        writes: Writes,
+    }
+
+    impl Transport for FakeTransport {
+        fn write_bulk(&self, _endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn read_bulk(
+            &self,
+            _endpoint: u8,
+            buf: &mut [u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            match self.reads.lock().unwrap().pop_front() {
+                Some(Ok(data)) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(rusb::Error::Timeout),
+            }
+        }
+    }
+
+    fn sniffer(reads: Vec<rusb::Result<Vec<u8>>>) -> SnifferDevice {
+        sniffer_with_writes(reads).0
+    }
+
+    pub(crate) fn sniffer_with_writes(
+        reads: Vec<rusb::Result<Vec<u8>>>,
+    ) -> (SnifferDevice, Writes) {
+        let writes = Writes::default();
+        let transport = FakeTransport {
+            reads: Mutex::new(reads.into()),
+            writes: writes.clone(),
+        };
+        let sniffer = SnifferDevice::from_transport(Box::new(transport), None, 0x83, 0x04);
+        (sniffer, writes)
+    }
+
+    /// Builds a frame as sent by the firmware, both length bytes hold the total length
+    pub(crate) fn frame(code: CmdCodes, body: &[u8]) -> Vec<u8> {
+        let len = body.len() + 4;
+        let mut f = vec![len as u8, len as u8, code as u8];
+        f.extend_from_slice(body);
+        f.push(calculate_crc(&f, f.len(), DEFAULT_CHECKSUM_SEED));
+        f
+    }
+
+    #[test]
+    fn receive_packet() {
+        let sniffer = sniffer(vec![Ok(frame(
+            CmdCodes::CmdGotPkt,
+            &[0xd3, 120, 0x02, 0x00, 0x2a],
+        ))]);
+        assert_eq!(
+            sniffer.receive_packet().unwrap(),
+            [0xd3, 120, 0x02, 0x00, 0x2a]
+        );
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::TimeOut)
+        ));
+    }
+
+    #[test]
+    fn receive_packet_into() {
+        let sniffer = sniffer(vec![Ok(frame(
+            CmdCodes::CmdGotPkt,
+            &[0xd3, 120, 0x02, 0x00, 0x2a],
+        ))]);
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        let data = sniffer.receive_packet_into(&mut buffer).unwrap();
+        assert_eq!(data, [0xd3, 120, 0x02, 0x00, 0x2a]);
+        let borrowed = data.as_ptr();
+        assert_eq!(borrowed, buffer[3..].as_ptr());
+    }
+
+    #[test]
+    fn stop_flag() {
+        let packet = frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x02, 0x00, 0x2a]);
+        let timeouts = (RECEIVE_TIMEOUT.as_millis() / STOP_POLL_INTERVAL.as_millis()) as usize;
+        let mut reads: Vec<_> = (0..timeouts - 1)
+            .map(|_| Err(rusb::Error::Timeout))
+            .collect();
+        reads.push(Ok(packet.clone()));
+        reads.push(Ok(packet));
+
+        // Polls that time out within the receive timeout are retried
+        let mut sniffer = sniffer(reads);
+        let stop = Arc::new(AtomicBool::new(false));
+        sniffer.set_stop_flag(stop.clone());
+        assert_eq!(
+            sniffer.receive_packet().unwrap(),
+            [0xd3, 120, 0x02, 0x00, 0x2a]
+        );
+
+        stop.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::TimeOut)
+        ));
+    }
+
+    #[test]
+    fn receive_errors() {
+        let sniffer = sniffer(vec![
+            Err(rusb::Error::NoDevice),
+            Err(rusb::Error::NotFound),
+            Err(rusb::Error::Io),
+            Err(rusb::Error::Pipe),
+        ]);
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::Disconnected)
+        ));
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::Disconnected)
+        ));
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::UsbError(rusb::Error::Io))
+        ));
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::UsbError(rusb::Error::Pipe))
+        ));
+
+        let sniffer = sniffer_with_writes(vec![Err(rusb::Error::NoDevice)]).0;
+        assert!(matches!(
+            sniffer.send_command(CmdCodes::CmdSniffOff, &[]),
+            Err(SnifferError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn send_packet() {
+        let ack = [0x02, 0x00, 0x2a];
+        let (sniffer, writes) = sniffer_with_writes(vec![Ok(frame(CmdCodes::CmdSendPktAck, &[]))]);
+        sniffer.send_packet(&ack).unwrap();
+        assert_eq!(
+            writes.lock().unwrap()[0],
+            Message::new(CmdCodes::CmdSendPkt as u8, &ack).to_bytes(DEFAULT_CHECKSUM_SEED)
+        );
+
+        assert!(matches!(
+            sniffer.send_packet(&[0; MAX_SEND_LEN + 1]),
+            Err(SnifferError::ProtocolError(_))
+        ));
+        assert_eq!(writes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn command_bytes() {
+        // Length, code, channel 11 and the checksum, which the length byte counts
+        let (sniffer, writes) =
+            sniffer_with_writes(vec![Ok(frame(CmdCodes::CmdSetChannelAck, &[]))]);
+        sniffer
+            .send_command(CmdCodes::CmdSetChannel, &[0x0b])
+            .unwrap();
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes[0], [0x04, 0x02, 0x0b, 0xf2]);
+        assert_eq!(writes[0].len(), writes[0][0] as usize);
+    }
+
+    /// Takes one byte less than it is given
+    struct ShortTransport;
+
+    impl Transport for ShortTransport {
+        fn write_bulk(&self, _endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            Ok(buf.len() - 1)
+        }
+
+        fn read_bulk(
+            &self,
+            _endpoint: u8,
+            _buf: &mut [u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            Err(rusb::Error::Timeout)
+        }
+    }
+
+    #[test]
+    fn short_write() {
+        let sniffer = SnifferDevice::from_transport(Box::new(ShortTransport), None, 0x83, 0x04);
+        assert!(matches!(
+            sniffer.send_command(CmdCodes::CmdInit, &[]),
+            Err(SnifferError::ShortWrite(2, 3))
+        ));
+    }
+
+    #[test]
+    fn raw_command() {
+        // An unknown code, answered with an error frame
+        let response = frame(CmdCodes::CmdErr, &[0x42]);
+        let (sniffer, writes) = sniffer_with_writes(vec![Ok(response.clone())]);
+        assert_eq!(sniffer.raw_command(0x42, &[0x01, 0x02]).unwrap(), response);
+        assert_eq!(writes.lock().unwrap()[0], [0x05, 0x42, 0x01, 0x02, 0xbb]);
+
+        // The longest payload is sent, the fake has no answer for it
+        _ = sniffer.raw_command(0x42, &[0; MAX_COMMAND_PAYLOAD]);
+        assert_eq!(writes.lock().unwrap()[1][0], 0xff);
+        assert!(matches!(
+            sniffer.raw_command(0x42, &[0; MAX_COMMAND_PAYLOAD + 1]),
+            Err(SnifferError::ProtocolError(_))
+        ));
+        assert_eq!(writes.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn receive_short_frames() {
+        let mut reads: Vec<_> = (0..4).map(|n| Ok(vec![n as u8; n])).collect();
+        reads.push(Ok(frame(CmdCodes::CmdGotPkt, &[])));
+        let sniffer = sniffer(reads);
+
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::ProtocolError("empty read"))
+        ));
+        for _ in 1..4 {
+            assert!(matches!(
+                sniffer.receive_packet(),
+                Err(SnifferError::ProtocolError("short frame"))
+            ));
+        }
+        assert_eq!(sniffer.receive_packet().unwrap(), []);
+    }
+
+    #[test]
+    fn receive_size_mismatch() {
+        // The read returns trailing garbage, so only the right length field finds the
+        // packet and the checksum
+        let body = [0xd3, 120, 0x02, 0x00, 0x2a];
+        let padded = |f: Vec<u8>| [f, vec![0xee; 4]].concat();
+        let mut first_right = padded(frame(CmdCodes::CmdGotPkt, &body));
+        first_right[1] += 4;
+        let mut second_right = padded(frame(CmdCodes::CmdGotPkt, &body));
+        second_right[0] += 4;
+        let mut too_short = frame(CmdCodes::CmdGotPkt, &body);
+        too_short[0] = 3;
+
+        let lenient = sniffer(vec![
+            Ok(first_right.clone()),
+            Ok(second_right),
+            Ok(too_short),
+        ]);
+        assert_eq!(lenient.receive_packet().unwrap(), body);
+        assert!(lenient.take_length_mismatch());
+        assert!(!lenient.take_length_mismatch());
+        assert_eq!(lenient.receive_packet().unwrap(), body);
+        assert!(matches!(
+            lenient.receive_packet(),
+            Err(SnifferError::ProtocolError("short frame"))
+        ));
+
+        let mut strict = sniffer(vec![Ok(first_right)]);
+        strict.set_strict();
+        assert!(matches!(
+            strict.receive_packet(),
+            Err(SnifferError::ProtocolError("size mismatch"))
+        ));
+    }
+
+    #[test]
+    fn rejected_size_mismatch() {
+        // Frames that are rejected or aren't packets don't count as a mismatch
+        let body = [0xd3, 120, 0x02, 0x00, 0x2a];
+        let mut too_short = frame(CmdCodes::CmdGotPkt, &body);
+        too_short[0] = 3;
+        let mut ack = [frame(CmdCodes::CmdInit, &[]), vec![0xee; 4]].concat();
+        ack[0] += 4;
+
+        let lenient = sniffer(vec![
+            Ok(too_short),
+            Ok(ack),
+            Ok(frame(CmdCodes::CmdGotPkt, &body)),
+        ]);
+        assert!(matches!(
+            lenient.receive_packet(),
+            Err(SnifferError::ProtocolError("short frame"))
+        ));
+        assert!(matches!(
+            lenient.receive_packet(),
+            Err(SnifferError::NonPacketFrame(_))
+        ));
+        assert_eq!(lenient.receive_packet().unwrap(), body);
+        assert!(!lenient.take_length_mismatch());
+    }
+
+    #[test]
+    fn max_frame_len() {
+        let packet = |len| [[0xd3, 120].as_slice(), &vec![0; len]].concat();
+        let mut sniffer = sniffer(vec![
+            Ok(frame(CmdCodes::CmdGotPkt, &packet(MAX_FRAME_LEN))),
+            Ok(frame(CmdCodes::CmdGotPkt, &packet(MAX_FRAME_LEN + 1))),
+            Ok(frame(CmdCodes::CmdGotPkt, &packet(21))),
+        ]);
+        assert_eq!(sniffer.receive_packet().unwrap().len(), MAX_FRAME_LEN + 2);
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::FrameTooLong(128))
+        ));
+
+        sniffer.set_max_frame_len(20);
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::FrameTooLong(21))
+        ));
+    }
+
+    #[test]
+    fn receive_interleaved_ack() {
+        // A code the firmware doesn't document keeps its value
+        let mut unknown = frame(CmdCodes::CmdErr, &[]);
+        unknown[2] = 0x42;
+        unknown[3] = calculate_crc(&unknown, 3, DEFAULT_CHECKSUM_SEED);
+        let sniffer = sniffer(vec![
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+            Ok(unknown),
+            Ok(frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x02, 0x00, 0x2a])),
+        ]);
+        assert!(matches!(
+            sniffer.receive_packet(),
+            Err(SnifferError::NonPacketFrame(0x07))
+        ));
+        let e = sniffer.receive_packet().unwrap_err();
+        assert!(matches!(e, SnifferError::NonPacketFrame(0x42)));
+        assert_eq!(e.to_string(), "unexpected frame with command code 0x42");
+        assert_eq!(
+            sniffer.receive_packet().unwrap(),
+            [0xd3, 120, 0x02, 0x00, 0x2a]
+        );
+    }
+
+    #[test]
+    fn udev_rule_for_device() {
+        assert_eq!(
+            udev_rule(0x0451, 0x16a8),
+            r#"SUBSYSTEM=="usb", ATTRS{idVendor}=="0451", ATTRS{idProduct}=="16a8", GROUP="plugdev", TAG+="uaccess""#
+        );
+    }
+
+    #[test]
+    fn checksum_seed() {
+        assert_eq!(calculate_crc(&[0x03, 0x00], 2, DEFAULT_CHECKSUM_SEED), 0xfc);
+        assert_eq!(calculate_crc(&[0x03, 0x00], 2, 0x00), 0x03);
+
+        let (mut sniffer, writes) = sniffer_with_writes(vec![Ok(frame(CmdCodes::CmdInitAck, &[]))]);
+        sniffer.set_checksum_seed(0x5a);
+        sniffer.send_command(CmdCodes::CmdInit, &[]).unwrap();
+        assert_eq!(writes.lock().unwrap()[0], [0x03, 0x00, 0x59]);
+    }
+
+    #[test]
+    fn verify_init_ack() {
+        let drain = || Err(rusb::Error::Timeout);
+        assert_eq!(
+            sniffer(vec![drain(), Ok(frame(CmdCodes::CmdInitAck, &[]))])
+                .verify()
+                .unwrap(),
+            None
+        );
+        let versioned = sniffer(vec![drain(), Ok(frame(CmdCodes::CmdInitAck, &[2]))]);
+        assert_eq!(versioned.verify().unwrap(), Some(2));
+
+        let mut bad_checksum = frame(CmdCodes::CmdInitAck, &[]);
+        bad_checksum[3] ^= 0xff;
+        let mut wrong_length = frame(CmdCodes::CmdInitAck, &[]);
+        wrong_length[1] = 5;
+        for response in [
+            Ok(bad_checksum),
+            Ok(wrong_length),
+            Ok(frame(CmdCodes::CmdErr, &[])),
+            Err(rusb::Error::Timeout),
+        ] {
+            let result = sniffer(vec![drain(), response]).verify();
+            assert!(matches!(result, Err(SnifferError::NotASniffer(_))));
+        }
+
+        // Left over frames are skipped, a failing read isn't taken for an empty queue
+        let leftover = sniffer(vec![
+            Ok(frame(CmdCodes::CmdInitAck, &[2])),
+            Ok(frame(CmdCodes::CmdGotPkt, &[0xd3, 120, 0x02, 0x00, 0x2a])),
+            drain(),
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+        ]);
+        assert_eq!(leftover.verify().unwrap(), None);
+        let unplugged = sniffer(vec![Err(rusb::Error::NoDevice)]);
+        assert!(matches!(
+            unplugged.verify(),
+            Err(SnifferError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn protocol_version() {
+        assert!(check_protocol_version(None).is_ok());
+        assert!(check_protocol_version(Some(1)).is_ok());
+        let result = check_protocol_version(Some(2));
+        assert!(matches!(result, Err(SnifferError::UnsupportedVersion(2))));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "firmware protocol version 2 is outside the tested versions 1-1"
+        );
+    }
+
+    #[test]
+    fn start_sniffing() {
+        let started = sniffer(vec![
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+        ]);
+        let state = started.start_sniffing(15, Duration::ZERO).unwrap();
+        assert_eq!(
+            state,
+            DeviceState {
+                initialized: true,
+                channel: Some(15),
+                sniffing: true
+            }
+        );
+        assert_eq!(state.to_string(), "initialized, channel 15, sniffing");
+
+        // The set channel command gets the wrong ack
+        let failing = sniffer(vec![
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+            Ok(frame(CmdCodes::CmdInitAck, &[])),
+        ]);
+        match failing.start_sniffing(15, Duration::ZERO) {
+            Err(SnifferError::StartFailed(state, e)) => {
+                assert_eq!(state.to_string(), "initialized");
+                assert!(matches!(*e, SnifferError::ProtocolError(_)));
+            }
+            _ => panic!("expected the startup to fail"),
+        }
+    }
+
+    #[test]
+    fn reconfigure_channel() {
+        let (sniffing, writes) = sniffer_with_writes(vec![
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+            Ok(frame(CmdCodes::CmdSetChannelAck, &[])),
+            Ok(frame(CmdCodes::CmdSniffOnAck, &[])),
+        ]);
+        let state = sniffing.reconfigure_channel(20).unwrap();
+        assert_eq!(state.to_string(), "initialized, channel 20, sniffing");
+        let writes = writes.lock().unwrap();
+        let commands: Vec<u8> = writes.iter().map(|w| w[1]).collect();
+        assert_eq!(
+            commands,
+            [
+                CmdCodes::CmdSniffOff as u8,
+                CmdCodes::CmdSetChannel as u8,
+                CmdCodes::CmdSniffOn as u8
+            ]
+        );
+        assert_eq!(writes[1][2], 20);
+
+        // Set channel fails, the sniffer is left stopped
+        let failing = sniffer(vec![
+            Ok(frame(CmdCodes::CmdSniffOffAck, &[])),
+            Ok(frame(CmdCodes::CmdErr, &[])),
+        ]);
+        match failing.reconfigure_channel(20) {
+            Err(SnifferError::ReconfigureFailed(state, _)) => {
+                assert!(!state.sniffing);
+                assert_eq!(state.channel, None);
+            }
+            _ => panic!("expected the channel change to fail"),
+        }
+    }
+
+    #[test]
+    fn setup_failure() {
+        let e = SnifferError::SetupFailed(SetupStage::ClaimInterface, rusb::Error::Busy);
+        assert_eq!(
+            e.to_string(),
+            format!("can't claim the interface: {}", rusb::Error::Busy)
+        );
+    }
+
+    #[test]
+    fn claim_busy() {
+        let e = claim_error(rusb::Error::Busy, 0x0451, 0x16a8);
+        assert!(matches!(e, SnifferError::Busy(0x0451, 0x16a8)));
+        assert_eq!(
+            e.to_string(),
+            "the interface of usb device 0451:16a8 is claimed by another program"
+        );
+        assert!(matches!(
+            claim_error(rusb::Error::Io, 0x0451, 0x16a8),
+            SnifferError::SetupFailed(SetupStage::ClaimInterface, rusb::Error::Io)
+        ));
+    }
+
+    #[test]
+    fn endpoint_description() {
+        assert_eq!(
+            describe_endpoint(0x83, Direction::In, TransferType::Bulk, 64),
+            "endpoint 0x83 in bulk, max packet size 64"
+        );
+        assert_eq!(
+            describe_endpoint(0x04, Direction::Out, TransferType::Interrupt, 8),
+            "endpoint 0x04 out interrupt, max packet size 8"
+        );
+    }
+
+    #[test]
+    fn select_configuration() {
+        let configs = [(1, false), (2, true), (3, true)];
+        assert_eq!(select_config(&configs, Some(1), None).unwrap(), 1);
+        assert_eq!(select_config(&configs, Some(3), None).unwrap(), 2);
+        assert_eq!(select_config(&configs, None, None).unwrap(), 1);
+        assert_eq!(select_config(&configs, Some(1), Some(2)).unwrap(), 2);
+        assert!(matches!(
+            select_config(&configs, Some(2), Some(0)),
+            Err(SnifferError::NoBulkEndpoints)
+        ));
+        assert!(matches!(
+            select_config(&configs, None, Some(3)),
+            Err(SnifferError::NoBulkEndpoints)
+        ));
+        assert!(matches!(
+            select_config(&[(1, false)], Some(1), None),
+            Err(SnifferError::NoBulkEndpoints)
+        ));
+    }
+
+    #[test]
+    fn select_endpoints() {
+        let endpoints = [
+            (0x81, Direction::In, TransferType::Interrupt),
+            (0x83, Direction::In, TransferType::Bulk),
+            (0x04, Direction::Out, TransferType::Bulk),
+            (0x85, Direction::In, TransferType::Bulk),
+        ];
+        assert_eq!(
+            select_endpoint(&endpoints, Direction::In, None).unwrap(),
+            0x83
+        );
+        assert_eq!(
+            select_endpoint(&endpoints, Direction::Out, None).unwrap(),
+            0x04
+        );
+        assert_eq!(
+            select_endpoint(&endpoints, Direction::In, Some(0x85)).unwrap(),
+            0x85
+        );
+
+        for (direction, address, problem) in [
+            (Direction::In, 0x86, "is not on the interface"),
+            (Direction::Out, 0x83, "is not an out endpoint"),
+            (Direction::In, 0x04, "is not an in endpoint"),
+            (Direction::In, 0x81, "is not a bulk endpoint"),
+        ] {
+            match select_endpoint(&endpoints, direction, Some(address)) {
+                Err(SnifferError::BadEndpoint(a, p)) => assert_eq!((a, p), (address, problem)),
+                r => panic!("expected a bad endpoint error, got {:?}", r),
+            }
+        }
+        assert_eq!(
+            SnifferError::BadEndpoint(0x86, "is not on the interface").to_string(),
+            "endpoint 0x86 is not on the interface"
+        );
+        assert!(matches!(
+            select_endpoint(&endpoints[..2], Direction::Out, None),
+            Err(SnifferError::NoBulkEndpoints)
+        ));
+    }
+
+    #[test]
+    fn cc2531_capabilities() {
+        let capabilities = Capabilities::cc2531();
+        assert_eq!(capabilities.channels.clone().count(), 16);
+        assert_eq!(
+            capabilities.to_string(),
+            "channels: 11-26\nmax frame length: 127 bytes\nframe metadata: rssi, correlation, fcs\n\
+             commands: CmdInit, CmdSetChannel, CmdSendPkt, CmdSniffOn, CmdSniffOff\n"
+        );
+    }
+
+    #[test]
+    fn select_by_name() {
+        let devices = || {
+            vec![
+                ("hub", Some("4-Port USB 2.0 Hub".to_string())),
+                ("no access", None),
+                ("sniffer 1", Some("TI CC2531 USB CDC".to_string())),
+                ("sniffer 2", Some("CC2531 USB Dongle".to_string())),
+            ]
+            .into_iter()
+        };
+        assert_eq!(
+            select_named(devices(), "CC2531").unwrap(),
+            ["sniffer 1", "sniffer 2"]
+        );
+        assert_eq!(select_named(devices(), "Dongle").unwrap(), ["sniffer 2"]);
+        assert!(matches!(
+            select_named(devices(), "CC2652"),
+            Err(SnifferError::NameNotFound(name)) if name == "CC2652"
+        ));
+        let devices: Vec<(&str, Option<String>)> = vec![];
+        assert!(matches!(
+            select_named(devices.into_iter(), "CC2531"),
+            Err(SnifferError::NoUsbDevices)
+        ));
+    }
+
+    #[test]
+    fn select_from_devices() {
+        let devices = vec![("hub", 0x1d6b, 0x0002), ("sniffer", 0x0451, 0x16a8)];
+        assert_eq!(
+            select_devices(devices.into_iter(), 0x0451, 0x16a8).unwrap(),
+            ["sniffer"]
+        );
+
+        let devices = vec![
+            ("sniffer 1", 0x0451, 0x16a8),
+            ("hub", 0x1d6b, 0x0002),
+            ("sniffer 2", 0x0451, 0x16a8),
+        ];
+        assert_eq!(
+            select_devices(devices.into_iter(), 0x0451, 0x16a8).unwrap(),
+            ["sniffer 1", "sniffer 2"]
+        );
+
+        let devices = vec![("hub", 0x1d6b, 0x0002)];
+        assert!(matches!(
+            select_devices(devices.into_iter(), 0x0451, 0x16a8),
+            Err(SnifferError::NotFound(0x0451, 0x16a8))
+        ));
+
+        let devices: Vec<(&str, u16, u16)> = vec![];
+        assert!(matches!(
+            select_devices(devices.into_iter(), 0x0451, 0x16a8),
+            Err(SnifferError::NoUsbDevices)
+        ));
+
+        let devices = vec![("hub", 0x1d6b, 0x0002), ("bootloader", 0x0451, 0x16a0)];
+        assert!(matches!(
+            select_devices(devices.into_iter(), 0x0451, 0x16a8),
+            Err(SnifferError::Bootloader(0x0451, 0x16a0))
+        ));
+    }
 }
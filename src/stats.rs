@@ -0,0 +1,815 @@
+use crate::fcs::{fcs_valid, FCS_LEN};
+use crate::lowpan;
+use crate::mac::{Address, FrameType, MacHeader};
+use crate::packet::CapturedPacket;
+use crate::sniffer::MAX_FRAME_LEN;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+pub const DEFAULT_RSSI_DECAY: f64 = 0.2;
+
+// Length of a 100% bar in the frame type chart
+const CHART_WIDTH: usize = 40;
+
+/// A decay of 0 would never move the average away from the first sample
+pub fn valid_rssi_decay(decay: f64) -> bool {
+    decay > 0.0 && decay <= 1.0
+}
+
+/// Smoothed RSSI of the frames from one source address
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceSignal {
+    /// Exponential moving average, in dBm
+    pub rssi: f64,
+    pub packets: u64,
+}
+
+/// Signal strength per source address, to locate devices by moving the sniffer around
+#[derive(Clone, Debug)]
+pub struct SourceSignals {
+    /// Weight of a new sample in the average, between 0 and 1
+    pub decay: f64,
+    pub sources: HashMap<Address, SourceSignal>,
+    /// Frames without a source address that could be parsed
+    pub unknown: u64,
+}
+
+impl SourceSignals {
+    pub fn new(decay: f64) -> Self {
+        SourceSignals {
+            decay,
+            sources: HashMap::new(),
+            unknown: 0,
+        }
+    }
+
+    pub fn record(&mut self, address: Address, rssi: i8) {
+        let rssi = rssi as f64;
+        let decay = self.decay;
+        self.sources
+            .entry(address)
+            .and_modify(|s| {
+                s.rssi += decay * (rssi - s.rssi);
+                s.packets += 1;
+            })
+            .or_insert(SourceSignal { rssi, packets: 1 });
+    }
+
+    /// The sources, strongest signal first
+    pub fn sorted(&self) -> Vec<(Address, SourceSignal)> {
+        let mut sources: Vec<(Address, SourceSignal)> =
+            self.sources.iter().map(|(a, s)| (*a, s.clone())).collect();
+        sources.sort_by(|a, b| b.1.rssi.total_cmp(&a.1.rssi));
+        sources
+    }
+
+    /// The `n` sources that sent the most frames, most first
+    pub fn top_talkers(&self, n: usize) -> Vec<(Address, u64)> {
+        let mut talkers: Vec<(Address, u64)> =
+            self.sources.iter().map(|(a, s)| (*a, s.packets)).collect();
+        talkers.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        talkers.truncate(n);
+        talkers
+    }
+}
+
+impl Default for SourceSignals {
+    fn default() -> Self {
+        SourceSignals::new(DEFAULT_RSSI_DECAY)
+    }
+}
+
+// Width of the histogram buckets, in dBm and LQI steps
+pub const RSSI_BUCKET: i16 = 5;
+pub const LQI_BUCKET: u16 = 16;
+
+/// Packet counts per RSSI and LQI range, keyed by the lowest value in the bucket
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histograms {
+    pub rssi: BTreeMap<i16, u64>,
+    pub lqi: BTreeMap<u16, u64>,
+}
+
+impl Histograms {
+    pub fn record(&mut self, rssi: i8, lqi: u8) {
+        let rssi = (rssi as i16).div_euclid(RSSI_BUCKET) * RSSI_BUCKET;
+        let lqi = lqi as u16 / LQI_BUCKET * LQI_BUCKET;
+        *self.rssi.entry(rssi).or_default() += 1;
+        *self.lqi.entry(lqi).or_default() += 1;
+    }
+
+    /// One `metric,from,to,count` line per bucket, the range is inclusive
+    pub fn write_csv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "metric,from,to,count")?;
+        for (from, count) in &self.rssi {
+            writeln!(w, "rssi,{},{},{}", from, from + RSSI_BUCKET - 1, count)?;
+        }
+        for (from, count) in &self.lqi {
+            writeln!(w, "lqi,{},{},{}", from, from + LQI_BUCKET - 1, count)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let rssi: Vec<String> = self
+            .rssi
+            .iter()
+            .map(|(from, n)| bucket_json(*from as i32, RSSI_BUCKET as i32, *n))
+            .collect();
+        let lqi: Vec<String> = self
+            .lqi
+            .iter()
+            .map(|(from, n)| bucket_json(*from as i32, LQI_BUCKET as i32, *n))
+            .collect();
+        writeln!(
+            w,
+            "{{\"rssi\":[{}],\"lqi\":[{}]}}",
+            rssi.join(","),
+            lqi.join(",")
+        )
+    }
+}
+
+fn bucket_json(from: i32, width: i32, count: u64) -> String {
+    format!(
+        "{{\"from\":{},\"to\":{},\"count\":{}}}",
+        from,
+        from + width - 1,
+        count
+    )
+}
+
+// Frame control, sequence number and FCS, the size of an Imm-Ack
+const MIN_FRAME_LEN: usize = 5;
+
+/// Bucket for a received frame, the first that applies in this order:
+///
+/// - oversized: longer than the 127 bytes a PHY packet can hold.
+/// - runt: shorter than an Imm-Ack, 5 bytes including the FCS.
+/// - malformed: the FCS doesn't match, when it is checked, or the MAC header doesn't parse.
+/// - inconsistent: the header parses, but the frame is too short for the fields it calls
+///   for or is an Imm-Ack with bytes after the header, see `MacHeader::check_length`.
+/// - valid: everything else.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameQuality {
+    Valid,
+    Malformed,
+    Inconsistent,
+    Runt,
+    Oversized,
+}
+
+impl FrameQuality {
+    /// Classifies a frame as received, including the FCS
+    pub fn classify(frame: &[u8], check_fcs: bool) -> FrameQuality {
+        if frame.len() > MAX_FRAME_LEN {
+            FrameQuality::Oversized
+        } else if frame.len() < MIN_FRAME_LEN {
+            FrameQuality::Runt
+        } else if check_fcs && !fcs_valid(frame) {
+            FrameQuality::Malformed
+        } else {
+            let mpdu = &frame[..frame.len() - FCS_LEN];
+            match MacHeader::parse(mpdu) {
+                Err(_) => FrameQuality::Malformed,
+                Ok(header) if header.check_length(mpdu).is_err() => FrameQuality::Inconsistent,
+                Ok(_) => FrameQuality::Valid,
+            }
+        }
+    }
+}
+
+/// Received frames per `FrameQuality`, including the ones dropped for a bad FCS
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QualityCounts {
+    pub valid: u64,
+    pub malformed: u64,
+    pub inconsistent: u64,
+    pub runt: u64,
+    pub oversized: u64,
+}
+
+impl QualityCounts {
+    pub fn record(&mut self, quality: FrameQuality) {
+        let count = match quality {
+            FrameQuality::Valid => &mut self.valid,
+            FrameQuality::Malformed => &mut self.malformed,
+            FrameQuality::Inconsistent => &mut self.inconsistent,
+            FrameQuality::Runt => &mut self.runt,
+            FrameQuality::Oversized => &mut self.oversized,
+        };
+        *count += 1;
+    }
+}
+
+/// Counters for a capture run
+#[derive(Clone, Debug, Default)]
+pub struct CaptureStats {
+    /// Packets written to the capture file
+    pub packets: u64,
+    /// Packets dropped because the FCS didn't match
+    pub bad_fcs: u64,
+    /// Packets dropped because the writer queue was full
+    pub dropped: u64,
+    /// Packets left out by a filter such as --beacons
+    pub filtered: u64,
+    /// Keepalives the sniffer didn't answer
+    pub keepalive_failures: u64,
+    /// Frames rejected for being longer than the maximum frame length
+    pub too_long: u64,
+    /// Frames whose two length fields differed, read with the smaller one
+    pub length_mismatches: u64,
+    /// Packets left out by --drop-unparseable
+    pub unparseable: u64,
+    /// Packets left out by --drop-inconsistent
+    pub inconsistent: u64,
+    /// Lowest and highest RSSI seen, in dBm
+    pub rssi_range: Option<(i8, i8)>,
+    /// Timestamps of the first and the last written packet
+    pub frame_times: Option<(Duration, Duration)>,
+    /// Longest time between two written packets, a long one can mean the sniffer hung
+    pub longest_gap: Duration,
+    /// Written packets per channel
+    pub channels: BTreeMap<u8, u64>,
+    /// Written packets per frame type, `?` for frames too short for a frame control field
+    pub frame_types: BTreeMap<String, u64>,
+    pub sources: SourceSignals,
+    pub histograms: Histograms,
+    /// Data frames with a 6LoWPAN fragment header, only counted when this starts as `Some`
+    pub lowpan_fragments: Option<u64>,
+    pub quality: QualityCounts,
+}
+
+impl CaptureStats {
+    /// Counts a written packet
+    pub fn record(&mut self, packet: &CapturedPacket) {
+        self.packets += 1;
+        self.rssi_range = match self.rssi_range {
+            Some((min, max)) => Some((min.min(packet.rssi), max.max(packet.rssi))),
+            None => Some((packet.rssi, packet.rssi)),
+        };
+        self.frame_times = match self.frame_times {
+            Some((first, last)) => {
+                let gap = packet.timestamp.saturating_sub(last);
+                self.longest_gap = self.longest_gap.max(gap);
+                Some((first.min(packet.timestamp), last.max(packet.timestamp)))
+            }
+            None => Some((packet.timestamp, packet.timestamp)),
+        };
+        *self.channels.entry(packet.channel).or_default() += 1;
+        self.histograms.record(packet.rssi, packet.lqi);
+
+        let frame_type = match packet.payload.get(..2) {
+            Some(fc) => FrameType::from(u16::from_le_bytes([fc[0], fc[1]])).to_string(),
+            None => "?".to_string(),
+        };
+        *self.frame_types.entry(frame_type).or_default() += 1;
+
+        match MacHeader::parse(&packet.payload).ok().and_then(|h| h.src) {
+            Some(src) => self.sources.record(src, packet.rssi),
+            None => self.sources.unknown += 1,
+        }
+
+        if let Some(fragments) = &mut self.lowpan_fragments {
+            if lowpan::fragment(&packet.payload).is_some() {
+                *fragments += 1;
+            }
+        }
+    }
+
+    /// Time from the first to the last written packet
+    pub fn duration(&self) -> Duration {
+        self.frame_times
+            .map(|(first, last)| last - first)
+            .unwrap_or_default()
+    }
+
+    /// Percentage of the written packets per frame type, the most frequent first
+    pub fn frame_type_shares(&self) -> Vec<(String, f64)> {
+        let mut shares: Vec<(String, f64)> = self
+            .frame_types
+            .iter()
+            .map(|(frame_type, count)| {
+                let share = 100.0 * *count as f64 / self.packets.max(1) as f64;
+                (frame_type.clone(), share)
+            })
+            .collect();
+        shares.sort_by(|a, b| b.1.total_cmp(&a.1));
+        shares
+    }
+
+    /// `frame_type_shares` as an ASCII bar chart, one line per frame type
+    pub fn write_type_chart<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (frame_type, share) in self.frame_type_shares() {
+            let bar = "#".repeat((share / 100.0 * CHART_WIDTH as f64).round() as usize);
+            writeln!(
+                w,
+                "{:>6} |{:<width$}| {:5.1}%",
+                frame_type,
+                bar,
+                share,
+                width = CHART_WIDTH
+            )?;
+        }
+        Ok(())
+    }
+
+    /// One line progress report for a capture that has been running for `elapsed`
+    pub fn report(&self, elapsed: Duration) -> String {
+        let seconds = elapsed.as_secs_f64();
+        let rate = if seconds > 0.0 {
+            self.packets as f64 / seconds
+        } else {
+            0.0
+        };
+
+        let mut report = format!(
+            "{} packets in {:.0}s ({:.1} packets/s)",
+            self.packets, seconds, rate
+        );
+        if let Some((min, max)) = self.rssi_range {
+            report.push_str(&format!(", rssi {} to {} dBm", min, max));
+        }
+        if self.dropped > 0 {
+            report.push_str(&format!(", {} dropped", self.dropped));
+        }
+        if self.filtered > 0 {
+            report.push_str(&format!(", {} filtered", self.filtered));
+        }
+        report
+    }
+}
+
+/// Stats for a finished capture, written with `--summary`
+pub struct CaptureSummary {
+    pub device: String,
+    /// The identifier stored in the capture file, if any
+    pub capture_id: Option<String>,
+    /// Start and end of the capture since the UNIX epoch
+    pub started: Duration,
+    pub ended: Duration,
+    pub stats: CaptureStats,
+}
+
+impl CaptureSummary {
+    pub fn write_text<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let stats = &self.stats;
+        writeln!(w, "device: {}", self.device)?;
+        if let Some(id) = &self.capture_id {
+            writeln!(w, "capture id: {}", id)?;
+        }
+        writeln!(w, "start: {:.3}", self.started.as_secs_f64())?;
+        writeln!(w, "end: {:.3}", self.ended.as_secs_f64())?;
+        writeln!(w, "packets: {}", stats.packets)?;
+        writeln!(w, "bad fcs: {}", stats.bad_fcs)?;
+        writeln!(w, "dropped: {}", stats.dropped)?;
+        writeln!(w, "filtered: {}", stats.filtered)?;
+        let quality = &stats.quality;
+        writeln!(
+            w,
+            "frames: {} valid, {} malformed, {} inconsistent, {} runt, {} oversized",
+            quality.valid, quality.malformed, quality.inconsistent, quality.runt, quality.oversized
+        )?;
+        if let Some((min, max)) = stats.rssi_range {
+            writeln!(w, "rssi: {} to {} dBm", min, max)?;
+        }
+        if let Some((first, last)) = stats.frame_times {
+            writeln!(w, "first packet: {:.3}", first.as_secs_f64())?;
+            writeln!(w, "last packet: {:.3}", last.as_secs_f64())?;
+            writeln!(w, "longest gap: {:.3} s", stats.longest_gap.as_secs_f64())?;
+        }
+        for (channel, count) in &stats.channels {
+            writeln!(w, "channel {}: {}", channel, count)?;
+        }
+        for (frame_type, count) in &stats.frame_types {
+            writeln!(w, "{}: {}", frame_type, count)?;
+        }
+        if let Some(fragments) = stats.lowpan_fragments {
+            writeln!(w, "6lowpan fragments: {}", fragments)?;
+        }
+        for (address, signal) in stats.sources.sorted() {
+            writeln!(
+                w,
+                "source {}: rssi {:.1} dBm, {} packets",
+                address, signal.rssi, signal.packets
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let stats = &self.stats;
+        write!(w, "{{\"device\":{}", json_string(&self.device))?;
+        if let Some(id) = &self.capture_id {
+            write!(w, ",\"capture_id\":{}", json_string(id))?;
+        }
+        write!(w, ",\"start\":{:.3}", self.started.as_secs_f64())?;
+        write!(w, ",\"end\":{:.3}", self.ended.as_secs_f64())?;
+        write!(w, ",\"packets\":{}", stats.packets)?;
+        write!(w, ",\"bad_fcs\":{}", stats.bad_fcs)?;
+        write!(w, ",\"dropped\":{}", stats.dropped)?;
+        write!(w, ",\"filtered\":{}", stats.filtered)?;
+        let quality = &stats.quality;
+        write!(
+            w,
+            ",\"frames\":{{\"valid\":{},\"malformed\":{},\"inconsistent\":{},\"runt\":{},\"oversized\":{}}}",
+            quality.valid,
+            quality.malformed,
+            quality.inconsistent,
+            quality.runt,
+            quality.oversized
+        )?;
+        if let Some((min, max)) = stats.rssi_range {
+            write!(w, ",\"rssi_min\":{},\"rssi_max\":{}", min, max)?;
+        }
+        if let Some((first, last)) = stats.frame_times {
+            write!(
+                w,
+                ",\"first_packet\":{:.3},\"last_packet\":{:.3},\"longest_gap\":{:.3}",
+                first.as_secs_f64(),
+                last.as_secs_f64(),
+                stats.longest_gap.as_secs_f64()
+            )?;
+        }
+        let channels: Vec<String> = stats
+            .channels
+            .iter()
+            .map(|(c, n)| format!("\"{}\":{}", c, n))
+            .collect();
+        write!(w, ",\"channels\":{{{}}}", channels.join(","))?;
+        let frame_types: Vec<String> = stats
+            .frame_types
+            .iter()
+            .map(|(t, n)| format!("{}:{}", json_string(t), n))
+            .collect();
+        write!(w, ",\"frame_types\":{{{}}}", frame_types.join(","))?;
+        if let Some(fragments) = stats.lowpan_fragments {
+            write!(w, ",\"lowpan_fragments\":{}", fragments)?;
+        }
+        let sources: Vec<String> = stats
+            .sources
+            .sorted()
+            .iter()
+            .map(|(a, s)| {
+                format!(
+                    "{{\"address\":\"{}\",\"rssi\":{:.1},\"packets\":{}}}",
+                    a, s.rssi, s.packets
+                )
+            })
+            .collect();
+        writeln!(w, ",\"sources\":[{}]}}", sources.join(","))
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mac::Address;
+    use crate::packet::CapturedPacket;
+    use crate::stats::{
+        CaptureStats, CaptureSummary, FrameQuality, QualityCounts, SourceSignal, SourceSignals,
+    };
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    fn packet(rssi: i8) -> CapturedPacket {
+        CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 13,
+            rssi,
+            lqi: 120,
+            payload: vec![],
+        }
+    }
+
+    fn summary() -> CaptureSummary {
+        let mut stats = CaptureStats::default();
+        stats.record(&CapturedPacket {
+            timestamp: Duration::from_millis(1000500),
+            payload: vec![0x02, 0x00, 0x2a],
+            ..packet(-45)
+        });
+        stats.record(&CapturedPacket {
+            timestamp: Duration::from_secs(1002),
+            ..packet(-60)
+        });
+        CaptureSummary {
+            device: "CC2531 \"USB\" Dongle".to_string(),
+            capture_id: None,
+            started: Duration::from_secs(1000),
+            ended: Duration::from_millis(1002500),
+            stats,
+        }
+    }
+
+    #[test]
+    fn report() {
+        let mut stats = CaptureStats::default();
+        assert_eq!(
+            stats.report(Duration::ZERO),
+            "0 packets in 0s (0.0 packets/s)"
+        );
+
+        stats.record(&packet(-45));
+        stats.record(&packet(-80));
+        stats.record(&packet(-60));
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.rssi_range, Some((-80, -45)));
+        assert_eq!(
+            stats.report(Duration::from_secs(2)),
+            "3 packets in 2s (1.5 packets/s), rssi -80 to -45 dBm"
+        );
+
+        stats.dropped = 2;
+        assert!(stats
+            .report(Duration::from_secs(2))
+            .ends_with(", 2 dropped"));
+    }
+
+    #[test]
+    fn frame_type_shares() {
+        let mut stats = CaptureStats::default();
+        assert!(stats.frame_type_shares().is_empty());
+
+        let frames: [&[u8]; 4] = [&[0x41, 0x88], &[0x02, 0x00], &[0x41, 0x88], &[0x00, 0x80]];
+        for frame in frames {
+            stats.record(&CapturedPacket {
+                payload: frame.to_vec(),
+                ..packet(-45)
+            });
+        }
+        assert_eq!(
+            stats.frame_type_shares(),
+            [
+                ("DATA".to_string(), 50.0),
+                ("ACK".to_string(), 25.0),
+                ("BEACON".to_string(), 25.0)
+            ]
+        );
+
+        let mut out = vec![];
+        stats.write_type_chart(&mut out).unwrap();
+        let chart = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines[0], format!("  DATA |{:<40}|  50.0%", "#".repeat(20)));
+        assert_eq!(lines[2], format!("BEACON |{:<40}|  25.0%", "#".repeat(10)));
+        assert!(chart.is_ascii());
+    }
+
+    #[test]
+    fn summary_text() {
+        let mut out = vec![];
+        summary().write_text(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "device: CC2531 \"USB\" Dongle\nstart: 1000.000\nend: 1002.500\npackets: 2\n\
+             bad fcs: 0\ndropped: 0\nfiltered: 0\nframes: 0 valid, 0 malformed, 0 inconsistent, 0 runt, 0 oversized\n\
+             rssi: -60 to -45 dBm\nfirst packet: 1000.500\nlast packet: 1002.000\nlongest gap: 1.500 s\n\
+             channel 13: 2\n?: 1\nACK: 1\n"
+        );
+    }
+
+    #[test]
+    fn summary_json() {
+        let mut out = vec![];
+        summary().write_json(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"device\":\"CC2531 \\\"USB\\\" Dongle\",\"start\":1000.000,\"end\":1002.500,\
+             \"packets\":2,\"bad_fcs\":0,\"dropped\":0,\"filtered\":0,\
+             \"frames\":{\"valid\":0,\"malformed\":0,\"inconsistent\":0,\"runt\":0,\"oversized\":0},\"rssi_min\":-60,\"rssi_max\":-45,\
+             \"first_packet\":1000.500,\"last_packet\":1002.000,\"longest_gap\":1.500,\
+             \"channels\":{\"13\":2},\"frame_types\":{\"?\":1,\"ACK\":1},\"sources\":[]}\n"
+        );
+    }
+
+    #[test]
+    fn duration_and_gaps() {
+        let mut stats = CaptureStats::default();
+        assert_eq!(stats.duration(), Duration::ZERO);
+
+        for millis in [1000, 1200, 4200, 4300] {
+            stats.record(&CapturedPacket {
+                timestamp: Duration::from_millis(millis),
+                ..packet(-45)
+            });
+        }
+        assert_eq!(
+            stats.frame_times,
+            Some((Duration::from_millis(1000), Duration::from_millis(4300)))
+        );
+        assert_eq!(stats.duration(), Duration::from_millis(3300));
+        assert_eq!(stats.longest_gap, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn source_rssi() {
+        let mut signals = SourceSignals::new(0.5);
+        signals.record(Address::Short(0xabcd), -60);
+        signals.record(Address::Short(0xabcd), -40);
+        signals.record(Address::Short(0xabcd), -40);
+        signals.record(Address::Short(0x1234), -50);
+
+        assert_eq!(
+            signals.sorted(),
+            [
+                (
+                    Address::Short(0xabcd),
+                    SourceSignal {
+                        rssi: -45.0,
+                        packets: 3
+                    }
+                ),
+                (
+                    Address::Short(0x1234),
+                    SourceSignal {
+                        rssi: -50.0,
+                        packets: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn top_talkers() {
+        let mut stats = CaptureStats::default();
+        // Data frames from 0xABCD, 0xABCD and 0x1234, an Imm-Ack without addresses
+        let from = |src: u16| CapturedPacket {
+            payload: [
+                [0x61, 0x88, 0x2a, 0x34, 0x12, 0x78, 0x56].as_slice(),
+                &src.to_le_bytes(),
+                &[0xff],
+            ]
+            .concat(),
+            ..packet(-45)
+        };
+        stats.record(&from(0xabcd));
+        stats.record(&from(0x1234));
+        stats.record(&from(0xabcd));
+        stats.record(&CapturedPacket {
+            payload: vec![0x02, 0x00, 0x2a, 0xe0, 0x3b],
+            ..packet(-45)
+        });
+
+        assert_eq!(
+            stats.sources.top_talkers(10),
+            [(Address::Short(0xabcd), 2), (Address::Short(0x1234), 1)]
+        );
+        assert_eq!(stats.sources.top_talkers(1), [(Address::Short(0xabcd), 2)]);
+        assert_eq!(stats.sources.unknown, 1);
+    }
+
+    #[test]
+    fn source_from_mac_header() {
+        let mut stats = CaptureStats::default();
+        stats.record(&CapturedPacket {
+            payload: vec![0x61, 0x88, 0x2a, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0xff],
+            ..packet(-45)
+        });
+        let mut out = vec![];
+        CaptureSummary {
+            device: "test".to_string(),
+            capture_id: None,
+            started: Duration::ZERO,
+            ended: Duration::ZERO,
+            stats,
+        }
+        .write_text(&mut out)
+        .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("source 0xABCD: rssi -45.0 dBm, 1 packets\n"));
+    }
+
+    #[test]
+    fn lowpan_fragments() {
+        // FRAG1 of a 300 byte datagram, then a frame without a fragment header
+        let fragment = vec![
+            0x61, 0x88, 0x2b, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0xc1, 0x2c, 0x12, 0x34, 0x7a,
+            0x33, 0x00, 0x00,
+        ];
+        let mut stats = CaptureStats::default();
+        stats.record(&CapturedPacket {
+            payload: fragment.clone(),
+            ..packet(-45)
+        });
+        assert_eq!(stats.lowpan_fragments, None);
+
+        let mut stats = CaptureStats {
+            lowpan_fragments: Some(0),
+            ..Default::default()
+        };
+        stats.record(&CapturedPacket {
+            payload: fragment,
+            ..packet(-45)
+        });
+        stats.record(&packet(-45));
+        assert_eq!(stats.lowpan_fragments, Some(1));
+    }
+
+    #[test]
+    fn frame_quality() {
+        // Imm-Ack with a correct FCS
+        let ack = [0x02, 0x00, 0x2a, 0xe0, 0x3b];
+        assert_eq!(FrameQuality::classify(&ack, true), FrameQuality::Valid);
+        assert_eq!(
+            FrameQuality::classify(&[0x02, 0x00, 0x2a], true),
+            FrameQuality::Runt
+        );
+        assert_eq!(
+            FrameQuality::classify(&[0xff; 128], true),
+            FrameQuality::Oversized
+        );
+
+        // A bad FCS only counts when it is checked
+        let bad_fcs = [0x02, 0x00, 0x2a, 0xe0, 0x3c];
+        assert_eq!(
+            FrameQuality::classify(&bad_fcs, true),
+            FrameQuality::Malformed
+        );
+        assert_eq!(FrameQuality::classify(&bad_fcs, false), FrameQuality::Valid);
+        // Data frame with a short destination address cut off after the PAN id
+        let truncated = [0x61, 0x88, 0x2a, 0x34, 0x12, 0x00, 0x00];
+        assert_eq!(
+            FrameQuality::classify(&truncated, false),
+            FrameQuality::Malformed
+        );
+        // Data frame, 2006 security, cut off in the auxiliary security header
+        let truncated = [
+            0x49, 0x98, 0x2a, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0x0d, 0x00, 0x00,
+        ];
+        assert_eq!(
+            FrameQuality::classify(&truncated, false),
+            FrameQuality::Inconsistent
+        );
+        // Imm-Ack with a stray byte before the FCS
+        let long_ack = [0x02, 0x00, 0x2a, 0x00, 0xe0, 0x3b];
+        assert_eq!(
+            FrameQuality::classify(&long_ack, false),
+            FrameQuality::Inconsistent
+        );
+
+        let mut counts = QualityCounts::default();
+        for quality in [FrameQuality::Valid, FrameQuality::Valid, FrameQuality::Runt] {
+            counts.record(quality);
+        }
+        assert_eq!(
+            counts,
+            QualityCounts {
+                valid: 2,
+                runt: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn histograms() {
+        let mut stats = CaptureStats::default();
+        for (rssi, lqi) in [(-45, 120), (-41, 100), (-46, 127), (-80, 40), (0, 0)] {
+            stats.record(&CapturedPacket {
+                lqi,
+                ..packet(rssi)
+            });
+        }
+
+        let histograms = &stats.histograms;
+        assert_eq!(histograms.rssi.values().sum::<u64>(), stats.packets);
+        assert_eq!(histograms.lqi.values().sum::<u64>(), stats.packets);
+        assert_eq!(
+            histograms.rssi,
+            BTreeMap::from([(-80, 1), (-50, 1), (-45, 2), (0, 1)])
+        );
+        assert_eq!(
+            histograms.lqi,
+            BTreeMap::from([(0, 1), (32, 1), (96, 1), (112, 2)])
+        );
+
+        let mut out = vec![];
+        histograms.write_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("metric,from,to,count\nrssi,-80,-76,1\n"));
+        assert!(csv.ends_with("lqi,112,127,2\n"));
+
+        let mut out = vec![];
+        histograms.write_json(&mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.starts_with("{\"rssi\":[{\"from\":-80,\"to\":-76,\"count\":1},"));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::mac::{Address, MacHeader, SuperframeSpec};
+use crate::packet::CapturedPacket;
+
+/// One line description of a frame, e.g. `DATA seq=42 PAN=0x1234 0xABCD->0x5678 rssi=-45 lqi=120`
+pub fn summarize(pkt: &CapturedPacket) -> String {
+    let header = match MacHeader::parse(&pkt.payload) {
+        Ok(h) => h,
+        Err(_) => return format!("? (unparsed, {} bytes)", pkt.payload.len()),
+    };
+
+    let mut summary = format!("{} seq={}", header.frame_type, header.sequence);
+    if let Some(pan) = header.pan() {
+        summary.push_str(&format!(" PAN=0x{:04X}", pan));
+    }
+    if header.src.is_some() || header.dst.is_some() {
+        summary.push_str(&format!(
+            " {}->{}",
+            format_address(header.src),
+            format_address(header.dst)
+        ));
+    }
+    summary.push_str(&format!(" rssi={} lqi={}", pkt.rssi, pkt.lqi));
+    summary
+}
+
+/// The frame summary followed by the superframe specification, None if the frame isn't
+/// a beacon that can be decoded
+pub fn describe_beacon(pkt: &CapturedPacket) -> Option<String> {
+    let header = MacHeader::parse(&pkt.payload).ok()?;
+    let spec = SuperframeSpec::parse(&pkt.payload, &header).ok()?;
+    Some(format!("{} {}", summarize(pkt), spec))
+}
+
+fn format_address(address: Option<Address>) -> String {
+    match address {
+        Some(a) => a.to_string(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::CapturedPacket;
+    use crate::summary::{describe_beacon, summarize};
+    use std::time::Duration;
+
+    fn packet(payload: &[u8]) -> CapturedPacket {
+        CapturedPacket {
+            timestamp: Duration::ZERO,
+            channel: 13,
+            rssi: -45,
+            lqi: 120,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn summarize_data() {
+        let p = packet(&[0x61, 0x88, 0x2a, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0xff]);
+        assert_eq!(
+            summarize(&p),
+            "DATA seq=42 PAN=0x1234 0xABCD->0x5678 rssi=-45 lqi=120"
+        );
+    }
+
+    #[test]
+    fn summarize_ack() {
+        let p = packet(&[0x02, 0x00, 0x2a]);
+        assert_eq!(summarize(&p), "ACK seq=42 rssi=-45 lqi=120");
+    }
+
+    #[test]
+    fn summarize_beacon() {
+        // Beacon, short source address, no destination
+        let p = packet(&[0x00, 0x80, 0x07, 0x34, 0x12, 0x00, 0x00, 0xff, 0xcf]);
+        assert_eq!(
+            summarize(&p),
+            "BEACON seq=7 PAN=0x1234 0x0000-> rssi=-45 lqi=120"
+        );
+    }
+
+    #[test]
+    fn beacon_superframe() {
+        let p = packet(&[0x00, 0x80, 0x07, 0x34, 0x12, 0x00, 0x00, 0xff, 0xcf]);
+        assert_eq!(
+            describe_beacon(&p).unwrap(),
+            "BEACON seq=7 PAN=0x1234 0x0000-> rssi=-45 lqi=120 BO=15 SO=15 cap=15 coordinator permit"
+        );
+        assert_eq!(describe_beacon(&packet(&[0x02, 0x00, 0x2a])), None);
+    }
+
+    #[test]
+    fn summarize_command() {
+        // Beacon request, broadcast destination, no source
+        let p = packet(&[0x03, 0x08, 0x01, 0xff, 0xff, 0xff, 0xff, 0x07]);
+        assert_eq!(
+            summarize(&p),
+            "CMD seq=1 PAN=0xFFFF ->0xFFFF rssi=-45 lqi=120"
+        );
+    }
+
+    #[test]
+    fn summarize_unparsed() {
+        let p = packet(&[0x61, 0x88]);
+        assert_eq!(summarize(&p), "? (unparsed, 2 bytes)");
+    }
+}
@@ -0,0 +1,1178 @@
+use crate::fcs::{FcsMode, FCS_LEN};
+#[cfg(feature = "nmea")]
+use crate::location::LatestFix;
+use crate::location::Location;
+use crate::lowpan;
+use crate::packet::{CapturedPacket, LqiMode};
+use crate::pcaptap;
+use crate::pcaptap::{Phy, TapBlock};
+use crate::sink::PacketSink;
+use clap::ValueEnum;
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketOption;
+use pcap_file::pcapng::blocks::interface_description::{
+    InterfaceDescriptionBlock, InterfaceDescriptionOption,
+};
+use pcap_file::pcapng::blocks::interface_statistics::{
+    InterfaceStatisticsBlock, InterfaceStatisticsOption,
+};
+use pcap_file::pcapng::blocks::section_header::{SectionHeaderBlock, SectionHeaderOption};
+use pcap_file::pcapng::{PcapNgBlock, PcapNgWriter};
+use pcap_file::{DataLink, PcapError};
+use std::borrow::Cow;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{error, fmt};
+
+pub const DEFAULT_SNAPLEN: u32 = 256;
+/// A snaplen of 0 means packets are stored whole, as in pcapng
+pub const NO_SNAPLEN: u32 = 0;
+
+// pcap-file doesn't expose custom options, so the capture id is stored as a comment
+// starting with this prefix
+pub const CAPTURE_ID_PREFIX: &str = "capture-id: ";
+
+/// Prefix of the section header comment with the location given for the capture
+pub const LOCATION_PREFIX: &str = "location: ";
+
+/// Comment of the interface statistics blocks written as heartbeats
+pub const HEARTBEAT_COMMENT: &str = "heartbeat";
+
+/// The TLVs of a TAP header: FCS type, RSSI, channel assignment and LQI, then the
+/// timeslot length and the bit rate when they are set
+fn tap_tlvs(
+    fcs_type: u8,
+    rssi: i8,
+    channel: u8,
+    lqi: u8,
+    timeslot_length: Option<u32>,
+    phy: Option<Phy>,
+) -> [Option<TapBlock>; 6] {
+    [
+        Some(TapBlock::FcsType(fcs_type)),
+        Some(TapBlock::TlvRssi(rssi as f32)),
+        Some(TapBlock::ChannelAssignment(channel as u16)),
+        Some(TapBlock::TlvLqi(lqi)),
+        timeslot_length.map(TapBlock::TimeslotLength),
+        phy.map(|phy| TapBlock::BitRate(phy.bit_rate())),
+    ]
+}
+
+/// Link type of the capture file
+///
+/// - tap: every MPDU is preceded by a TAP header with the FCS type, RSSI, channel and LQI.
+/// - withfcs: bare MPDUs including the FCS, for tools that don't understand TAP.
+/// - nofcs: bare MPDUs without the FCS, it is removed if the FCS mode kept it.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum LinkType {
+    Tap,
+    #[value(name = "withfcs")]
+    WithFcs,
+    #[value(name = "nofcs")]
+    NoFcs,
+}
+
+impl LinkType {
+    fn data_link(&self) -> DataLink {
+        match self {
+            LinkType::Tap => DataLink::IEEE802_15_4_TAP,
+            LinkType::WithFcs => DataLink::IEEE802_15_4,
+            LinkType::NoFcs => DataLink::IEEE802_15_4_NOFCS,
+        }
+    }
+}
+
+/// Resolution of the packet timestamps in the capture file
+///
+/// - ns: nanoseconds, as the timestamps are taken.
+/// - us: microseconds, rounded, for tools that don't handle other resolutions.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum TsResolution {
+    Ns,
+    Us,
+}
+
+impl TsResolution {
+    /// Value of the if_tsresol option, a negative power of 10
+    fn if_tsresol(&self) -> u8 {
+        match self {
+            TsResolution::Ns => 9,
+            TsResolution::Us => 6,
+        }
+    }
+
+    /// The timestamp rounded to the resolution
+    pub fn round(&self, timestamp: Duration) -> Duration {
+        match self {
+            TsResolution::Ns => timestamp,
+            TsResolution::Us => Duration::from_micros(((timestamp.as_nanos() + 500) / 1000) as u64),
+        }
+    }
+
+    /// pcap-file writes the nanoseconds of an EPB timestamp whatever the resolution of
+    /// the interface, so the value is passed as that many units of the resolution
+    fn epb_timestamp(&self, timestamp: Duration) -> Duration {
+        match self {
+            TsResolution::Ns => timestamp,
+            TsResolution::Us => Duration::from_nanos(self.round(timestamp).as_micros() as u64),
+        }
+    }
+}
+
+/// Whether a frame was received by the sniffer or transmitted by it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    /// The direction bits of the epb_flags option
+    fn epb_flags(&self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WriterError {
+    FcsRequired,
+    SnaplenTooSmall(u32, usize),
+    IoError(io::Error),
+    PcapError(PcapError),
+    InvalidPath(PathBuf, String),
+}
+
+impl fmt::Display for WriterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriterError::FcsRequired => {
+                write!(
+                    f,
+                    "the withfcs link type can't be used when the FCS is stripped"
+                )
+            }
+            WriterError::SnaplenTooSmall(snaplen, required) => write!(
+                f,
+                "snaplen {} can't hold the {} byte TAP header",
+                snaplen, required
+            ),
+            WriterError::IoError(e) => write!(f, "io error: {}", e),
+            WriterError::PcapError(e) => write!(f, "pcap error: {}", e),
+            WriterError::InvalidPath(path, reason) => {
+                write!(f, "can't write to {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl From<PcapError> for WriterError {
+    fn from(e: PcapError) -> Self {
+        WriterError::PcapError(e)
+    }
+}
+
+impl From<io::Error> for WriterError {
+    fn from(e: io::Error) -> Self {
+        WriterError::IoError(e)
+    }
+}
+
+impl error::Error for WriterError {}
+
+/// Checks that a file can be written at `path`, so a bad path is reported before the
+/// sniffer is set up. An existing file is left as it is.
+pub fn check_output_path(path: &Path) -> Result<(), WriterError> {
+    let invalid = |reason: String| WriterError::InvalidPath(path.to_path_buf(), reason);
+
+    if path.is_dir() {
+        return Err(invalid("it is a directory".to_string()));
+    }
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(invalid(format!("{} is not a directory", parent.display())));
+    }
+
+    let writable = if path.exists() {
+        OpenOptions::new().append(true).open(path).map(drop)
+    } else {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .and_then(|_| fs::remove_file(path))
+    };
+    writable.map_err(|e| invalid(e.to_string()))
+}
+
+/// Settings for the capture file
+#[derive(Clone, Debug)]
+pub struct WriterOptions {
+    pub fcs: FcsMode,
+    pub snaplen: u32,
+    /// Comments for the section header, one option per comment
+    pub comments: Vec<String>,
+    pub linktype: LinkType,
+    /// Without a TAP header, store RSSI, LQI and channel as a comment on every packet
+    pub radio_comments: bool,
+    /// Identifier stored in the section header, to correlate files
+    pub capture_id: Option<String>,
+    pub lqi_mode: LqiMode,
+    /// Add a comment to packets carrying a 6LoWPAN fragment header
+    pub lowpan_comments: bool,
+    /// Store whether every packet was received or transmitted in its epb_flags
+    pub direction_flags: bool,
+    pub ts_resolution: TsResolution,
+    /// Number of packets collected before they are written to the file, 1 writes every
+    /// packet as it arrives. `flush` writes out a partial batch.
+    pub batch_size: usize,
+    /// EUI-64 of the sniffing radio, stored in the interface description when known
+    pub eui: Option<u64>,
+    /// TSCH timeslot length in microseconds, written as a TAP TLV on every packet
+    pub timeslot_length: Option<u32>,
+    /// PHY of the frames, its bit rate is written as a TAP TLV on every packet
+    pub phy: Option<Phy>,
+    /// Start of the capture. When set the packets are stored with the time since then,
+    /// so the file shows them from 1970-01-01 00:00:00 and hides the wall clock time.
+    pub time_origin: Option<Duration>,
+    /// Where the capture was made, stored as a section header comment
+    pub location: Option<Location>,
+    /// Position of a GPS receiver, stored as a `location=lat,lon` comment on every
+    /// packet once it has a fix
+    #[cfg(feature = "nmea")]
+    pub fix: Option<LatestFix>,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        WriterOptions {
+            fcs: FcsMode::default(),
+            snaplen: DEFAULT_SNAPLEN,
+            comments: vec![],
+            linktype: LinkType::Tap,
+            radio_comments: false,
+            capture_id: None,
+            lqi_mode: LqiMode::Raw,
+            lowpan_comments: false,
+            direction_flags: false,
+            ts_resolution: TsResolution::Ns,
+            batch_size: 1,
+            eui: None,
+            timeslot_length: None,
+            phy: None,
+            time_origin: None,
+            location: None,
+            #[cfg(feature = "nmea")]
+            fix: None,
+        }
+    }
+}
+
+/// Counts the bytes written, which gives the file offset of every block
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Wraps captured packets in a TAP header and writes them to a pcapng stream. The
+/// blocks are buffered and written out in batches of `batch_size` packets.
+pub struct CaptureWriter<W: Write> {
+    writer: PcapNgWriter<CountingWriter<BufWriter<W>>>,
+    fcs: FcsMode,
+    snaplen: u32,
+    linktype: LinkType,
+    radio_comments: bool,
+    lqi_mode: LqiMode,
+    lowpan_comments: bool,
+    direction_flags: bool,
+    ts_resolution: TsResolution,
+    timeslot_length: Option<u32>,
+    phy: Option<Phy>,
+    time_origin: Option<Duration>,
+    #[cfg(feature = "nmea")]
+    fix: Option<LatestFix>,
+    index: Option<Box<dyn Write + Send>>,
+    frames: u64,
+    batch_size: usize,
+    batched: usize,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Writes the section header and the interface description for the sniffer
+    pub fn new(
+        w: W,
+        interface_description: &str,
+        options: &WriterOptions,
+    ) -> Result<Self, WriterError> {
+        let snaplen = options.snaplen;
+        let required = match options.linktype {
+            LinkType::Tap => {
+                // The values don't change the length
+                let tlvs = tap_tlvs(0, 0, 0, 0, options.timeslot_length, options.phy);
+                pcaptap::header_len(tlvs.iter().flatten())
+            }
+            LinkType::WithFcs | LinkType::NoFcs => 0,
+        };
+        if snaplen != NO_SNAPLEN && (snaplen as usize) < required {
+            return Err(WriterError::SnaplenTooSmall(snaplen, required));
+        }
+        if options.linktype == LinkType::WithFcs && options.fcs == FcsMode::Strip {
+            return Err(WriterError::FcsRequired);
+        }
+
+        let mut shb_options: Vec<SectionHeaderOption> = options
+            .comments
+            .iter()
+            .map(|c| SectionHeaderOption::Comment(Cow::from(c.clone())))
+            .collect();
+        if let Some(id) = &options.capture_id {
+            shb_options.push(SectionHeaderOption::Comment(Cow::from(format!(
+                "{}{}",
+                CAPTURE_ID_PREFIX, id
+            ))));
+        }
+        if let Some(location) = options.location {
+            shb_options.push(SectionHeaderOption::Comment(Cow::from(format!(
+                "{}{}",
+                LOCATION_PREFIX, location
+            ))));
+        }
+        let shb = SectionHeaderBlock {
+            options: shb_options,
+            ..Default::default()
+        };
+        let counting = CountingWriter {
+            inner: BufWriter::new(w),
+            written: 0,
+        };
+        let mut writer = PcapNgWriter::with_section_header(counting, shb)?;
+
+        let mut idb_options = vec![
+            InterfaceDescriptionOption::IfName(Cow::from("cc2531-usb")),
+            InterfaceDescriptionOption::IfDescription(Cow::from(interface_description)),
+            InterfaceDescriptionOption::IfTsResol(options.ts_resolution.if_tsresol()),
+        ];
+        if let Some(eui) = options.eui {
+            idb_options.push(InterfaceDescriptionOption::IfEuIAddr(eui));
+        }
+        // Readers add if_tsoffset to every timestamp, 0 keeps the relative times at the
+        // epoch instead of pretending they are wall clock times
+        if options.time_origin.is_some() {
+            idb_options.push(InterfaceDescriptionOption::IfTsOffset(0));
+        }
+        let idb = InterfaceDescriptionBlock {
+            linktype: options.linktype.data_link(),
+            snaplen,
+            options: idb_options,
+        };
+        writer.write_block(&idb.into_block())?;
+        writer.get_mut().flush()?;
+
+        Ok(CaptureWriter {
+            writer,
+            fcs: options.fcs,
+            snaplen,
+            linktype: options.linktype,
+            radio_comments: options.radio_comments,
+            lqi_mode: options.lqi_mode,
+            lowpan_comments: options.lowpan_comments,
+            direction_flags: options.direction_flags,
+            ts_resolution: options.ts_resolution,
+            timeslot_length: options.timeslot_length,
+            phy: options.phy,
+            time_origin: options.time_origin,
+            #[cfg(feature = "nmea")]
+            fix: options.fix.clone(),
+            index: None,
+            frames: 0,
+            batch_size: options.batch_size.max(1),
+            batched: 0,
+        })
+    }
+
+    /// Also writes a `frame,offset,channel,timestamp` CSV line for every packet, frames
+    /// are numbered from 1 and the offset is where the packet block starts in the file
+    pub fn set_index(&mut self, mut index: Box<dyn Write + Send>) -> Result<(), WriterError> {
+        writeln!(index, "frame,offset,channel,timestamp")?;
+        self.index = Some(index);
+        Ok(())
+    }
+
+    /// Writes out the pending batch and returns the underlying writer
+    pub fn into_inner(self) -> Result<W, WriterError> {
+        let buffered = self.writer.into_inner().inner;
+        buffered.into_inner().map_err(|e| e.into_error().into())
+    }
+
+    /// Like `write_packet`, for a frame that was received or transmitted, e.g. one sent
+    /// with `SnifferDevice::send_packet`. The direction is stored with `direction_flags`.
+    pub fn write_directed(
+        &mut self,
+        packet: &CapturedPacket,
+        direction: Direction,
+    ) -> Result<(), WriterError> {
+        let mut epd_data: Vec<u8> = vec![];
+        let mut payload = packet.payload.as_slice();
+
+        match self.linktype {
+            LinkType::Tap => {
+                let tlvs = tap_tlvs(
+                    self.fcs.tap_fcs_type(),
+                    packet.rssi,
+                    packet.channel,
+                    self.lqi_mode.lqi(packet),
+                    self.timeslot_length,
+                    self.phy,
+                );
+                let len = pcaptap::header_len(tlvs.iter().flatten());
+                TapBlock::Header(len).write_to(&mut epd_data)?;
+                for tlv in tlvs.into_iter().flatten() {
+                    tlv.write_to(&mut epd_data)?;
+                }
+            }
+            LinkType::WithFcs => {}
+            LinkType::NoFcs => {
+                if self.fcs != FcsMode::Strip {
+                    payload = &payload[..payload.len().saturating_sub(FCS_LEN)];
+                }
+            }
+        }
+        epd_data.extend_from_slice(payload);
+
+        let mut options = vec![];
+        if self.radio_comments && self.linktype != LinkType::Tap {
+            options.push(EnhancedPacketOption::Comment(Cow::from(format!(
+                "rssi={} lqi={} channel={}",
+                packet.rssi,
+                self.lqi_mode.lqi(packet),
+                packet.channel
+            ))));
+        }
+        if let Some(fragment) = lowpan::fragment(&packet.payload).filter(|_| self.lowpan_comments) {
+            options.push(EnhancedPacketOption::Comment(Cow::from(
+                fragment.to_string(),
+            )));
+        }
+        #[cfg(feature = "nmea")]
+        if let Some(location) = self.fix.as_ref().and_then(|f| f.get()) {
+            options.push(EnhancedPacketOption::Comment(Cow::from(format!(
+                "location={}",
+                location
+            ))));
+        }
+        if self.direction_flags {
+            options.push(EnhancedPacketOption::Flags(direction.epb_flags()));
+        }
+
+        // The stored bytes must never exceed the snaplen of the interface, readers reject
+        // such packets. The original length is that of the whole packet.
+        let original_len = epd_data.len() as u32;
+        if self.snaplen != NO_SNAPLEN {
+            epd_data.truncate(self.snaplen as usize);
+        }
+
+        let timestamp = match self.time_origin {
+            Some(origin) => packet.timestamp.saturating_sub(origin),
+            None => packet.timestamp,
+        };
+        let epb = EnhancedPacketBlock {
+            interface_id: 0,
+            timestamp: self.ts_resolution.epb_timestamp(timestamp),
+            original_len,
+            data: Cow::from(epd_data.as_slice()),
+            options,
+        };
+
+        let offset = self.writer.get_ref().written;
+        self.writer.write_block(&epb.into_block())?;
+
+        self.frames += 1;
+        if let Some(index) = &mut self.index {
+            let timestamp = self.ts_resolution.round(timestamp);
+            writeln!(
+                index,
+                "{},{},{},{}.{:09}",
+                self.frames,
+                offset,
+                packet.channel,
+                timestamp.as_secs(),
+                timestamp.subsec_nanos()
+            )?;
+        }
+
+        self.batched += 1;
+        if self.batched >= self.batch_size {
+            self.writer.get_mut().flush()?;
+            self.batched = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> PacketSink for CaptureWriter<W> {
+    /// Writes the packet for the link type, the FCS mode has already been applied by the
+    /// caller. Packets from the sniffer were received.
+    fn write_packet(&mut self, packet: &CapturedPacket) -> Result<(), WriterError> {
+        self.write_directed(packet, Direction::Inbound)
+    }
+
+    /// Writes an interface statistics block with a "heartbeat" comment and the number of
+    /// frames written so far. Readers don't count it as a packet.
+    fn heartbeat(&mut self, time: Duration) -> Result<(), WriterError> {
+        let time = match self.time_origin {
+            Some(origin) => time.saturating_sub(origin),
+            None => time,
+        };
+        let isb = InterfaceStatisticsBlock {
+            interface_id: 0,
+            timestamp: self.ts_resolution.epb_timestamp(time).as_nanos() as u64,
+            options: vec![
+                InterfaceStatisticsOption::Comment(Cow::from(HEARTBEAT_COMMENT)),
+                InterfaceStatisticsOption::IsbIfRecv(self.frames),
+            ],
+        };
+        self.writer.write_block(&isb.into_block())?;
+        self.flush()
+    }
+
+    /// Writes out the pending batch and the index
+    fn flush(&mut self) -> Result<(), WriterError> {
+        if let Some(index) = &mut self.index {
+            index.flush()?;
+        }
+        self.writer.get_mut().flush()?;
+        self.batched = 0;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), WriterError> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fcs::FcsMode;
+    use crate::packet::{CapturedPacket, LqiMode};
+    use crate::pcaptap::{read_header, Phy, TapBlock};
+    use crate::sink::{MemoryBuffer, PacketSink};
+    use crate::writer::{
+        check_output_path, CaptureWriter, Direction, LinkType, TsResolution, WriterError,
+        WriterOptions, CAPTURE_ID_PREFIX, HEARTBEAT_COMMENT, LOCATION_PREFIX, NO_SNAPLEN,
+    };
+    use pcap_file::pcapng::blocks::enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption};
+    use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionOption;
+    use pcap_file::pcapng::blocks::interface_statistics::InterfaceStatisticsOption;
+    use pcap_file::pcapng::blocks::section_header::SectionHeaderOption;
+    use pcap_file::pcapng::{Block, PcapNgReader};
+    use pcap_file::DataLink;
+    use std::fs;
+    use std::time::Duration;
+
+    fn packet(payload_len: usize) -> CapturedPacket {
+        CapturedPacket {
+            timestamp: Duration::from_secs(1),
+            channel: 13,
+            rssi: -45,
+            lqi: 120,
+            payload: vec![0xaa; payload_len],
+        }
+    }
+
+    /// Writes the packet, returns the interface description and the packet blocks
+    fn write_blocks(options: &WriterOptions, packet: &CapturedPacket) -> Vec<Block<'static>> {
+        let mut writer = CaptureWriter::new(Vec::new(), "test", options).unwrap();
+        writer.write_packet(packet).unwrap();
+
+        let data = writer.into_inner().unwrap();
+        let mut reader = PcapNgReader::new(data.as_slice()).unwrap();
+        let mut blocks = vec![];
+        while let Some(block) = reader.next_block() {
+            blocks.push(block.unwrap().into_owned());
+        }
+        blocks
+    }
+
+    fn linktype_blocks(options: &WriterOptions) -> (DataLink, EnhancedPacketBlock<'static>) {
+        let mut ack = packet(0);
+        ack.payload = vec![0x02, 0x00, 0x2a, 0xe0, 0x3b];
+        let blocks = write_blocks(options, &ack);
+        match (&blocks[0], &blocks[1]) {
+            (Block::InterfaceDescription(idb), Block::EnhancedPacket(epb)) => {
+                (idb.linktype, epb.clone())
+            }
+            _ => panic!("expected an interface description and a packet"),
+        }
+    }
+
+    #[test]
+    fn linktype_tap() {
+        let (linktype, epb) = linktype_blocks(&WriterOptions::default());
+        assert_eq!(linktype, DataLink::IEEE802_15_4_TAP);
+        assert_eq!(epb.data.len(), 36 + 5);
+        assert_eq!(epb.data[..4], [0, 0, 36, 0]);
+    }
+
+    #[test]
+    fn tap_fcs_type() {
+        // The FCS type TLV follows the TAP header, by default 0 so dissectors don't read
+        // the last two bytes as an FCS. The frame isn't shortened.
+        let (_, epb) = linktype_blocks(&WriterOptions::default());
+        assert_eq!(epb.data[4..9], [0, 0, 1, 0, 0]);
+        assert_eq!(epb.data.len(), 36 + 5);
+
+        // 1 for a 16-bit CRC
+        let options = WriterOptions {
+            fcs: FcsMode::Keep,
+            ..Default::default()
+        };
+        let (_, epb) = linktype_blocks(&options);
+        assert_eq!(epb.data[4..9], [0, 0, 1, 0, 1]);
+
+        let options = WriterOptions {
+            fcs: FcsMode::Strip,
+            ..Default::default()
+        };
+        let (_, epb) = linktype_blocks(&options);
+        assert_eq!(epb.data[4..9], [0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn timeslot_length() {
+        let options = WriterOptions {
+            timeslot_length: Some(10_000),
+            ..Default::default()
+        };
+        let (_, epb) = linktype_blocks(&options);
+        assert_eq!(epb.data.len(), 44 + 5);
+        let (blocks, payload) = read_header(&epb.data).unwrap();
+        assert_eq!(blocks.last(), Some(&TapBlock::TimeslotLength(10_000)));
+        assert_eq!(payload, [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+    }
+
+    #[test]
+    fn phy_bit_rate() {
+        let (_, epb) = linktype_blocks(&WriterOptions::default());
+        let (blocks, _) = read_header(&epb.data).unwrap();
+        assert!(!blocks.iter().any(|b| matches!(b, TapBlock::BitRate(_))));
+
+        for (phy, bit_rate) in [(Phy::Oqpsk2450, 250_000), (Phy::Bpsk915, 40_000)] {
+            let options = WriterOptions {
+                phy: Some(phy),
+                ..Default::default()
+            };
+            let (_, epb) = linktype_blocks(&options);
+            assert_eq!(epb.data.len(), 44 + 5);
+            let (blocks, payload) = read_header(&epb.data).unwrap();
+            assert_eq!(blocks.last(), Some(&TapBlock::BitRate(bit_rate)));
+            assert_eq!(payload, [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+        }
+    }
+
+    #[test]
+    fn lqi_mode() {
+        let (_, epb) = linktype_blocks(&WriterOptions::default());
+        assert_eq!(epb.data[28..33], [10, 0, 1, 0, 120]);
+
+        let options = WriterOptions {
+            lqi_mode: LqiMode::Estimated,
+            ..Default::default()
+        };
+        let (_, epb) = linktype_blocks(&options);
+        assert_eq!(epb.data[28..33], [10, 0, 1, 0, 255]);
+    }
+
+    #[test]
+    fn lowpan_comments() {
+        let mut fragment = packet(0);
+        fragment.payload = vec![
+            0x61, 0x88, 0x2b, 0x34, 0x12, 0x78, 0x56, 0xcd, 0xab, 0xe1, 0x2c, 0x12, 0x34, 0x0c,
+            0x00, 0x00,
+        ];
+        let options = WriterOptions {
+            lowpan_comments: true,
+            ..Default::default()
+        };
+        match &write_blocks(&options, &fragment)[1] {
+            Block::EnhancedPacket(epb) => assert_eq!(
+                epb.options,
+                [EnhancedPacketOption::Comment(
+                    "6lowpan fragment tag=0x1234 size=300 offset=96".into()
+                )]
+            ),
+            _ => panic!("expected a packet"),
+        }
+
+        let (_, epb) = linktype_blocks(&options);
+        assert!(epb.options.is_empty());
+    }
+
+    #[test]
+    fn direction_flags() {
+        let options = WriterOptions {
+            direction_flags: true,
+            ..Default::default()
+        };
+        let mut writer = CaptureWriter::new(Vec::new(), "test", &options).unwrap();
+        writer.write_packet(&packet(5)).unwrap();
+        writer
+            .write_directed(&packet(5), Direction::Outbound)
+            .unwrap();
+
+        let data = writer.into_inner().unwrap();
+        let mut reader = PcapNgReader::new(data.as_slice()).unwrap();
+        let mut flags = vec![];
+        while let Some(block) = reader.next_block() {
+            if let Block::EnhancedPacket(epb) = block.unwrap().into_owned() {
+                flags.push(epb.options);
+            }
+        }
+        assert_eq!(
+            flags,
+            [
+                [EnhancedPacketOption::Flags(0b01)],
+                [EnhancedPacketOption::Flags(0b10)]
+            ]
+        );
+
+        let (_, epb) = linktype_blocks(&WriterOptions::default());
+        assert!(epb.options.is_empty());
+    }
+
+    #[test]
+    fn interface_eui() {
+        let has_eui = |options: &WriterOptions| match &write_blocks(options, &packet(5))[0] {
+            Block::InterfaceDescription(idb) => idb
+                .options
+                .iter()
+                .any(|o| matches!(o, InterfaceDescriptionOption::IfEuIAddr(_))),
+            _ => panic!("expected an interface description"),
+        };
+        assert!(!has_eui(&WriterOptions::default()));
+
+        let options = WriterOptions {
+            eui: Some(0x00124b0001020304),
+            ..Default::default()
+        };
+        match &write_blocks(&options, &packet(5))[0] {
+            Block::InterfaceDescription(idb) => assert!(idb
+                .options
+                .contains(&InterfaceDescriptionOption::IfEuIAddr(0x00124b0001020304))),
+            _ => panic!("expected an interface description"),
+        }
+    }
+
+    #[test]
+    fn ts_resolution() {
+        let packet = CapturedPacket {
+            timestamp: Duration::new(1, 123_456_789),
+            ..packet(5)
+        };
+        for (resolution, tsresol, stored) in [
+            (TsResolution::Ns, 9, 1_123_456_789),
+            (TsResolution::Us, 6, 1_123_457),
+        ] {
+            let options = WriterOptions {
+                ts_resolution: resolution,
+                ..Default::default()
+            };
+            let blocks = write_blocks(&options, &packet);
+            match (&blocks[0], &blocks[1]) {
+                (Block::InterfaceDescription(idb), Block::EnhancedPacket(epb)) => {
+                    assert!(idb
+                        .options
+                        .contains(&InterfaceDescriptionOption::IfTsResol(tsresol)));
+                    // pcap-file reads the stored value back as nanoseconds
+                    assert_eq!(epb.timestamp.as_nanos(), stored);
+                }
+                _ => panic!("expected an interface description and a packet"),
+            }
+        }
+
+        let rounded = TsResolution::Us.round(packet.timestamp);
+        assert_eq!(rounded, Duration::new(1, 123_457_000));
+        assert_eq!(rounded.subsec_nanos() % 1000, 0);
+    }
+
+    #[test]
+    fn time_origin() {
+        let packets = [
+            CapturedPacket {
+                timestamp: Duration::new(1000, 500_000_000),
+                ..packet(5)
+            },
+            CapturedPacket {
+                timestamp: Duration::new(1002, 250_000_000),
+                ..packet(5)
+            },
+        ];
+        let timestamps = |options: &WriterOptions| {
+            let mut writer = CaptureWriter::new(Vec::new(), "test", options).unwrap();
+            for packet in &packets {
+                writer.write_packet(packet).unwrap();
+            }
+            let data = writer.into_inner().unwrap();
+            let mut reader = PcapNgReader::new(data.as_slice()).unwrap();
+            let mut timestamps = vec![];
+            while let Some(block) = reader.next_block() {
+                match block.unwrap() {
+                    Block::InterfaceDescription(idb) => assert_eq!(
+                        idb.options
+                            .contains(&InterfaceDescriptionOption::IfTsOffset(0)),
+                        options.time_origin.is_some()
+                    ),
+                    Block::EnhancedPacket(epb) => timestamps.push(epb.timestamp),
+                    _ => {}
+                }
+            }
+            timestamps
+        };
+
+        let absolute = timestamps(&WriterOptions::default());
+        let relative = timestamps(&WriterOptions {
+            time_origin: Some(Duration::from_secs(1000)),
+            ..Default::default()
+        });
+        assert_eq!(absolute[0], Duration::new(1000, 500_000_000));
+        assert_eq!(relative[0], Duration::from_millis(500));
+        assert_eq!(relative[1] - relative[0], absolute[1] - absolute[0]);
+    }
+
+    #[test]
+    fn linktype_withfcs() {
+        let options = WriterOptions {
+            linktype: LinkType::WithFcs,
+            ..Default::default()
+        };
+        let (linktype, epb) = linktype_blocks(&options);
+        assert_eq!(linktype, DataLink::IEEE802_15_4);
+        assert_eq!(epb.data.as_ref(), [0x02, 0x00, 0x2a, 0xe0, 0x3b]);
+        assert!(epb.options.is_empty());
+
+        let options = WriterOptions {
+            linktype: LinkType::WithFcs,
+            fcs: FcsMode::Strip,
+            ..Default::default()
+        };
+        let result = CaptureWriter::new(Vec::new(), "test", &options);
+        assert!(matches!(result, Err(WriterError::FcsRequired)));
+    }
+
+    #[test]
+    fn linktype_nofcs() {
+        let options = WriterOptions {
+            linktype: LinkType::NoFcs,
+            radio_comments: true,
+            ..Default::default()
+        };
+        let (linktype, epb) = linktype_blocks(&options);
+        assert_eq!(linktype, DataLink::IEEE802_15_4_NOFCS);
+        assert_eq!(epb.data.as_ref(), [0x02, 0x00, 0x2a]);
+        assert_eq!(
+            epb.options,
+            [EnhancedPacketOption::Comment(
+                "rssi=-45 lqi=120 channel=13".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn snaplen_too_small() {
+        let options = WriterOptions {
+            snaplen: 35,
+            ..Default::default()
+        };
+        let result = CaptureWriter::new(Vec::new(), "test", &options);
+        assert!(matches!(result, Err(WriterError::SnaplenTooSmall(35, 36))));
+    }
+
+    #[test]
+    fn snaplen_frame_too_long() {
+        // A maximum length frame cut to 64 bytes, without a TAP header
+        let options = WriterOptions {
+            snaplen: 64,
+            linktype: LinkType::WithFcs,
+            ..Default::default()
+        };
+        let blocks = write_blocks(&options, &packet(127));
+        assert_eq!(blocks.len(), 2);
+        match &blocks[1] {
+            Block::EnhancedPacket(epb) => {
+                assert_eq!(epb.data.len(), 64);
+                assert_eq!(epb.original_len, 127);
+            }
+            _ => panic!("expected an enhanced packet"),
+        }
+
+        // No limit
+        let options = WriterOptions {
+            snaplen: NO_SNAPLEN,
+            ..Default::default()
+        };
+        let (_, epb) = linktype_blocks(&options);
+        assert_eq!(epb.data.len(), 36 + 5);
+        assert_eq!(epb.original_len, 36 + 5);
+    }
+
+    #[test]
+    fn snaplen_truncates() {
+        let options = WriterOptions {
+            snaplen: 40,
+            ..Default::default()
+        };
+        let blocks = write_blocks(&options, &packet(10));
+
+        match &blocks[0] {
+            Block::InterfaceDescription(idb) => assert_eq!(idb.snaplen, 40),
+            _ => panic!("expected an interface description"),
+        }
+        match &blocks[1] {
+            Block::EnhancedPacket(epb) => {
+                assert_eq!(epb.data.len(), 40);
+                assert_eq!(epb.original_len, 46);
+            }
+            _ => panic!("expected an enhanced packet"),
+        }
+    }
+
+    #[test]
+    fn capture_id() {
+        let options = WriterOptions {
+            capture_id: Some("0b5a2f34-6e8c-4d6b-9a57-3b1f0c2e7d41".to_string()),
+            ..Default::default()
+        };
+        let writer = CaptureWriter::new(Vec::new(), "test", &options).unwrap();
+
+        let data = writer.into_inner().unwrap();
+        let reader = PcapNgReader::new(data.as_slice()).unwrap();
+        assert_eq!(
+            reader.section().options,
+            [SectionHeaderOption::Comment(
+                format!("{}0b5a2f34-6e8c-4d6b-9a57-3b1f0c2e7d41", CAPTURE_ID_PREFIX).into()
+            )]
+        );
+    }
+
+    #[test]
+    fn heartbeat() {
+        let options = WriterOptions {
+            ts_resolution: TsResolution::Us,
+            ..Default::default()
+        };
+        let mut writer = CaptureWriter::new(Vec::new(), "test", &options).unwrap();
+        writer.write_packet(&packet(5)).unwrap();
+        writer.heartbeat(Duration::from_millis(2500)).unwrap();
+
+        let data = writer.into_inner().unwrap();
+        let mut reader = PcapNgReader::new(data.as_slice()).unwrap();
+        let mut blocks = vec![];
+        while let Some(block) = reader.next_block() {
+            blocks.push(block.unwrap().into_owned());
+        }
+        assert_eq!(blocks.len(), 3);
+        assert!(matches!(blocks[1], Block::EnhancedPacket(_)));
+        match &blocks[2] {
+            Block::InterfaceStatistics(isb) => {
+                assert_eq!(isb.timestamp, 2_500_000);
+                assert_eq!(
+                    isb.options,
+                    [
+                        InterfaceStatisticsOption::Comment(HEARTBEAT_COMMENT.into()),
+                        InterfaceStatisticsOption::IsbIfRecv(1)
+                    ]
+                );
+            }
+            _ => panic!("expected interface statistics"),
+        }
+    }
+
+    #[test]
+    fn location() {
+        let options = WriterOptions {
+            location: Some("52.370216,4.895168".parse().unwrap()),
+            ..Default::default()
+        };
+        let writer = CaptureWriter::new(Vec::new(), "test", &options).unwrap();
+
+        let data = writer.into_inner().unwrap();
+        let reader = PcapNgReader::new(data.as_slice()).unwrap();
+        assert_eq!(
+            reader.section().options,
+            [SectionHeaderOption::Comment(
+                format!("{}52.370216,4.895168", LOCATION_PREFIX).into()
+            )]
+        );
+    }
+
+    #[cfg(feature = "nmea")]
+    #[test]
+    fn nmea_location() {
+        use crate::location::LatestFix;
+
+        let fix = LatestFix::default();
+        let options = WriterOptions {
+            fix: Some(fix.clone()),
+            ..Default::default()
+        };
+        let mut writer = CaptureWriter::new(Vec::new(), "test", &options).unwrap();
+        // No comment before the first fix
+        writer.write_packet(&packet(5)).unwrap();
+        let gga = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\n";
+        fix.follow(gga.as_bytes()).unwrap();
+        writer.write_packet(&packet(5)).unwrap();
+
+        let data = writer.into_inner().unwrap();
+        let mut reader = PcapNgReader::new(data.as_slice()).unwrap();
+        let mut comments = vec![];
+        while let Some(block) = reader.next_block() {
+            if let Block::EnhancedPacket(epb) = block.unwrap().into_owned() {
+                comments.push(epb.options);
+            }
+        }
+        assert_eq!(
+            comments,
+            [
+                vec![],
+                vec![EnhancedPacketOption::Comment(
+                    "location=48.117300,11.516667".into()
+                )]
+            ]
+        );
+    }
+
+    #[test]
+    fn section_comments() {
+        let options = WriterOptions {
+            comments: vec!["first".to_string(), "second".to_string()],
+            ..Default::default()
+        };
+        let writer = CaptureWriter::new(Vec::new(), "test", &options).unwrap();
+
+        let data = writer.into_inner().unwrap();
+        let reader = PcapNgReader::new(data.as_slice()).unwrap();
+        assert_eq!(
+            reader.section().options,
+            [
+                SectionHeaderOption::Comment("first".into()),
+                SectionHeaderOption::Comment("second".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn output_path() {
+        let dir = std::env::temp_dir().join(format!("ccsniffer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join("capture.pcap");
+        assert!(check_output_path(&file).is_ok());
+        assert!(!file.exists());
+
+        assert!(matches!(
+            check_output_path(&dir),
+            Err(WriterError::InvalidPath(_, _))
+        ));
+        assert!(matches!(
+            check_output_path(&dir.join("missing/capture.pcap")),
+            Err(WriterError::InvalidPath(_, _))
+        ));
+
+        // A regular file where a directory is expected can't be written, even as root
+        fs::write(&file, b"").unwrap();
+        let result = check_output_path(&file.join("capture.pcap"));
+        assert!(matches!(result, Err(WriterError::InvalidPath(_, _))));
+        assert!(check_output_path(&file).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index() {
+        let index = MemoryBuffer::new();
+        let mut writer = CaptureWriter::new(Vec::new(), "test", &WriterOptions::default()).unwrap();
+        writer.set_index(Box::new(index.clone())).unwrap();
+        writer.write_packet(&packet(5)).unwrap();
+        writer
+            .write_packet(&CapturedPacket {
+                channel: 15,
+                ..packet(3)
+            })
+            .unwrap();
+        writer.finish().unwrap();
+        let data = writer.into_inner().unwrap();
+
+        let index = String::from_utf8(index.bytes()).unwrap();
+        let lines: Vec<&str> = index.lines().collect();
+        assert_eq!(lines[0], "frame,offset,channel,timestamp");
+        assert_eq!(lines.len(), 3);
+        for (n, line) in lines[1..].iter().enumerate() {
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(fields[0], (n + 1).to_string());
+            assert_eq!(fields[3], "1.000000000");
+            // Every offset points at an enhanced packet block, pcap-file writes big endian
+            let offset: usize = fields[1].parse().unwrap();
+            assert_eq!(data[offset..offset + 4], [0, 0, 0, 6]);
+        }
+        assert!(lines[2].contains(",15,"));
+    }
+
+    #[test]
+    fn batched_writes() {
+        let options = WriterOptions {
+            batch_size: 3,
+            ..Default::default()
+        };
+        let file = MemoryBuffer::new();
+        let mut writer = CaptureWriter::new(file.clone(), "test", &options).unwrap();
+        let header_len = file.bytes().len();
+        assert!(header_len > 0);
+
+        writer.write_packet(&packet(5)).unwrap();
+        writer.write_packet(&packet(5)).unwrap();
+        assert_eq!(file.bytes().len(), header_len);
+        writer.write_packet(&packet(5)).unwrap();
+        let batch_len = file.bytes().len();
+        assert!(batch_len > header_len);
+
+        writer.write_packet(&packet(5)).unwrap();
+        assert_eq!(file.bytes().len(), batch_len);
+        writer.finish().unwrap();
+
+        // Same bytes as writing every packet on its own
+        let mut unbatched = CaptureWriter::new(Vec::new(), "test", &Default::default()).unwrap();
+        for _ in 0..4 {
+            unbatched.write_packet(&packet(5)).unwrap();
+        }
+        assert_eq!(file.bytes(), unbatched.into_inner().unwrap());
+    }
+}
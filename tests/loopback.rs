@@ -0,0 +1,75 @@
+//! Transmits a frame with one sniffer and captures it with another, to check the whole
+//! send and receive path. Needs two sticks with the sniffer firmware next to each other,
+//! run with `cargo test --features hardware-tests`. Skipped when there are fewer sticks.
+
+use ccsniffer_rust::fcs::fcs_valid;
+use ccsniffer_rust::packet::{now, CapturedPacket};
+use ccsniffer_rust::sniffer::{CmdCodes, SnifferDevice, SnifferError, PRODUCT, VENDOR};
+use std::time::{Duration, Instant};
+
+const CHANNEL: u8 = 26;
+// How long the receiver listens for the frame
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Opens every attached sniffer
+fn sniffers() -> Vec<SnifferDevice> {
+    // Without USB access the global context can't initialise and would panic
+    if rusb::Context::new().is_err() {
+        return vec![];
+    }
+    let Ok(devices) = rusb::devices() else {
+        return vec![];
+    };
+    devices
+        .iter()
+        .filter(|d| {
+            d.device_descriptor()
+                .is_ok_and(|desc| desc.vendor_id() == VENDOR && desc.product_id() == PRODUCT)
+        })
+        .map(|d| SnifferDevice::new(d).expect("can't open the sniffer"))
+        .collect()
+}
+
+#[test]
+fn send_and_receive() {
+    let mut sniffers = sniffers();
+    if sniffers.len() < 2 {
+        eprintln!("skipped: needs two sniffers, found {}", sniffers.len());
+        return;
+    }
+    let transmitter = sniffers.pop().unwrap();
+    let receiver = sniffers.pop().unwrap();
+
+    receiver
+        .start_sniffing(CHANNEL, Duration::ZERO)
+        .expect("receiver doesn't start");
+    transmitter.send_command(CmdCodes::CmdInit, &[]).unwrap();
+    transmitter
+        .send_command(CmdCodes::CmdSetChannel, &[CHANNEL])
+        .unwrap();
+
+    // Data frame from 0xABCD to the broadcast address on PAN 0x1234, without the FCS
+    let mut frame = vec![0x41, 0x88, 0x5a, 0x34, 0x12, 0xff, 0xff, 0xcd, 0xab];
+    frame.extend_from_slice(b"ccsniffer loopback");
+    transmitter.send_packet(&frame).expect("send failed");
+
+    let started = Instant::now();
+    let received = loop {
+        assert!(started.elapsed() < WAIT, "the frame wasn't received");
+        let data = match receiver.receive_packet() {
+            Ok(data) => data,
+            Err(SnifferError::TimeOut) => continue,
+            Err(e) => panic!("receive failed: {}", e),
+        };
+        let Some(packet) = CapturedPacket::from_sniffer_data(data, CHANNEL, now()) else {
+            continue;
+        };
+        if packet.payload.starts_with(&frame) {
+            break packet;
+        }
+    };
+
+    assert_eq!(received.payload.len(), frame.len() + 2);
+    assert!(fcs_valid(&received.payload));
+    receiver.send_command(CmdCodes::CmdSniffOff, &[]).unwrap();
+}